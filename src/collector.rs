@@ -0,0 +1,404 @@
+//! This module defines the collectors driven by the executor, each one
+//! reading a subsystem and pushing its samples into the shared buffers.
+//! The CPU, memory, disks and networks are either read through the
+//! sysinfo crate or natively from procfs and sysfs, the other subsystems
+//! are always read natively
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+use sysinfo::{ProcessorExt, System, SystemExt, DiskExt, NetworkExt, NetworksExt};
+use crate::cpu::{self, CpuCollector};
+use crate::disk::DiskCollector;
+use crate::diskio::DiskIoCollector;
+use crate::error::{Error, Result};
+use crate::load::LoadCollector;
+use crate::mem::MemCollector;
+use crate::net::{self, NetCollector};
+use crate::psi::PsiCollector;
+use crate::systats::SystatsData;
+use crate::utils;
+
+
+static OS_RELEASE: &str = "/etc/os-release";
+static OS_TYPE: &str = "/proc/sys/kernel/ostype";
+static HOSTNAME: &str = "/proc/sys/kernel/hostname";
+static UPTIME: &str = "/proc/uptime";
+
+pub const DEFAULT_PROC_ROOT: &str = "/proc";
+pub const DEFAULT_SYS_ROOT: &str = "/sys";
+
+/// Mount points of procfs and sysfs, for reading the statistics of the
+/// host from a container or of a fixture tree in tests
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsRoots {
+    proc_root: String,
+    sys_root: String,
+}
+
+impl Default for FsRoots {
+    fn default() -> Self {
+        FsRoots::new(DEFAULT_PROC_ROOT, DEFAULT_SYS_ROOT)
+    }
+}
+
+impl FsRoots {
+    pub fn new(proc_root: &str, sys_root: &str) -> Self {
+        FsRoots {
+            proc_root: proc_root.trim_end_matches('/').to_string(),
+            sys_root: sys_root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == FsRoots::default()
+    }
+
+    /// Maps a path under /proc or /sys to the configured roots, other
+    /// paths are returned unchanged
+    pub fn path(&self, path: &str) -> String {
+        let mapped = [(DEFAULT_PROC_ROOT, &self.proc_root),
+                      (DEFAULT_SYS_ROOT, &self.sys_root)];
+        for (default, root) in mapped.iter() {
+            if let Some(rest) = path.strip_prefix(default) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    return format!("{}{}", root, rest);
+                }
+            }
+        }
+        path.to_string()
+    }
+}
+
+pub trait Collector: Send {
+    fn name(&self) -> &'static str;
+
+    /// Called once before the first sample
+    fn init(&mut self, _systats: &mut SystatsData) {}
+
+    /// Refreshes the data that is slow to read, before the buffers are
+    /// locked for writing
+    fn refresh(&mut self) {}
+
+    /// Pushes the samples that could be read, returning the error of the
+    /// first source that could not
+    fn collect(&mut self, systats: &mut SystatsData, now: Instant)
+        -> Result<()>;
+}
+
+/// Outcome of the last collections of a collector
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CollectorState {
+    pub error: Option<String>,
+    /// Consecutive failed collections
+    pub failures: u64,
+    /// Unix time of the last successful collection, in milliseconds
+    pub last_success: Option<u64>,
+}
+
+impl CollectorState {
+    pub fn update(&mut self, result: &Result<()>, timestamp: u64) {
+        match result {
+            Ok(()) => {
+                self.error = None;
+                self.failures = 0;
+                self.last_success = Some(timestamp);
+            },
+            Err(err) => {
+                self.error = Some(err.to_string());
+                self.failures += 1;
+            },
+        }
+    }
+}
+
+/// Source of the CPU, memory, disks and networks statistics
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    #[default]
+    Sysinfo,
+    Procfs,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sysinfo" => Ok(Backend::Sysinfo),
+            "procfs" => Ok(Backend::Procfs),
+            _ => Err(format!("unknown backend: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Sysinfo => write!(f, "sysinfo"),
+            Backend::Procfs => write!(f, "procfs"),
+        }
+    }
+}
+
+/// Returns the collectors of the backend, followed by the native
+/// collectors common to both backends
+pub fn collectors(backend: Backend, roots: &FsRoots)
+    -> Vec<Box<dyn Collector>> {
+    let mut collectors: Vec<Box<dyn Collector>> = match backend {
+        Backend::Sysinfo => vec![
+            Box::new(SysinfoCollector::new(roots.clone())),
+            Box::new(MemCollector::new(false, roots.clone())),
+        ],
+        Backend::Procfs => vec![
+            Box::new(SystemCollector::new(roots.clone())),
+            Box::new(CpuCollector::new(roots.clone())),
+            Box::new(MemCollector::new(true, roots.clone())),
+            Box::new(DiskCollector::new(roots.clone())),
+            Box::new(NetCollector::new(roots.clone())),
+        ],
+    };
+    collectors.push(Box::new(DiskIoCollector::new(roots.clone())));
+    collectors.push(Box::new(LoadCollector::new(roots.clone())));
+    collectors.push(Box::new(PsiCollector::new(roots.clone())));
+    collectors
+}
+
+/// Reads the CPU, memory, disks and networks through the sysinfo crate,
+/// which always reads /proc and /sys. Only what sysinfo does not expose,
+/// read from sysfs, honors the roots
+pub struct SysinfoCollector {
+    sysinfo: System,
+    roots: FsRoots,
+}
+
+impl SysinfoCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        SysinfoCollector { sysinfo: System::new_all(), roots }
+    }
+}
+
+impl Collector for SysinfoCollector {
+    fn name(&self) -> &'static str {
+        "sysinfo"
+    }
+
+    fn init(&mut self, systats: &mut SystatsData) {
+        let mut disks: Vec<&str> = self.sysinfo.disks().iter()
+            .filter_map(|x| x.name().to_str())
+            .collect();
+        disks.dedup();
+        let nets: Vec<&str> = self.sysinfo.networks().iter()
+            .map(|(k, _v)| k.as_str())
+            .collect();
+        systats.build_dynamic_values(self.sysinfo.processors().len(),
+                                     &disks, &nets);
+        // Not exposed by sysinfo, read from sysfs once as natively
+        for (id, core) in systats.cpus.iter_mut() {
+            core.governor = cpu::read_governor(&self.roots, *id);
+            core.driver = cpu::read_driver(&self.roots, *id);
+        }
+        for (ifname, netstat) in systats.networks.iter_mut() {
+            netstat.address = net::read_address(&self.roots, ifname);
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.sysinfo.refresh_cpu();
+        self.sysinfo.refresh_memory();
+        self.sysinfo.refresh_disks();
+        self.sysinfo.refresh_networks();
+    }
+
+    /// Pushes what sysinfo reported, the CPUs and memory it could not read
+    /// being left unavailable
+    fn collect(&mut self, systats: &mut SystatsData, now: Instant)
+        -> Result<()> {
+        let sysinfo = &self.sysinfo;
+        let mut result = Ok(());
+        for (id, processor) in sysinfo.processors().iter().enumerate() {
+            if let Some(core) = systats.cpus.get_mut(&id) {
+                core.usage.push_back(processor.cpu_usage());
+                core.freq.push_back(processor.frequency());
+            }
+        }
+        for disk in sysinfo.disks() {
+            let name = disk.name().to_str().unwrap_or("").to_string();
+            if let Some(buf) = systats.disk_usage.get_mut(&name) {
+                buf.push_back(disk.total_space() -
+                              disk.available_space());
+            }
+        }
+        for (ifname, netdata) in sysinfo.networks().iter() {
+            if let Some(netstat) = systats.networks.get_mut(ifname) {
+                netstat.rx_bytes.push(netdata.total_received(), now);
+                netstat.tx_bytes.push(netdata.total_transmitted(), now);
+                netstat.rx_packets.push(netdata.total_packets_received(), now);
+                netstat.tx_packets.push(netdata.total_packets_transmitted(), now);
+                netstat.rx_errors.push(netdata.total_errors_on_received(), now);
+                netstat.tx_errors.push(netdata.total_errors_on_transmitted(), now);
+                let stats_dir = self.roots.path(&net::NET_STATISTICS.replace("@", ifname));
+                match utils::read_u64(&format!("{}/rx_dropped", stats_dir)) {
+                    Ok(drops) => netstat.rx_drops.push(drops, now),
+                    Err(err) => result = result.and(Err(err)),
+                }
+                match utils::read_u64(&format!("{}/tx_dropped", stats_dir)) {
+                    Ok(drops) => netstat.tx_drops.push(drops, now),
+                    Err(err) => result = result.and(Err(err)),
+                }
+            }
+        }
+        if systats.name.is_empty() {
+            systats.name = sysinfo.name().unwrap_or_default();
+        }
+        if systats.hostname.is_empty() {
+            systats.hostname = sysinfo.host_name().unwrap_or_default();
+        }
+        if systats.cpu_model.is_empty() {
+            systats.cpu_model = sysinfo.global_processor_info().brand()
+                .trim().to_string();
+        }
+        systats.uptime = sysinfo.uptime();
+        systats.cpu_cores = sysinfo.physical_core_count().unwrap_or(0);
+        if sysinfo.processors().is_empty() {
+            result = result.and(Err(Error::Missing { what: "CPU usage" }));
+        } else {
+            let processor = sysinfo.global_processor_info();
+            systats.cpu_usage.push_back(processor.cpu_usage());
+            systats.cpu_freq.push_back(processor.frequency());
+        }
+        if sysinfo.total_memory() == 0 {
+            result = result.and(Err(Error::Missing { what: "memory" }));
+        } else {
+            systats.total_mem = sysinfo.total_memory();
+            systats.total_swap = sysinfo.total_swap();
+            systats.mem_free.push_back(sysinfo.free_memory());
+            systats.mem_used.push_back(sysinfo.used_memory());
+            systats.mem_available.push_back(sysinfo.available_memory());
+        }
+        result
+    }
+}
+
+/// Reads the name of the operating system, the host name and the uptime
+/// natively
+pub struct SystemCollector {
+    roots: FsRoots,
+}
+
+impl SystemCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        SystemCollector { roots }
+    }
+}
+
+/// Returns the NAME of /etc/os-release, or the kernel name when the
+/// file is missing. The os-release of the host is only read with the
+/// default roots
+fn read_os_name(roots: &FsRoots) -> Option<String> {
+    let name = if roots.is_default() {
+        utils::read_file(OS_RELEASE).ok().and_then(|content| {
+            content.lines()
+                .find_map(|line| line.strip_prefix("NAME="))
+                .map(|name| name.trim_matches('"').to_string())
+        })
+    } else {
+        None
+    };
+    name.or_else(|| {
+        Some(utils::read_file(&roots.path(OS_TYPE)).ok()?.trim().to_string())
+    })
+}
+
+impl Collector for SystemCollector {
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+    fn collect(&mut self, systats: &mut SystatsData, _now: Instant)
+        -> Result<()> {
+        if systats.name.is_empty() {
+            systats.name = read_os_name(&self.roots).unwrap_or_default();
+        }
+        if systats.hostname.is_empty() {
+            systats.hostname = utils::read_file(&self.roots.path(HOSTNAME))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_default();
+        }
+        let uptime = utils::parse_file(&self.roots.path(UPTIME), |content| {
+            content.split_whitespace().next()?.parse::<f64>().ok()
+        })?;
+        systats.uptime = uptime as u64;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fixture_collectors() {
+    use std::time::Duration;
+    use crate::ringbuf::BufferSpec;
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let roots = FsRoots::new(&format!("{}/proc", fixtures),
+                             &format!("{}/sys", fixtures));
+    let mut systats = SystatsData::new(&BufferSpec::new(4, false));
+    let mut collectors = collectors(Backend::Procfs, &roots);
+    for collector in collectors.iter_mut() {
+        collector.init(&mut systats);
+    }
+    let start = Instant::now();
+    for i in 0..2 {
+        for collector in collectors.iter_mut() {
+            collector.refresh();
+            collector.collect(&mut systats, start + Duration::from_secs(i))
+                .unwrap();
+        }
+    }
+    assert_eq!(systats.name, "Linux");
+    assert_eq!(systats.hostname, "web-1");
+    assert_eq!(systats.uptime, 12345);
+    assert_eq!(systats.cpu_model, "Intel(R) Core(TM) i7-8650U CPU @ 1.90GHz");
+    assert_eq!(systats.cpu_cores, 2);
+    assert_eq!(systats.cpu_usage.get_last(), Some(0.));
+    assert_eq!(systats.cpu_freq.get_last(), Some(2150));
+    assert_eq!(systats.cpus.len(), 2);
+    assert_eq!(systats.cpus[&1].freq.get_last(), Some(1900));
+    assert_eq!(systats.cpus[&1].governor, "performance");
+    assert_eq!(systats.cpus[&0].driver, "intel_pstate");
+    assert_eq!(systats.total_mem, 16303360);
+    assert_eq!(systats.mem_available.get_last(), Some(12288000));
+    assert_eq!(systats.memory.swap_used.get_last(), Some(524288));
+    assert!(systats.disk_usage.contains_key("/dev/sda1"));
+    let eth0 = &systats.networks["eth0"];
+    assert_eq!(eth0.address, "52:54:00:12:34:56");
+    assert_eq!(eth0.tx_bytes.total, 2000);
+    assert_eq!(eth0.tx_drops.total, 8000);
+    assert_eq!(eth0.rx_bytes.rate.get_last(), Some(0.));
+    assert_eq!(systats.diskio.len(), 2);
+    assert!(!systats.diskio.contains_key("loop0"));
+    assert_eq!(systats.load.load15.get_last(), Some(1.));
+    assert_eq!(systats.load.procs_running.get_last(), Some(1));
+    assert!(systats.pressure.cpu.available);
+    assert_eq!(systats.pressure.cpu.some.avg10.get_last(), Some(1.5));
+    assert_eq!(systats.pressure.io.full.stall_us.get_last(), Some(0));
+    // Missing files are reported as errors, but those of PSI which is then
+    // unsupported
+    let roots = FsRoots::new("/nonexistent/proc", "/nonexistent/sys");
+    let mut state = CollectorState::default();
+    for mut collector in self::collectors(Backend::Procfs, &roots) {
+        let result = collector.collect(&mut systats, start);
+        if collector.name() == "pressure" {
+            assert!(result.is_ok());
+            continue;
+        }
+        assert!(result.is_err(), "{} should fail", collector.name());
+        state.update(&result, 1000);
+    }
+    assert!(!systats.pressure.cpu.available);
+    assert_eq!(state.failures, 7);
+    assert_eq!(state.error.as_deref(),
+               Some("failed to read /nonexistent/proc/loadavg: \
+                     No such file or directory (os error 2)"));
+    state.update(&Ok(()), 2000);
+    assert_eq!(state, CollectorState { error: None, failures: 0,
+                                       last_success: Some(2000) });
+}
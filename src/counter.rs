@@ -0,0 +1,101 @@
+//! This module turns monotonic counters, as the ones exported by the
+//! kernel, into per-second rates between consecutive samples
+
+use std::time::Instant;
+use crate::ringbuf::{BufferSpec, RingStatsBuffer};
+
+
+/// Returns how much the counter increased from `prev` to `value`.
+///
+/// A smaller value means the counter was reset and started over from zero.
+pub fn counter_delta(prev: u64, value: u64) -> u64 {
+    if value >= prev {
+        value - prev
+    } else {
+        value
+    }
+}
+
+/// Same as counter_delta, for the counters the kernel keeps in 32 bits.
+///
+/// A smaller value is a counter that wrapped around when the previous
+/// value was close to its limit, or a counter that was reset otherwise.
+pub fn counter_delta32(prev: u64, value: u64) -> u64 {
+    let limit = u32::MAX as u64;
+    if value < prev && prev > limit/2 && prev <= limit && value <= limit {
+        (limit - prev) + value + 1
+    } else {
+        counter_delta(prev, value)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct CounterRate {
+    last: Option<(u64, Instant)>,
+}
+
+impl CounterRate {
+    pub fn new() -> Self {
+        CounterRate { last: None }
+    }
+
+    /// Updates the counter, returning its rate per second since the last
+    /// update, or None on the first update
+    pub fn update(&mut self, value: u64, now: Instant) -> Option<f64> {
+        let rate = match self.last {
+            Some((prev, then)) if now > then => {
+                let elapsed = now.duration_since(then).as_secs_f64();
+                Some(counter_delta(prev, value) as f64/elapsed)
+            },
+            _ => None,
+        };
+        self.last = Some((value, now));
+        rate
+    }
+}
+
+/// Ring buffer of the rates of a counter, along with its last raw value
+pub struct CounterBuffer {
+    pub rate: RingStatsBuffer<f64>,
+    pub total: u64,
+    counter: CounterRate,
+}
+
+impl CounterBuffer {
+    pub fn new(spec: &BufferSpec) -> Self {
+        CounterBuffer {
+            rate: RingStatsBuffer::with_spec(spec),
+            total: 0,
+            counter: CounterRate::new(),
+        }
+    }
+
+    pub fn push(&mut self, value: u64, now: Instant) {
+        self.total = value;
+        if let Some(rate) = self.counter.update(value, now) {
+            self.rate.push_back_at(rate, now);
+        }
+    }
+}
+
+#[test]
+fn test_counter_rate() {
+    use std::time::Duration;
+    assert_eq!(counter_delta(10, 25), 15);
+    // Reset, whatever the previous value of a 64-bit counter
+    assert_eq!(counter_delta(1000, 10), 10);
+    assert_eq!(counter_delta(u32::MAX as u64 - 9, 5), 5);
+    assert_eq!(counter_delta(u64::MAX - 5, 10), 10);
+    // 32-bit wraparound, or reset far from the limit
+    assert_eq!(counter_delta32(10, 25), 15);
+    assert_eq!(counter_delta32(u32::MAX as u64 - 9, 5), 15);
+    assert_eq!(counter_delta32(1000, 10), 10);
+    let start = Instant::now();
+    let mut counter = CounterRate::new();
+    assert_eq!(counter.update(100, start), None);
+    assert_eq!(counter.update(300, start + Duration::from_millis(500)),
+               Some(400.));
+    // Same instant gives no rate
+    assert_eq!(counter.update(400, start + Duration::from_millis(500)), None);
+    assert_eq!(counter.update(400, start + Duration::from_secs(2)), Some(0.));
+}
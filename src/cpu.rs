@@ -1,119 +1,210 @@
-//! This module implements the SysInfo trait for the CPU subsystem,
-//! allowing to collect and display data from CPU
+//! This module collects the usage of the CPU from /proc/stat, along with
+//! the frequency, governor and scaling driver of each core from sysfs
 
-use std::default::Default;
-use super::SysInfo;
+use std::collections::HashMap;
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::Result;
+use crate::load::PROC_STAT;
+use crate::systats::{CpuCore, SystatsData};
 use crate::utils;
 
 static CPU_INFO: &str = "/proc/cpuinfo";
-static CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
 static CPU_FREQ: &str = "/sys/devices/system/cpu/cpu@/cpufreq/scaling_cur_freq";
 static CPU_DRIVER: &str = "/sys/devices/system/cpu/cpu@/cpufreq/scaling_driver";
 static CPU_GOVERNOR: &str = "/sys/devices/system/cpu/cpu@/cpufreq/scaling_governor";
 
-#[derive(Default)]
-struct Cpu {
-    id: u32,
-    governor: String,
-    driver: String,
-    freq: f64
+/// Time spent by a CPU since boot, in clock ticks
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct CpuTimes {
+    pub busy: u64,
+    pub total: u64,
 }
 
-#[derive(Default)]
-pub struct CpuInfo {
-    model: String,
-    cpus: Vec<Cpu>
-}
-
-impl CpuInfo {
-    fn read_cpus_online(&mut self) {
-        let content: String = utils::open_and_read(CPU_ONLINE);
-        let content = content.trim();
-        let cpus_online = utils::parse_online_cpus(content);
-        for cpu in cpus_online {
-            let mut cpui = Cpu::default();
-            cpui.id = cpu;
-            self.cpus.push(cpui);
+impl CpuTimes {
+    /// Returns the usage, in percent, since the `prev` times
+    pub fn usage_since(&self, prev: &CpuTimes) -> f32 {
+        let total = self.total.saturating_sub(prev.total);
+        if total == 0 {
+            return 0.;
         }
+        let busy = self.busy.saturating_sub(prev.busy);
+        (busy as f64/total as f64*100.) as f32
     }
+}
 
-    fn read_model() -> String {
-        let content: String = utils::open_and_read(CPU_INFO);
-        let key: &str = "model name";
-        let cpu_model = match content.find(key) {
-            Some(idx_b) => {
-                let subcontent = &content[idx_b + key.len()..];
-                match subcontent.find("\n") {
-                    Some(idx_e) => {
-                        let mut model = &subcontent[..idx_e];
-                        model = model.trim_matches(
-                            |c: char| c.is_ascii_whitespace() || c == ':');
-                        Some(String::from(model))
-                    },
-                    _ => None
-                }
-            },
-            _ => None
-        };
-        cpu_model.unwrap_or(String::new())
-    }
+/// Parses the cpu lines of /proc/stat, the aggregate line having no id.
+/// Guest time is already accounted in user time and is left out
+pub fn parse_cpu_times(content: &str) -> Vec<(Option<usize>, CpuTimes)> {
+    content.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = match fields.next()?.strip_prefix("cpu")? {
+                "" => None,
+                id => Some(id.parse::<usize>().ok()?),
+            };
+            let values: Vec<u64> = fields.take(8)
+                .filter_map(|field| field.parse::<u64>().ok())
+                .collect();
+            if values.len() < 4 {
+                return None;
+            }
+            let total: u64 = values.iter().sum();
+            // idle and iowait
+            let idle = values[3] + values.get(4).unwrap_or(&0);
+            Some((id, CpuTimes { busy: total - idle, total }))
+        })
+        .collect()
+}
+
+fn read_model(content: &str) -> String {
+    let trim: &[_] = &[' ', '\t', ':'];
+    utils::parse_key_from_text(content, "model name", "\n", Some(trim))
+        .unwrap_or_default()
+}
 
-    fn read_freq(id: &u32) -> f64 {
-        let filename = CPU_FREQ.replace("@", &*id.to_string());
-        let content: String = utils::open_and_read(&filename);
-        match content.trim().parse::<f64>() {
-            Ok(val) => val/1000.,
-            _ => 0.
+/// Counts the distinct physical cores, falling back to the number of
+/// processors when the topology is not reported
+fn read_physical_cores(content: &str) -> usize {
+    let mut processors = 0;
+    let mut cores = Vec::new();
+    let mut physical_id = "";
+    for line in content.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            "processor" => processors += 1,
+            "physical id" => physical_id = value,
+            "core id" => cores.push((physical_id, value)),
+            _ => (),
         }
     }
+    cores.sort_unstable();
+    cores.dedup();
+    if cores.is_empty() { processors } else { cores.len() }
+}
 
-    fn read_driver(id: &u32) -> String {
-        let filename = CPU_DRIVER.replace("@", &*id.to_string());
-        let content: String = utils::open_and_read(&filename);
-        String::from(content.trim())
-    }
+/// Frequency of each processor in /proc/cpuinfo, used when cpufreq is
+/// not available
+fn read_cpuinfo_freqs(content: &str) -> Vec<u64> {
+    content.lines()
+        .filter(|line| line.starts_with("cpu MHz"))
+        .filter_map(|line| line.split(':').nth(1)?.trim().parse::<f64>().ok())
+        .map(|freq| freq as u64)
+        .collect()
+}
+
+/// Returns the frequency of a core, in MHz
+fn read_freq(roots: &FsRoots, id: usize) -> Option<u64> {
+    let filename = roots.path(&CPU_FREQ.replace("@", &id.to_string()));
+    utils::read_u64(&filename).ok().map(|khz| khz/1000)
+}
+
+pub fn read_driver(roots: &FsRoots, id: usize) -> String {
+    let filename = roots.path(&CPU_DRIVER.replace("@", &id.to_string()));
+    utils::read_file(&filename).unwrap_or_default().trim().to_string()
+}
+
+pub fn read_governor(roots: &FsRoots, id: usize) -> String {
+    let filename = roots.path(&CPU_GOVERNOR.replace("@", &id.to_string()));
+    utils::read_file(&filename).unwrap_or_default().trim().to_string()
+}
+
+pub struct CpuCollector {
+    roots: FsRoots,
+    last: HashMap<Option<usize>, CpuTimes>,
+}
 
-    fn read_governor(id: &u32) -> String {
-        let filename = CPU_GOVERNOR.replace("@", &*id.to_string());
-        let content: String = utils::open_and_read(&filename);
-        String::from(content.trim())
+impl CpuCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        CpuCollector { roots, last: HashMap::new() }
     }
 }
 
-impl SysInfo for CpuInfo {
-    fn new() -> Self {
-        CpuInfo::default()
+impl Collector for CpuCollector {
+    fn name(&self) -> &'static str {
+        "cpu"
     }
 
-    fn read(&mut self) {
-        self.model = CpuInfo::read_model();
-        self.read_cpus_online();
-        for cpu in self.cpus.iter_mut() {
-            cpu.freq = CpuInfo::read_freq(&cpu.id);
-            cpu.driver = CpuInfo::read_driver(&cpu.id);
-            cpu.governor = CpuInfo::read_governor(&cpu.id);
+    fn init(&mut self, systats: &mut SystatsData) {
+        if let Ok(content) = utils::read_file(&self.roots.path(CPU_INFO)) {
+            systats.cpu_model = read_model(&content);
+            systats.cpu_cores = read_physical_cores(&content);
         }
     }
 
-    fn display(&self) {
-        println!("|{:=^85}|", " CPU INFO ");
-        println!("| {:83} |", self.model);
-        let end = self.cpus.len();
-        for i in (0..end-1).step_by(2) {
-            let cpu1 = &self.cpus[i];
-            let cpu2 = &self.cpus[i+1];
-            println!("|{:-^42}|{:-^42}|", format!(" CPU{} ", cpu1.id), format!(" CPU{} ", cpu2.id));
-            println!("| {:7.2} MHz | {:11} | {:11} | {:7.2} MHz | {:11} | {:11} |",
-                     cpu1.freq, cpu1.governor, cpu1.driver, cpu2.freq, cpu2.governor,
-                     cpu2.driver);
+    fn collect(&mut self, systats: &mut SystatsData, _now: Instant)
+        -> Result<()> {
+        let times = utils::parse_file(&self.roots.path(PROC_STAT), |content| {
+            let times = parse_cpu_times(content);
+            if times.is_empty() { None } else { Some(times) }
+        })?;
+        let spec = systats.spec.clone();
+        let mut cpuinfo_freqs = None;
+        let mut freqs = Vec::new();
+        for (id, times) in times {
+            let usage = self.last.insert(id, times)
+                .map(|prev| times.usage_since(&prev));
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    if let Some(usage) = usage {
+                        systats.cpu_usage.push_back(usage);
+                    }
+                    continue;
+                }
+            };
+            let freq = read_freq(&self.roots, id).or_else(|| {
+                cpuinfo_freqs.get_or_insert_with(|| {
+                    utils::read_file(&self.roots.path(CPU_INFO))
+                        .map(|content| read_cpuinfo_freqs(&content))
+                        .unwrap_or_default()
+                }).get(id).copied()
+            });
+            // The governor and driver are read once, when the core appears
+            let core = systats.cpus.entry(id).or_insert_with(|| CpuCore {
+                governor: read_governor(&self.roots, id),
+                driver: read_driver(&self.roots, id),
+                ..CpuCore::new(&spec)
+            });
+            if let Some(usage) = usage {
+                core.usage.push_back(usage);
+            }
+            if let Some(freq) = freq {
+                core.freq.push_back(freq);
+                freqs.push(freq);
+            }
         }
-        if end % 2 == 1 {
-            let cpu = &self.cpus[end-1];
-            println!("|{:-^42}|", format!(" CPU{} ", cpu.id));
-            println!("| {:7.2} MHz | {:11} | {:11} |",
-                     cpu.freq, cpu.governor, cpu.driver);
+        if !freqs.is_empty() {
+            systats.cpu_freq.push_back(freqs.iter().sum::<u64>()/freqs.len() as u64);
         }
+        Ok(())
     }
 }
 
-
+#[test]
+fn test_cpu_times() {
+    let content = "\
+cpu  100 0 50 800 50 0 0 0 0 0\n\
+cpu0 60 0 20 400 20 0 0 0 0 0\n\
+cpu1 40 0 30 400 30 0 0 0 0 0\n\
+intr 12345 1 2 3 4\n";
+    let times = parse_cpu_times(content);
+    assert_eq!(times.len(), 3);
+    assert_eq!(times[0], (None, CpuTimes { busy: 150, total: 1000 }));
+    assert_eq!(times[2], (Some(1), CpuTimes { busy: 70, total: 500 }));
+    let next = CpuTimes { busy: 200, total: 1200 };
+    assert_eq!(next.usage_since(&times[0].1), 25.);
+    assert_eq!(next.usage_since(&next), 0.);
+    let cpuinfo = "\
+processor\t: 0\nmodel name\t: Some CPU @ 2.10GHz\ncpu MHz\t\t: 2100.000\n\
+physical id\t: 0\ncore id\t\t: 0\n\n\
+processor\t: 1\nmodel name\t: Some CPU @ 2.10GHz\ncpu MHz\t\t: 1800.500\n\
+physical id\t: 0\ncore id\t\t: 0\n";
+    assert_eq!(read_model(cpuinfo), "Some CPU @ 2.10GHz");
+    assert_eq!(read_physical_cores(cpuinfo), 1);
+    assert_eq!(read_physical_cores("processor\t: 0\nprocessor\t: 1\n"), 2);
+    assert_eq!(read_cpuinfo_freqs(cpuinfo), vec![2100, 1800]);
+}
@@ -0,0 +1,121 @@
+//! This module collects the space used on the mounted block devices, from
+//! /proc/mounts and statvfs(3). With a custom procfs root, the mounts of
+//! the host are read from its init process and reached through its root
+
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::Result;
+use crate::ringbuf::RingStatsBuffer;
+use crate::systats::SystatsData;
+use crate::utils;
+
+pub static MOUNTS: &str = "/proc/mounts";
+static HOST_MOUNTS: &str = "/proc/1/mounts";
+static HOST_ROOT: &str = "/proc/1/root";
+
+/// A block device along with the first mount point it was found at
+#[derive(Debug, PartialEq)]
+pub struct Mount {
+    pub device: String,
+    pub mount_point: String,
+}
+
+/// Parses /proc/mounts, keeping only the first mount of each device
+/// under /dev
+pub fn parse_mounts(content: &str) -> Vec<Mount> {
+    let mut mounts: Vec<Mount> = Vec::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let (device, mount_point) = match (fields.next(), fields.next()) {
+            (Some(device), Some(mount_point)) => (device, mount_point),
+            _ => continue,
+        };
+        if !device.starts_with("/dev/") ||
+           mounts.iter().any(|mount| mount.device == device) {
+            continue;
+        }
+        // Spaces in mount points are escaped in octal
+        mounts.push(Mount { device: device.to_string(),
+                            mount_point: mount_point.replace("\\040", " ") });
+    }
+    mounts
+}
+
+/// Returns the bytes used on the filesystem, excluding the blocks only
+/// available to root as the sysinfo backend does
+// The statvfs fields are narrower than u64 on 32-bit targets
+#[allow(clippy::unnecessary_cast)]
+fn used_space(mount_point: &str) -> Option<u64> {
+    let path = CString::new(mount_point).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    let blocks = (stat.f_blocks as u64).saturating_sub(stat.f_bavail as u64);
+    Some(blocks*stat.f_frsize as u64)
+}
+
+pub struct DiskCollector {
+    roots: FsRoots,
+}
+
+impl DiskCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        DiskCollector { roots }
+    }
+
+    fn mounts_path(&self) -> String {
+        if self.roots.is_default() {
+            MOUNTS.to_string()
+        } else {
+            self.roots.path(HOST_MOUNTS)
+        }
+    }
+
+    /// Returns the path a mount point of the host is reachable at
+    fn host_path(&self, mount_point: &str) -> String {
+        if self.roots.is_default() {
+            mount_point.to_string()
+        } else {
+            format!("{}{}", self.roots.path(HOST_ROOT), mount_point)
+        }
+    }
+}
+
+impl Collector for DiskCollector {
+    fn name(&self) -> &'static str {
+        "disks"
+    }
+
+    fn collect(&mut self, systats: &mut SystatsData, _now: Instant)
+        -> Result<()> {
+        let mounts = parse_mounts(&utils::read_file(&self.mounts_path())?);
+        let spec = systats.spec.clone();
+        for mount in mounts {
+            if let Some(used) = used_space(&self.host_path(&mount.mount_point)) {
+                systats.disk_usage.entry(mount.device)
+                    .or_insert_with(|| RingStatsBuffer::with_spec(&spec))
+                    .push_back(used);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mounts() {
+    let content = "\
+proc /proc proc rw,relatime 0 0\n\
+/dev/sda1 / ext4 rw,relatime 0 0\n\
+/dev/sdb1 /mnt/my\\040disk ext4 rw 0 0\n\
+/dev/sda1 /var/lib/docker ext4 rw,relatime 0 0\n";
+    assert_eq!(parse_mounts(content), vec![
+        Mount { device: "/dev/sda1".to_string(), mount_point: "/".to_string() },
+        Mount { device: "/dev/sdb1".to_string(),
+                mount_point: "/mnt/my disk".to_string() },
+    ]);
+    assert!(used_space("/").is_some());
+}
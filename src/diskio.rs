@@ -0,0 +1,212 @@
+//! This module collects the I/O statistics of the block devices from
+//! /proc/diskstats, computing rates, latency and utilization between
+//! consecutive samples
+
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::Result;
+use crate::counter::{counter_delta, counter_delta32};
+use crate::ringbuf::{BufferSpec, RestoreSeries, RingStatsBuffer};
+use crate::systats::SystatsData;
+use crate::utils;
+
+
+pub static DISK_STATS: &str = "/proc/diskstats";
+
+const SECTOR_SIZE: f64 = 512.;
+
+/// Counters of one line of /proc/diskstats
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct DiskstatsEntry {
+    pub name: String,
+    pub reads: u64,
+    pub read_sectors: u64,
+    pub read_ms: u64,
+    pub writes: u64,
+    pub write_sectors: u64,
+    pub write_ms: u64,
+    pub in_progress: u64,
+    pub io_ms: u64,
+    pub weighted_io_ms: u64,
+}
+
+impl DiskstatsEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 14 {
+            return None;
+        }
+        let num = |i: usize| fields[i].parse::<u64>().ok();
+        Some(DiskstatsEntry {
+            name: fields[2].to_string(),
+            reads: num(3)?,
+            read_sectors: num(5)?,
+            read_ms: num(6)?,
+            writes: num(7)?,
+            write_sectors: num(9)?,
+            write_ms: num(10)?,
+            in_progress: num(11)?,
+            io_ms: num(12)?,
+            weighted_io_ms: num(13)?,
+        })
+    }
+
+    /// Loop and RAM devices, or devices never used, are not worth tracking
+    pub fn is_relevant(&self) -> bool {
+        !(self.name.starts_with("loop") || self.name.starts_with("ram") ||
+          self.reads + self.writes == 0)
+    }
+}
+
+pub fn parse_diskstats(content: &str) -> Vec<DiskstatsEntry> {
+    content.lines().filter_map(DiskstatsEntry::parse).collect()
+}
+
+pub struct DiskIoStats {
+    pub read_iops: RingStatsBuffer<f64>,
+    pub write_iops: RingStatsBuffer<f64>,
+    /// Bytes per second
+    pub read_bytes: RingStatsBuffer<f64>,
+    pub write_bytes: RingStatsBuffer<f64>,
+    /// Average time, in milliseconds, for the requests to be served
+    pub await_ms: RingStatsBuffer<f64>,
+    /// Average number of requests in the queue
+    pub queue_depth: RingStatsBuffer<f64>,
+    /// Percentage of the time the device was busy
+    pub utilization: RingStatsBuffer<f64>,
+    last: Option<(DiskstatsEntry, Instant)>,
+}
+
+impl DiskIoStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        DiskIoStats {
+            read_iops: RingStatsBuffer::with_spec(spec),
+            write_iops: RingStatsBuffer::with_spec(spec),
+            read_bytes: RingStatsBuffer::with_spec(spec),
+            write_bytes: RingStatsBuffer::with_spec(spec),
+            await_ms: RingStatsBuffer::with_spec(spec),
+            queue_depth: RingStatsBuffer::with_spec(spec),
+            utilization: RingStatsBuffer::with_spec(spec),
+            last: None,
+        }
+    }
+
+    /// Lists the buffers along with the name of their metric
+    pub fn buffers(&self) -> [(&'static str, &RingStatsBuffer<f64>); 7] {
+        [
+            ("read_iops", &self.read_iops),
+            ("write_iops", &self.write_iops),
+            ("read_bytes", &self.read_bytes),
+            ("write_bytes", &self.write_bytes),
+            ("await_ms", &self.await_ms),
+            ("queue_depth", &self.queue_depth),
+            ("utilization", &self.utilization),
+        ]
+    }
+
+    /// Same as buffers, to restore them
+    pub fn buffers_mut(&mut self) -> [(&'static str, &mut dyn RestoreSeries); 7] {
+        [
+            ("read_iops", &mut self.read_iops),
+            ("write_iops", &mut self.write_iops),
+            ("read_bytes", &mut self.read_bytes),
+            ("write_bytes", &mut self.write_bytes),
+            ("await_ms", &mut self.await_ms),
+            ("queue_depth", &mut self.queue_depth),
+            ("utilization", &mut self.utilization),
+        ]
+    }
+
+    pub fn push(&mut self, entry: DiskstatsEntry, now: Instant) {
+        if let Some((prev, then)) = &self.last {
+            if now > *then {
+                let elapsed = now.duration_since(*then).as_secs_f64();
+                let elapsed_ms = elapsed*1000.;
+                let reads = counter_delta(prev.reads, entry.reads) as f64;
+                let writes = counter_delta(prev.writes, entry.writes) as f64;
+                // The times are kept in 32 bits by the kernel
+                let busy_ms = (counter_delta32(prev.read_ms, entry.read_ms) +
+                               counter_delta32(prev.write_ms,
+                                               entry.write_ms)) as f64;
+                let read_sectors = counter_delta(prev.read_sectors,
+                                                 entry.read_sectors) as f64;
+                let write_sectors = counter_delta(prev.write_sectors,
+                                                  entry.write_sectors) as f64;
+                let io_ms = counter_delta32(prev.io_ms, entry.io_ms) as f64;
+                let weighted_ms = counter_delta32(prev.weighted_io_ms,
+                                                  entry.weighted_io_ms) as f64;
+                self.read_iops.push_back(reads/elapsed);
+                self.write_iops.push_back(writes/elapsed);
+                self.read_bytes.push_back(read_sectors*SECTOR_SIZE/elapsed);
+                self.write_bytes.push_back(write_sectors*SECTOR_SIZE/elapsed);
+                let ios = reads + writes;
+                self.await_ms.push_back(if ios > 0. { busy_ms/ios } else { 0. });
+                self.queue_depth.push_back(weighted_ms/elapsed_ms);
+                self.utilization.push_back((io_ms/elapsed_ms*100.).min(100.));
+            }
+        }
+        self.last = Some((entry, now));
+    }
+}
+
+pub struct DiskIoCollector {
+    roots: FsRoots,
+}
+
+impl DiskIoCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        DiskIoCollector { roots }
+    }
+}
+
+impl Collector for DiskIoCollector {
+    fn name(&self) -> &'static str {
+        "diskio"
+    }
+
+    fn collect(&mut self, systats: &mut SystatsData, now: Instant)
+        -> Result<()> {
+        let content = utils::read_file(&self.roots.path(DISK_STATS))?;
+        systats.push_diskstats(parse_diskstats(&content), now);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_diskstats() {
+    use std::time::Duration;
+    let content = "\
+   7       0 loop0 12 0 100 4 0 0 0 0 0 8 4 0 0 0 0\n\
+   8       0 sda 1000 10 8000 500 2000 20 16000 1500 1 3000 4000 0 0 0 0\n\
+   8       1 sda1 0 0 0 0 0 0 0 0 0 0 0\n";
+    let entries = parse_diskstats(content);
+    assert_eq!(entries.len(), 3);
+    assert!(!entries[0].is_relevant());
+    assert!(entries[1].is_relevant());
+    assert!(!entries[2].is_relevant());
+    let mut first = entries[1].clone();
+    assert_eq!(first.name, "sda");
+    assert_eq!(first.writes, 2000);
+    assert_eq!(first.weighted_io_ms, 4000);
+    let start = Instant::now();
+    let mut stats = DiskIoStats::new(&BufferSpec::new(4, false));
+    stats.push(first.clone(), start);
+    assert_eq!(stats.read_iops.len(), 0);
+    // 2 seconds later: 100 reads, 300 writes, 800 ms busy on io
+    first.reads += 100;
+    first.read_sectors += 800;
+    first.read_ms += 200;
+    first.writes += 300;
+    first.write_sectors += 1600;
+    first.write_ms += 600;
+    first.io_ms += 800;
+    first.weighted_io_ms += 1000;
+    stats.push(first, start + Duration::from_secs(2));
+    assert_eq!(stats.read_iops.get_last(), Some(50.));
+    assert_eq!(stats.write_iops.get_last(), Some(150.));
+    assert_eq!(stats.read_bytes.get_last(), Some(800.*512./2.));
+    assert_eq!(stats.write_bytes.get_last(), Some(1600.*512./2.));
+    assert_eq!(stats.await_ms.get_last(), Some(2.));
+    assert_eq!(stats.queue_depth.get_last(), Some(0.5));
+    assert_eq!(stats.utilization.get_last(), Some(40.));
+}
@@ -0,0 +1,38 @@
+//! This module defines the errors of the collectors
+
+use std::fmt;
+use std::io;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file or directory could not be read
+    Io { path: String, source: io::Error },
+    /// The content of a file is not in the expected format
+    Parse { path: String },
+    /// A statistic was not reported by the sysinfo crate
+    Missing { what: &'static str },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => {
+                write!(f, "failed to read {}: {}", path, source)
+            },
+            Error::Parse { path } => write!(f, "failed to parse {}", path),
+            Error::Missing { what } => write!(f, "{} not reported", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Parse { .. } | Error::Missing { .. } => None,
+        }
+    }
+}
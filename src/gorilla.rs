@@ -0,0 +1,239 @@
+//! This module compresses a series of samples as Gorilla does: the
+//! timestamps by their delta-of-delta and the values by XOR with the
+//! previous one, both packed in a stream of bits
+
+
+/// Bits appended to a byte vector, most significant first
+#[derive(Default, Debug)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used of the last byte, 8 if full
+    used: u32,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 || self.bytes.is_empty() {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// Writes the `count` low bits of `value`
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for shift in (0..count).rev() {
+            self.write_bit(value >> shift & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos/8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+/// Buckets of the delta-of-delta: control bits, their number and the bits
+/// of the value
+const DOD_BUCKETS: [(u64, u32, u32); 3] = [
+    (0b10, 2, 7),
+    (0b110, 3, 9),
+    (0b1110, 4, 12),
+];
+
+fn fits(value: i64, bits: u32) -> bool {
+    let limit = 1i64 << (bits - 1);
+    value >= -limit && value < limit
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+/// Compressed samples, along with the state needed to append new ones
+#[derive(Default, Debug)]
+pub struct Encoder {
+    writer: BitWriter,
+    count: u32,
+    ts: u64,
+    delta: i64,
+    value: u64,
+    leading: u32,
+    trailing: u32,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Samples appended so far
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Appends a sample, timestamps being expected in increasing order
+    pub fn push(&mut self, ts: u64, value: f64) {
+        let bits = value.to_bits();
+        if self.count == 0 {
+            self.writer.write_bits(ts, 64);
+            self.writer.write_bits(bits, 64);
+        } else {
+            let delta = ts.wrapping_sub(self.ts) as i64;
+            self.push_dod(delta.wrapping_sub(self.delta));
+            self.push_xor(bits ^ self.value);
+            self.delta = delta;
+        }
+        self.ts = ts;
+        self.value = bits;
+        self.count += 1;
+    }
+
+    fn push_dod(&mut self, dod: i64) {
+        if dod == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        for (control, control_bits, bits) in DOD_BUCKETS {
+            if fits(dod, bits) {
+                self.writer.write_bits(control, control_bits);
+                self.writer.write_bits(dod as u64, bits);
+                return;
+            }
+        }
+        self.writer.write_bits(0b1111, 4);
+        self.writer.write_bits(dod as u64, 64);
+    }
+
+    fn push_xor(&mut self, xor: u64) {
+        if xor == 0 {
+            self.writer.write_bit(false);
+            return;
+        }
+        self.writer.write_bit(true);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        // Reuses the previous meaningful bits when they fit
+        if self.count > 1 && leading >= self.leading &&
+            trailing >= self.trailing {
+            self.writer.write_bit(false);
+            let meaningful = 64 - self.leading - self.trailing;
+            self.writer.write_bits(xor >> self.trailing, meaningful);
+            return;
+        }
+        let meaningful = 64 - leading - trailing;
+        self.writer.write_bit(true);
+        self.writer.write_bits(leading as u64, 5);
+        // 64 meaningful bits are written as 0
+        self.writer.write_bits(meaningful as u64 & 0x3f, 6);
+        self.writer.write_bits(xor >> trailing, meaningful);
+        self.leading = leading;
+        self.trailing = trailing;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.writer.bytes
+    }
+}
+
+/// Decodes `count` samples compressed by an Encoder, none if the bytes
+/// are truncated
+pub fn decode(bytes: &[u8], count: u32) -> Option<Vec<(u64, f64)>> {
+    let mut reader = BitReader { bytes, pos: 0 };
+    let mut samples = Vec::with_capacity(count as usize);
+    if count == 0 {
+        return Some(samples);
+    }
+    let mut ts = reader.read_bits(64)?;
+    let mut value = reader.read_bits(64)?;
+    let mut delta = 0i64;
+    let (mut leading, mut trailing) = (0, 0);
+    samples.push((ts, f64::from_bits(value)));
+    for _ in 1..count {
+        let mut control = 0;
+        while control < 4 && reader.read_bit()? {
+            control += 1;
+        }
+        let dod = match control {
+            0 => 0,
+            4 => reader.read_bits(64)? as i64,
+            _ => {
+                let bits = DOD_BUCKETS[control - 1].2;
+                sign_extend(reader.read_bits(bits)?, bits)
+            },
+        };
+        delta = delta.wrapping_add(dod);
+        ts = ts.wrapping_add(delta as u64);
+        if reader.read_bit()? {
+            if reader.read_bit()? {
+                leading = reader.read_bits(5)? as u32;
+                let meaningful = match reader.read_bits(6)? as u32 {
+                    0 => 64,
+                    meaningful => meaningful,
+                };
+                trailing = 64 - leading - meaningful;
+            }
+            let meaningful = 64 - leading - trailing;
+            value ^= reader.read_bits(meaningful)? << trailing;
+        }
+        samples.push((ts, f64::from_bits(value)));
+    }
+    Some(samples)
+}
+
+#[test]
+fn test_gorilla() {
+    let mut samples: Vec<(u64, f64)> = Vec::new();
+    let start = 1_700_000_000_000u64;
+    for i in 0..100u64 {
+        // Sampled every second with some jitter and a gap
+        let ts = start + i*1000 + i % 3 + if i > 50 { 3_600_000 } else { 0 };
+        samples.push((ts, (i % 7) as f64*1.5));
+    }
+    samples.push((u64::MAX, f64::NAN));
+    samples.push((0, -0.));
+    let mut encoder = Encoder::new();
+    for (ts, value) in samples.iter() {
+        encoder.push(*ts, *value);
+    }
+    assert_eq!(encoder.len(), 102);
+    let decoded = decode(encoder.as_bytes(), encoder.len()).unwrap();
+    assert_eq!(decoded.len(), samples.len());
+    for (decoded, sample) in decoded.iter().zip(samples.iter()) {
+        assert_eq!(decoded.0, sample.0);
+        assert_eq!(decoded.1.to_bits(), sample.1.to_bits());
+    }
+    // Regular samples of a steady value take about 2 bits each
+    let mut encoder = Encoder::new();
+    for i in 0..1000u64 {
+        encoder.push(start + i*1000, 42.);
+    }
+    assert!(encoder.as_bytes().len() < 16 + 2000/8 + 8);
+    assert!(decode(&encoder.as_bytes()[..100], encoder.len()).is_none());
+}
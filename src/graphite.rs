@@ -0,0 +1,320 @@
+//! This module implements a sink pushing the statistics of the schema to a
+//! Graphite carbon endpoint over TCP, in the plaintext or pickle protocol.
+//! Datapoints are buffered while the endpoint is down, reconnecting with an
+//! increasing delay
+
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::Value;
+use crate::schema::{DefaultSchemaBuilder, SysinfoSchema};
+use crate::sink::{self, Batch, Sink};
+
+
+pub const DEFAULT_TEMPLATE: &str = "sysinfo.{host}.{path}";
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Datapoints kept while the endpoint is down, the oldest being dropped
+/// first
+pub const MAX_PENDING: usize = 200_000;
+/// Datapoints per pickle message
+const PICKLE_CHUNK: usize = 500;
+/// Datapoints per write in the plaintext protocol
+const PLAINTEXT_CHUNK: usize = 1000;
+const MIN_BACKOFF: u64 = 1000;
+const MAX_BACKOFF: u64 = 60*1000;
+const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum GraphiteProtocol {
+    /// One `path value timestamp` line per datapoint
+    #[default]
+    Plaintext,
+    /// Lists of datapoints pickled, each prefixed by its length
+    Pickle,
+}
+
+impl FromStr for GraphiteProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plaintext" => Ok(GraphiteProtocol::Plaintext),
+            "pickle" => Ok(GraphiteProtocol::Pickle),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for GraphiteProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphiteProtocol::Plaintext => write!(f, "plaintext"),
+            GraphiteProtocol::Pickle => write!(f, "pickle"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphiteConfig {
+    /// Address of the carbon endpoint, as HOST:PORT
+    pub addr: String,
+    pub protocol: GraphiteProtocol,
+    /// Path of the metrics, `{host}` being replaced by the host name and
+    /// `{path}` by the path of the statistic in the schema, such as
+    /// `cpu.cpu_usage.avg`
+    pub template: String,
+    /// Period between two pushes of the schema
+    pub interval: Duration,
+}
+
+/// Path of a metric, along with its Unix time in seconds and value
+type Datapoint = (String, u64, f64);
+type Encode = fn(&[Datapoint]) -> Vec<u8>;
+
+/// Collects the numbers of the serialized schema, keyed by their dotted
+/// path. Map keys, such as disk names, are sanitized
+fn flatten(value: &Value, path: &mut String, values: &mut Vec<(String, f64)>) {
+    match value {
+        Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                values.push((path.clone(), number));
+            }
+        },
+        Value::Object(map) => {
+            for (key, value) in map {
+                let len = path.len();
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(&sink::sanitize(key));
+                flatten(value, path, values);
+                path.truncate(len);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// Pickles the datapoints as a list of `(path, (timestamp, value))`, with
+/// protocol 2, prefixed by its length as carbon expects
+fn pickle(datapoints: &[Datapoint]) -> Vec<u8> {
+    let mut body = vec![0x80, 2, b']', b'('];
+    for (path, ts, value) in datapoints {
+        body.push(b'X');
+        body.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        body.extend_from_slice(path.as_bytes());
+        body.push(b'G');
+        body.extend_from_slice(&(*ts as f64).to_be_bytes());
+        body.push(b'G');
+        body.extend_from_slice(&value.to_be_bytes());
+        // Two TUPLE2, of the timestamp and value then of the path
+        body.extend_from_slice(&[0x86, 0x86]);
+    }
+    body.extend_from_slice(b"e.");
+    let mut message = (body.len() as u32).to_be_bytes().to_vec();
+    message.extend_from_slice(&body);
+    message
+}
+
+fn plaintext(datapoints: &[Datapoint]) -> Vec<u8> {
+    let mut lines = String::new();
+    for (path, ts, value) in datapoints {
+        lines.push_str(&format!("{} {} {}\n", path, value, ts));
+    }
+    lines.into_bytes()
+}
+
+pub struct GraphiteSink {
+    config: GraphiteConfig,
+    schema: Arc<DefaultSchemaBuilder>,
+    stream: Option<TcpStream>,
+    pending: Vec<Datapoint>,
+    /// Timestamp of the last push of the schema
+    last: Option<u64>,
+    /// Delay before the next attempt after a failure, and its timestamp
+    backoff: u64,
+    retry_at: Option<u64>,
+}
+
+impl GraphiteSink {
+    pub fn new(config: GraphiteConfig, schema: Arc<DefaultSchemaBuilder>)
+        -> Self {
+        GraphiteSink {
+            config,
+            schema,
+            stream: None,
+            pending: Vec::new(),
+            last: None,
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    /// Datapoints of the statistics of the schema, at `ts` in seconds
+    pub fn datapoints(&self, schema: &SysinfoSchema, host: &str, ts: u64)
+        -> Vec<Datapoint> {
+        let mut values = Vec::new();
+        if let Ok(value) = serde_json::to_value(schema) {
+            flatten(&value, &mut String::new(), &mut values);
+        }
+        let host = sink::sanitize(host);
+        let prefix = self.config.template.replace("{host}", &host);
+        values.into_iter()
+            .map(|(path, value)| (prefix.replace("{path}", &path), ts, value))
+            .collect()
+    }
+
+    fn connect(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            let addr = self.config.addr.to_socket_addrs()?.next()
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no address for {}", self.config.addr)))?;
+            let stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)?;
+            stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Sends the pending datapoints, those not sent being kept if the
+    /// endpoint failed
+    fn send(&mut self, ts: u64) -> io::Result<()> {
+        let (chunk, encode) = match self.config.protocol {
+            GraphiteProtocol::Plaintext => (PLAINTEXT_CHUNK, plaintext as Encode),
+            GraphiteProtocol::Pickle => (PICKLE_CHUNK, pickle as Encode),
+        };
+        let mut sent = 0;
+        let mut result = Ok(());
+        while sent < self.pending.len() {
+            let end = (sent + chunk).min(self.pending.len());
+            let message = encode(&self.pending[sent..end]);
+            result = self.connect()
+                .and_then(|stream| stream.write_all(&message));
+            if result.is_err() {
+                break;
+            }
+            sent = end;
+        }
+        self.pending.drain(..sent);
+        match result {
+            Ok(()) => {
+                self.backoff = MIN_BACKOFF;
+                self.retry_at = None;
+                Ok(())
+            },
+            Err(err) => {
+                self.stream = None;
+                self.retry_at = Some(ts + self.backoff);
+                self.backoff = (self.backoff*2).min(MAX_BACKOFF);
+                Err(err)
+            },
+        }
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn push(&mut self, batch: &Batch) -> io::Result<()> {
+        let interval = self.config.interval.as_millis() as u64;
+        if self.last.is_none_or(|last| batch.ts >= last + interval) {
+            if let Some(schema) = self.schema.get_full_payload() {
+                let datapoints = self.datapoints(&schema, &batch.host,
+                                                 batch.ts/1000);
+                self.pending.extend(datapoints);
+                self.last = Some(batch.ts);
+            }
+            if self.pending.len() > MAX_PENDING {
+                let excess = self.pending.len() - MAX_PENDING;
+                self.pending.drain(..excess);
+            }
+        }
+        if let Some(retry_at) = self.retry_at {
+            if batch.ts < retry_at {
+                // Still failing as far as the executor is concerned
+                return Err(io::Error::other(format!(
+                    "{} datapoints pending, reconnecting in {} ms",
+                    self.pending.len(), retry_at - batch.ts)));
+            }
+        }
+        self.send(batch.ts)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let ts = self.last.unwrap_or(0);
+        self.send(ts)?;
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_graphite_sink() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use crate::ringbuf::BufferSpec;
+    use crate::schema::SystatsSchemaBuilder;
+    use crate::systats::SystatsData;
+    let mut systats = SystatsData::new(&BufferSpec::new(4, false));
+    systats.build_dynamic_values(0, &vec!["/dev/sda1"], &vec![]);
+    systats.cpu_usage.push_back(25.);
+    systats.disk_usage.get_mut("/dev/sda1").unwrap().push_back(300);
+    let schema = Arc::new(DefaultSchemaBuilder::new());
+    schema.build(&systats);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let config = GraphiteConfig {
+        addr: addr.clone(),
+        protocol: GraphiteProtocol::Plaintext,
+        template: "servers.{host}.{path}".to_string(),
+        interval: Duration::from_secs(10),
+    };
+    let mut sink = GraphiteSink::new(config, Arc::clone(&schema));
+    let datapoints = sink.datapoints(&schema.get_full_payload().unwrap(),
+                                     "web 1", 1);
+    assert!(datapoints.contains(&("servers.web_1.cpu.cpu_usage.last".to_string(),
+                                  1, 25.)));
+    assert!(datapoints.contains(&("servers.web_1.disks.dev_sda1.last".to_string(),
+                                  1, 300.)));
+    let mut expected = vec![0, 0, 0, 34, 0x80, 2, b']', b'(', b'X', 3, 0, 0, 0];
+    expected.extend_from_slice(b"a.b");
+    expected.push(b'G');
+    expected.extend_from_slice(&1f64.to_be_bytes());
+    expected.push(b'G');
+    expected.extend_from_slice(&2f64.to_be_bytes());
+    expected.extend_from_slice(&[0x86, 0x86, b'e', b'.']);
+    assert_eq!(pickle(&[("a.b".to_string(), 1, 2.)]), expected);
+
+    // Buffered while the endpoint is down, then sent once reconnected
+    let batch = |ts| Batch { ts, host: "web 1".to_string(),
+                             samples: Vec::new() };
+    assert!(sink.push(&batch(0)).is_err());
+    let len = sink.pending.len();
+    assert!(len > 0);
+    assert!(sink.push(&batch(500)).is_err());
+    assert_eq!(sink.pending.len(), len);
+    let listener = TcpListener::bind(&addr).unwrap();
+    sink.push(&batch(1000)).unwrap();
+    assert!(sink.pending.is_empty());
+    // Only pushed again after the interval
+    sink.push(&batch(10000)).unwrap();
+    sink.flush().unwrap();
+    drop(sink);
+    let (stream, _) = listener.accept().unwrap();
+    let lines: Vec<String> = BufReader::new(stream).lines()
+        .map(|line| line.unwrap())
+        .collect();
+    assert_eq!(lines.len(), len*2);
+    assert!(lines.contains(&"servers.web_1.cpu.cpu_usage.last 25 0".to_string()));
+    assert!(lines.contains(&"servers.web_1.cpu.cpu_usage.last 25 10".to_string()));
+}
@@ -0,0 +1,257 @@
+//! This module implements the queries over the samples kept in the
+//! ring buffers, returning them paired with their timestamps. Ranges
+//! older than the buffers are answered from the on-disk store if any,
+//! from the rollups otherwise
+
+use std::io;
+use std::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use crate::systats::{SeriesRef, SystatsData};
+use crate::tsdb::Tsdb;
+
+
+/// Parameters of a history query. Timestamps are Unix time in
+/// milliseconds and `step` groups the samples in buckets of that many
+/// milliseconds, averaging them. A `window` keeps only the samples
+/// within its span of the last one
+#[derive(Deserialize, Default, Debug)]
+pub struct HistoryQuery {
+    pub name: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub step: Option<u64>,
+    pub window: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct History {
+    pub metric: String,
+    pub name: Option<String>,
+    /// Milliseconds averaged by the samples older than the buffer, none
+    /// if they are all raw samples
+    pub resolution: Option<u64>,
+    /// Pairs of timestamp and value
+    pub samples: Vec<(u64, f64)>,
+}
+
+fn downsample(samples: Vec<(u64, f64)>, step: u64) -> Vec<(u64, f64)> {
+    let mut result: Vec<(u64, f64)> = Vec::new();
+    let mut bucket: Option<(u64, f64, usize)> = None;
+    for (ts, value) in samples {
+        let start = ts - ts % step;
+        bucket = match bucket {
+            Some((b_start, sum, count)) if b_start == start => {
+                Some((b_start, sum + value, count + 1))
+            },
+            Some((b_start, sum, count)) => {
+                result.push((b_start, sum/count as f64));
+                Some((start, value, 1))
+            },
+            None => Some((start, value, 1)),
+        };
+    }
+    if let Some((b_start, sum, count)) = bucket {
+        result.push((b_start, sum/count as f64));
+    }
+    result
+}
+
+/// Timestamps of the requested range, a window starting within its span
+/// of the last sample
+fn range(stats: &SystatsData, params: &HistoryQuery) -> (u64, u64) {
+    let mut from = params.from.unwrap_or(u64::MIN);
+    let window = params.window.as_deref()
+        .and_then(|name| stats.spec.windows.iter()
+                  .find(|window| &*window.name == name));
+    if let (Some(window), Some(last)) = (window, stats.timestamp.get_last()) {
+        let start = last.saturating_sub(window.span.as_millis() as u64);
+        from = from.max(start);
+    }
+    (from, params.to.unwrap_or(u64::MAX))
+}
+
+/// Returns the timestamp of the first sample of the series, if any, and
+/// its samples within the range paired with their timestamps
+fn buffered(stats: &SystatsData, series: &SeriesRef, from: u64, to: u64)
+    -> (Option<u64>, Vec<(u64, f64)>) {
+    let times = match stats.times.get(&(series.metric, series.label.clone())) {
+        Some(times) => times,
+        None => return (None, Vec::new()),
+    };
+    // Samples pushed before the series was first stamped have no time
+    let len = series.samples.len().min(times.len());
+    let skip = series.samples.len() - len;
+    let skip_times = times.len() - len;
+    let mut samples: Vec<(u64, f64)> = Vec::new();
+    for index in 0..len {
+        let ts = match times.get(skip_times + index) {
+            Some(ts) if ts >= from && ts <= to => ts,
+            _ => continue,
+        };
+        if let Some(value) = series.samples.get_f64(skip + index) {
+            samples.push((ts, value));
+        }
+    }
+    (times.get(skip_times), samples)
+}
+
+/// Whether the query explicitly starts before the first sample of the
+/// buffer, a query without `from` being answered by the buffer alone
+fn before_buffer(params: &HistoryQuery, from: u64, first: u64) -> bool {
+    params.from.is_some() && from < first
+}
+
+fn history(metric: &str, params: &HistoryQuery, resolution: Option<u64>,
+           mut samples: Vec<(u64, f64)>) -> History {
+    if let Some(step) = params.step {
+        if step > 0 {
+            samples = downsample(samples, step);
+        }
+    }
+    History {
+        metric: metric.to_string(),
+        name: params.name.clone(),
+        resolution,
+        samples,
+    }
+}
+
+/// Returns the samples of `metric` within the requested range, or None if
+/// there is no such metric. Disks and interfaces are selected by name
+pub fn query(stats: &SystatsData, metric: &str, params: &HistoryQuery)
+    -> Option<History> {
+    let series = stats.get_series(metric, params.name.as_deref())?;
+    let (from, to) = range(stats, params);
+    let (first, mut samples) = buffered(stats, &series, from, to);
+    let mut resolution = None;
+    let rollups = stats.rollups.get(&(series.metric, series.label.clone()));
+    match (first, rollups) {
+        (Some(first), Some(rollups)) if before_buffer(params, from, first) => {
+            // Rollups up to the buffer, followed by its raw samples
+            if let Some(tier) = rollups.tier_since(from) {
+                resolution = Some(tier.resolution);
+                let mut older: Vec<(u64, f64)> = tier.iter()
+                    .filter(|r| r.start + tier.resolution > from &&
+                            r.start < first && r.start <= to)
+                    .map(|r| (r.start, r.avg()))
+                    .collect();
+                older.append(&mut samples);
+                samples = older;
+            }
+        },
+        _ => (),
+    }
+    Some(history(metric, params, resolution, samples))
+}
+
+/// Answers from the on-disk store the queries of ranges older than the
+/// buffers, followed by the samples of the buffers not appended to it
+/// yet. None if the buffers hold the range or there is no such metric.
+/// They are only locked while taking their samples
+pub fn query_store(systats: &RwLock<SystatsData>, tsdb: &Tsdb, metric: &str,
+                   params: &HistoryQuery) -> io::Result<Option<History>> {
+    let (from, to, buffered) = match systats.read() {
+        Ok(stats) => {
+            let series = match stats.get_series(metric, params.name.as_deref()) {
+                Some(series) => series,
+                None => return Ok(None),
+            };
+            let (from, to) = range(&stats, params);
+            match buffered(&stats, &series, from, to) {
+                (Some(first), samples) if before_buffer(params, from, first) => {
+                    (from, to, samples)
+                },
+                _ => return Ok(None),
+            }
+        },
+        Err(_) => return Ok(None),
+    };
+    let mut samples = tsdb.query(metric, params.name.as_deref(), from, to)?;
+    let last = samples.last().map(|(ts, _)| *ts);
+    samples.extend(buffered.into_iter()
+                   .filter(|(ts, _)| last.is_none_or(|last| *ts > last)));
+    Ok(Some(history(metric, params, None, samples)))
+}
+
+#[test]
+fn test_history_query() {
+    use std::time::Duration;
+    use crate::ringbuf::{BufferSpec, Window};
+    let window = Window { name: "1s".into(), span: Duration::from_secs(1),
+                          len: 2 };
+    let spec = BufferSpec { capacity: 8, rst: false, windows: vec![window],
+                            ..Default::default() };
+    let mut stats = SystatsData::new(&spec);
+    stats.build_dynamic_values(0, &vec!["sda1"], &vec![]);
+    for i in 0..6u64 {
+        stats.timestamp.push_back(1000 + i*500);
+        stats.cpu_usage.push_back(i as f32);
+        // Found on the third sample, then missing the fifth
+        if i >= 2 && i != 4 {
+            stats.disk_usage.get_mut("sda1").unwrap().push_back(i*10);
+        }
+        stats.push_times(1000 + i*500);
+    }
+    let params = HistoryQuery { from: Some(1500), to: Some(3000),
+                                ..Default::default() };
+    let history = query(&stats, "cpu_usage", &params).unwrap();
+    assert_eq!(history.samples,
+               vec![(1500, 1.), (2000, 2.), (2500, 3.), (3000, 4.)]);
+    let params = HistoryQuery { name: Some("sda1".to_string()),
+                                ..Default::default() };
+    let history = query(&stats, "disk_usage", &params).unwrap();
+    assert_eq!(history.samples, vec![(2000, 20.), (2500, 30.), (3500, 50.)]);
+    let params = HistoryQuery { step: Some(1000), ..Default::default() };
+    let history = query(&stats, "cpu_usage", &params).unwrap();
+    assert_eq!(history.samples,
+               vec![(1000, 0.5), (2000, 2.5), (3000, 4.5)]);
+    let params = HistoryQuery { window: Some("1s".to_string()),
+                                ..Default::default() };
+    let history = query(&stats, "cpu_usage", &params).unwrap();
+    assert_eq!(history.samples, vec![(2500, 3.), (3000, 4.), (3500, 5.)]);
+    assert!(query(&stats, "disk_usage", &HistoryQuery::default()).is_none());
+    // Older than the buffer
+    stats.push_rollups(3500);
+    let params = HistoryQuery { from: Some(0), ..Default::default() };
+    let history = query(&stats, "cpu_usage", &params).unwrap();
+    assert_eq!(history.resolution, Some(60*1000));
+    assert_eq!(history.samples[..2], [(0, 5.), (1000, 0.)]);
+    assert_eq!(history.samples.len(), 7);
+    // Only from the rollups when asked for older samples
+    let history = query(&stats, "cpu_usage", &HistoryQuery::default())
+        .unwrap();
+    assert_eq!(history.resolution, None);
+    assert_eq!(history.samples.len(), 6);
+    assert!(query(&stats, "unknown", &HistoryQuery::default()).is_none());
+    // Older than the buffer, from the on-disk store and the samples not
+    // appended to it yet
+    let dir = std::env::temp_dir()
+        .join(format!("sysinfo-history-{}", std::process::id()));
+    let tsdb = Tsdb::open(&dir, Duration::from_secs(3600), None).unwrap();
+    let mut stats = SystatsData::new(&BufferSpec::new(2, false));
+    let mut tracker = crate::sink::SampleTracker::new();
+    for i in 0..4u64 {
+        let ts = 1000 + i*500;
+        stats.timestamp.push_back(ts);
+        stats.cpu_usage.push_back(i as f32);
+        stats.push_times(ts);
+        tsdb.push(&tracker.take(&stats, ts)).unwrap();
+        if i == 1 {
+            tsdb.flush().unwrap();
+        }
+    }
+    let systats = RwLock::new(stats);
+    let params = HistoryQuery { from: Some(0), ..Default::default() };
+    let history = query_store(&systats, &tsdb, "cpu_usage", &params)
+        .unwrap().unwrap();
+    assert_eq!(history.resolution, None);
+    assert_eq!(history.samples,
+               vec![(1000, 0.), (1500, 1.), (2000, 2.), (2500, 3.)]);
+    let params = HistoryQuery { from: Some(2000), ..Default::default() };
+    assert!(query_store(&systats, &tsdb, "cpu_usage", &params).unwrap()
+            .is_none());
+    // Without from, the buffer is enough
+    assert!(query_store(&systats, &tsdb, "cpu_usage", &HistoryQuery::default())
+            .unwrap().is_none());
+    let _ = std::fs::remove_dir_all(&dir);
+}
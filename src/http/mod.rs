@@ -1 +1,3 @@
 pub mod server;
+pub mod prometheus;
+pub mod stream;
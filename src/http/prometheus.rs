@@ -0,0 +1,473 @@
+//! Rendering of the SysinfoSchema in the Prometheus text exposition
+//! format (version 0.0.4)
+
+use std::fmt::Write;
+use num_traits::ToPrimitive;
+use crate::schema::{DiskIo, Metric, Net, SysinfoSchema};
+
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const PREFIX: &str = "sysinfo";
+const KIB: f64 = 1024.;
+const MHZ: f64 = 1_000_000.;
+const STATS: [&str; 4] = ["max", "min", "avg", "last"];
+
+enum MetricType {
+    Gauge,
+    Counter,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0. { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: MetricType) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind.as_str());
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, &str)],
+                value: f64) {
+    let _ = write!(out, "{}_{}", PREFIX, name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", format_value(value));
+}
+
+/// Writes one series per statistic of the metric, distinguished by the
+/// `stat` label, percentiles, standard deviation, rate and moving averages
+/// once computed. Values are multiplied by `scale` to get base units.
+/// Unavailable metrics have no series.
+fn write_metric<T>(out: &mut String, name: &str, labels: &[(&str, &str)],
+                   metric: &Metric<T>, scale: f64) where T: ToPrimitive {
+    if !metric.available {
+        return;
+    }
+    let values = [&metric.max, &metric.min, &metric.avg, &metric.last];
+    let mut stats: Vec<(String, f64)> = STATS.iter().zip(values.iter())
+        .map(|(stat, value)| (stat.to_string(),
+                              value.to_f64().unwrap_or(f64::NAN)))
+        .collect();
+    let distribution = [("p50", &metric.p50), ("p90", &metric.p90),
+                        ("p95", &metric.p95), ("p99", &metric.p99)];
+    for (stat, value) in distribution {
+        if let Some(value) = value {
+            stats.push((stat.to_string(), value.to_f64().unwrap_or(f64::NAN)));
+        }
+    }
+    if let Some(stddev) = metric.stddev {
+        stats.push(("stddev".to_string(), stddev));
+    }
+    if let Some(rate) = metric.rate {
+        stats.push(("rate".to_string(), rate));
+    }
+    for (name, value) in metric.ewma.iter() {
+        stats.push((format!("ewma_{}", name), *value));
+    }
+    for (stat, value) in stats.iter() {
+        let mut series_labels = labels.to_vec();
+        series_labels.push(("stat", stat));
+        write_sample(out, name, &series_labels, *value*scale);
+    }
+}
+
+fn render_system(out: &mut String, schema: &SysinfoSchema) {
+    write_header(out, "info", "System information", MetricType::Gauge);
+    write_sample(out, "info", &[("name", &schema.system.name)], 1.);
+    write_header(out, "uptime_seconds_total", "System uptime",
+                 MetricType::Counter);
+    write_sample(out, "uptime_seconds_total", &[],
+                 schema.system.uptime as f64);
+}
+
+fn render_cpu(out: &mut String, schema: &SysinfoSchema) {
+    let cpu = &schema.cpu;
+    write_header(out, "cpu_cores", "Number of physical CPU cores",
+                 MetricType::Gauge);
+    write_sample(out, "cpu_cores", &[], cpu.cpu_cores as f64);
+    write_header(out, "cpu_usage_percent", "Global CPU usage",
+                 MetricType::Gauge);
+    write_metric(out, "cpu_usage_percent", &[], &cpu.cpu_usage, 1.);
+    write_header(out, "cpu_frequency_hertz", "Global CPU frequency",
+                 MetricType::Gauge);
+    write_metric(out, "cpu_frequency_hertz", &[], &cpu.cpu_freq, MHZ);
+    let mut cores: Vec<_> = cpu.cores.iter().collect();
+    cores.sort_by_key(|(id, _)| **id);
+    let cores: Vec<_> = cores.into_iter()
+        .map(|(id, core)| (id.to_string(), core))
+        .collect();
+    write_header(out, "cpu_core_usage_percent", "Usage of the logical CPU",
+                 MetricType::Gauge);
+    for (id, core) in cores.iter() {
+        write_metric(out, "cpu_core_usage_percent", &[("cpu", id)],
+                     &core.cpu_usage, 1.);
+    }
+    write_header(out, "cpu_core_frequency_hertz",
+                 "Frequency of the logical CPU", MetricType::Gauge);
+    for (id, core) in cores.iter() {
+        write_metric(out, "cpu_core_frequency_hertz", &[("cpu", id)],
+                     &core.cpu_freq, MHZ);
+    }
+    write_header(out, "cpu_core_info",
+                 "Scaling governor and driver of the logical CPU",
+                 MetricType::Gauge);
+    for (id, core) in cores.iter() {
+        write_sample(out, "cpu_core_info",
+                     &[("cpu", id), ("governor", &core.governor),
+                       ("driver", &core.driver)], 1.);
+    }
+}
+
+fn render_mem(out: &mut String, schema: &SysinfoSchema) {
+    let mem = &schema.mem;
+    write_header(out, "memory_total_bytes", "Total memory",
+                 MetricType::Gauge);
+    write_sample(out, "memory_total_bytes", &[], mem.total_mem as f64*KIB);
+    write_header(out, "swap_total_bytes", "Total swap", MetricType::Gauge);
+    write_sample(out, "swap_total_bytes", &[], mem.total_swap as f64*KIB);
+    let metrics = [
+        ("memory_free_bytes", "Free memory", &mem.mem_free),
+        ("memory_used_bytes", "Used memory", &mem.mem_used),
+        ("memory_available_bytes", "Available memory", &mem.mem_available),
+        ("memory_buffer_bytes", "Buffer and cache memory", &mem.mem_buffer),
+        ("memory_buffers_bytes", "Memory used by block device buffers",
+         &mem.mem_buffers),
+        ("memory_cached_bytes", "Memory used by the page cache",
+         &mem.mem_cached),
+        ("memory_shared_bytes", "Shared memory", &mem.mem_shared),
+        ("memory_slab_bytes", "Kernel slab memory", &mem.mem_slab),
+        ("memory_dirty_bytes", "Memory waiting to be written back",
+         &mem.mem_dirty),
+        ("memory_writeback_bytes", "Memory being written back",
+         &mem.mem_writeback),
+        ("swap_used_bytes", "Used swap", &mem.swap_used),
+    ];
+    for (name, help, metric) in metrics.iter() {
+        write_header(out, name, help, MetricType::Gauge);
+        write_metric(out, name, &[], metric, KIB);
+    }
+    write_header(out, "swap_in_bytes_per_second", "Bytes swapped in",
+                 MetricType::Gauge);
+    write_metric(out, "swap_in_bytes_per_second", &[], &mem.swap_in, 1.);
+    write_header(out, "swap_out_bytes_per_second", "Bytes swapped out",
+                 MetricType::Gauge);
+    write_metric(out, "swap_out_bytes_per_second", &[], &mem.swap_out, 1.);
+    write_header(out, "hugepages_total", "Huge pages in the pool",
+                 MetricType::Gauge);
+    write_metric(out, "hugepages_total", &[], &mem.hugepages_total, 1.);
+    write_header(out, "hugepages_free", "Huge pages not allocated",
+                 MetricType::Gauge);
+    write_metric(out, "hugepages_free", &[], &mem.hugepages_free, 1.);
+    write_header(out, "hugepage_size_bytes", "Size of a huge page",
+                 MetricType::Gauge);
+    write_sample(out, "hugepage_size_bytes", &[], mem.hugepage_size as f64*KIB);
+}
+
+fn render_load(out: &mut String, schema: &SysinfoSchema) {
+    let load = &schema.load;
+    write_header(out, "load_average", "Load average of the system",
+                 MetricType::Gauge);
+    write_metric(out, "load_average", &[("period", "1m")], &load.load1, 1.);
+    write_metric(out, "load_average", &[("period", "5m")], &load.load5, 1.);
+    write_metric(out, "load_average", &[("period", "15m")], &load.load15, 1.);
+    write_header(out, "procs_running", "Processes in runnable state",
+                 MetricType::Gauge);
+    write_metric(out, "procs_running", &[], &load.procs_running, 1.);
+    write_header(out, "procs_blocked", "Processes blocked waiting for I/O",
+                 MetricType::Gauge);
+    write_metric(out, "procs_blocked", &[], &load.procs_blocked, 1.);
+    let rates = [
+        ("forks_per_second", "Processes created per second", &load.forks),
+        ("context_switches_per_second", "Context switches per second",
+         &load.context_switches),
+        ("interrupts_per_second", "Interrupts serviced per second",
+         &load.interrupts),
+    ];
+    for (name, help, metric) in rates.iter() {
+        write_header(out, name, help, MetricType::Gauge);
+        write_metric(out, name, &[], metric, 1.);
+    }
+}
+
+fn render_pressure(out: &mut String, schema: &SysinfoSchema) {
+    let psi = &schema.pressure;
+    let resources = [("cpu", &psi.cpu), ("memory", &psi.memory), ("io", &psi.io)];
+    write_header(out, "pressure_available",
+                 "Whether the kernel reports the pressure of the resource",
+                 MetricType::Gauge);
+    for (resource, pressure) in resources.iter() {
+        write_sample(out, "pressure_available", &[("resource", resource)],
+                     if pressure.available { 1. } else { 0. });
+    }
+    let available: Vec<_> = resources.iter()
+        .filter(|(_, pressure)| pressure.available)
+        .collect();
+    write_header(out, "pressure_stall_percent",
+                 "Share of time tasks stalled on the resource, averaged over \
+                 the period", MetricType::Gauge);
+    for (resource, pressure) in available.iter() {
+        for (kind, line) in [("some", &pressure.some), ("full", &pressure.full)] {
+            for (period, metric) in [("10s", &line.avg10), ("60s", &line.avg60),
+                                     ("300s", &line.avg300)] {
+                write_metric(out, "pressure_stall_percent",
+                             &[("resource", resource), ("kind", kind),
+                               ("period", period)], metric, 1.);
+            }
+        }
+    }
+    write_header(out, "pressure_stall_seconds",
+                 "Time tasks stalled on the resource between samples",
+                 MetricType::Gauge);
+    for (resource, pressure) in available.iter() {
+        for (kind, line) in [("some", &pressure.some), ("full", &pressure.full)] {
+            write_metric(out, "pressure_stall_seconds",
+                         &[("resource", resource), ("kind", kind)],
+                         &line.stall_us, 0.000_001);
+        }
+    }
+}
+
+fn render_disks(out: &mut String, schema: &SysinfoSchema) {
+    let mut disks: Vec<_> = schema.disks.iter().collect();
+    disks.sort_by(|a, b| a.0.cmp(b.0));
+    write_header(out, "disk_used_bytes", "Used space of the disk",
+                 MetricType::Gauge);
+    for (name, metric) in disks {
+        write_metric(out, "disk_used_bytes", &[("device", name)], metric, 1.);
+    }
+}
+
+type DiskIoMetric = fn(&DiskIo) -> &Metric<f64>;
+
+const DISKIO_METRICS: [(&str, &str, DiskIoMetric); 7] = [
+    ("disk_io_reads_per_second", "Read requests completed per second",
+     |d| &d.read_iops),
+    ("disk_io_writes_per_second", "Write requests completed per second",
+     |d| &d.write_iops),
+    ("disk_io_read_bytes_per_second", "Bytes read per second",
+     |d| &d.read_bytes),
+    ("disk_io_written_bytes_per_second", "Bytes written per second",
+     |d| &d.write_bytes),
+    ("disk_io_await_seconds", "Average time for the requests to be served",
+     |d| &d.await_ms),
+    ("disk_io_queue_depth", "Average number of requests in the queue",
+     |d| &d.queue_depth),
+    ("disk_io_utilization_percent", "Percentage of time the device was busy",
+     |d| &d.utilization),
+];
+
+fn render_diskio(out: &mut String, schema: &SysinfoSchema) {
+    let mut devices: Vec<_> = schema.diskio.iter().collect();
+    devices.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, help, metric) in DISKIO_METRICS.iter() {
+        let scale = if name.ends_with("_seconds") { 0.001 } else { 1. };
+        write_header(out, name, help, MetricType::Gauge);
+        for (device, diskio) in devices.iter() {
+            write_metric(out, name, &[("device", device)], metric(diskio),
+                         scale);
+        }
+    }
+}
+
+type NetCounter = fn(&Net) -> (&Metric<f64>, u64);
+
+const NET_COUNTERS: [(&str, &str, NetCounter); 8] = [
+    ("receive_bytes", "bytes received", |n| (&n.rx_bytes, n.rx_bytes_total)),
+    ("transmit_bytes", "bytes transmitted",
+     |n| (&n.tx_bytes, n.tx_bytes_total)),
+    ("receive_packets", "packets received",
+     |n| (&n.rx_packets, n.rx_packets_total)),
+    ("transmit_packets", "packets transmitted",
+     |n| (&n.tx_packets, n.tx_packets_total)),
+    ("receive_errors", "receive errors",
+     |n| (&n.rx_errors, n.rx_errors_total)),
+    ("transmit_errors", "transmit errors",
+     |n| (&n.tx_errors, n.tx_errors_total)),
+    ("receive_drop", "packets dropped on receive",
+     |n| (&n.rx_drops, n.rx_drops_total)),
+    ("transmit_drop", "packets dropped on transmit",
+     |n| (&n.tx_drops, n.tx_drops_total)),
+];
+
+fn render_networks(out: &mut String, schema: &SysinfoSchema) {
+    let mut networks: Vec<_> = schema.networks.iter().collect();
+    networks.sort_by(|a, b| a.0.cmp(b.0));
+    write_header(out, "network_info", "MAC address of the interface",
+                 MetricType::Gauge);
+    for (ifname, net) in networks.iter() {
+        write_sample(out, "network_info",
+                     &[("interface", ifname), ("address", &net.address)], 1.);
+    }
+    for (name, help, counter) in NET_COUNTERS.iter() {
+        let total_name = format!("network_{}_total", name);
+        write_header(out, &total_name,
+                     &format!("Total {} by the interface", help),
+                     MetricType::Counter);
+        for (ifname, net) in networks.iter() {
+            write_sample(out, &total_name, &[("interface", ifname)],
+                         counter(net).1 as f64);
+        }
+        let rate_name = format!("network_{}_per_second", name);
+        write_header(out, &rate_name,
+                     &format!("Rate of {} by the interface", help),
+                     MetricType::Gauge);
+        for (ifname, net) in networks.iter() {
+            write_metric(out, &rate_name, &[("interface", ifname)],
+                         counter(net).0, 1.);
+        }
+    }
+}
+
+fn render_status(out: &mut String, schema: &SysinfoSchema) {
+    let mut status: Vec<_> = schema.status.iter().collect();
+    status.sort_by(|a, b| a.0.cmp(b.0));
+    write_header(out, "collector_up",
+                 "Whether the last collection of the collector succeeded",
+                 MetricType::Gauge);
+    for (name, collector) in status.iter() {
+        write_sample(out, "collector_up", &[("collector", name)],
+                     if collector.ok { 1. } else { 0. });
+    }
+}
+
+/// Renders every value of the schema in the Prometheus text format
+pub fn render(schema: &SysinfoSchema) -> String {
+    let mut out = String::new();
+    render_system(&mut out, schema);
+    render_cpu(&mut out, schema);
+    render_mem(&mut out, schema);
+    render_load(&mut out, schema);
+    render_pressure(&mut out, schema);
+    render_disks(&mut out, schema);
+    render_diskio(&mut out, schema);
+    render_networks(&mut out, schema);
+    render_status(&mut out, schema);
+    out
+}
+
+#[test]
+fn test_render() {
+    use crate::schema::{CollectorStatus, Core, WindowMetric, WindowSelect};
+    let mut schema = SysinfoSchema::new();
+    schema.system.name = "Arch \"Linux\"".to_string();
+    schema.system.uptime = 42;
+    schema.cpu.cpu_usage = Metric { max: 50.5, min: 1., avg: 10., last: 2.5,
+                                    p99: Some(45.), rate: Some(-0.5),
+                                    available: true,
+                                    ewma: vec![("5m".into(), 12.5)],
+                                    windows: vec![("5m".into(), WindowMetric {
+                                        max: 20., min: 2., avg: 8. })],
+                                    ..Default::default() };
+    schema.cpu.cores.insert(3, Core {
+        cpu_usage: Metric { max: 99., min: 0., avg: 40., last: 98.,
+                            available: true, ..Default::default() },
+        cpu_freq: Metric { max: 3000, min: 800, avg: 1200, last: 2800,
+                           available: true, ..Default::default() },
+        governor: "schedutil".to_string(),
+        driver: "intel_pstate".to_string(),
+    });
+    schema.mem.total_mem = 2;
+    schema.load.load5.last = 0.75;
+    schema.load.load5.available = true;
+    schema.pressure.io.available = true;
+    schema.pressure.io.full.avg60.max = 12.5;
+    schema.pressure.io.full.avg60.available = true;
+    schema.status.insert("pressure".to_string(), CollectorStatus {
+        ok: false, error: Some("failed to read".to_string()), failures: 3,
+        last_success: None });
+    schema.disks.insert("/dev/sda1".to_string(),
+                        Metric { max: 30, min: 10, avg: 20, last: 15,
+                                 available: true, ..Default::default() });
+    let out = render(&schema);
+    assert!(out.contains("sysinfo_info{name=\"Arch \\\"Linux\\\"\"} 1\n"));
+    assert!(out.contains("# TYPE sysinfo_uptime_seconds_total counter\n"));
+    assert!(out.contains("sysinfo_uptime_seconds_total 42\n"));
+    assert!(out.contains("# TYPE sysinfo_cpu_usage_percent gauge\n"));
+    assert!(out.contains("sysinfo_cpu_usage_percent{stat=\"max\"} 50.5\n"));
+    assert!(out.contains("sysinfo_cpu_usage_percent{stat=\"last\"} 2.5\n"));
+    assert!(out.contains("sysinfo_cpu_usage_percent{stat=\"p99\"} 45\n"));
+    assert!(!out.contains("sysinfo_cpu_usage_percent{stat=\"p50\"}"));
+    assert!(out.contains("sysinfo_cpu_usage_percent{stat=\"rate\"} -0.5\n"));
+    assert!(out.contains(
+        "sysinfo_cpu_usage_percent{stat=\"ewma_5m\"} 12.5\n"));
+    assert!(out.contains(
+        "sysinfo_cpu_core_usage_percent{cpu=\"3\",stat=\"last\"} 98\n"));
+    assert!(out.contains(
+        "sysinfo_cpu_core_frequency_hertz{cpu=\"3\",stat=\"min\"} 800000000\n"));
+    assert!(out.contains("sysinfo_cpu_core_info{cpu=\"3\",governor=\"schedutil\",\
+                         driver=\"intel_pstate\"} 1\n"));
+    assert!(out.contains("sysinfo_memory_total_bytes 2048\n"));
+    // Unavailable metrics only have their header
+    assert!(out.contains("# TYPE sysinfo_memory_free_bytes gauge\n"));
+    assert!(!out.contains("sysinfo_memory_free_bytes{"));
+    assert!(out.contains("sysinfo_collector_up{collector=\"pressure\"} 0\n"));
+    assert!(out.contains(
+        "sysinfo_load_average{period=\"5m\",stat=\"last\"} 0.75\n"));
+    assert!(out.contains("sysinfo_pressure_available{resource=\"cpu\"} 0\n"));
+    assert!(out.contains("sysinfo_pressure_stall_percent{resource=\"io\",\
+                         kind=\"full\",period=\"60s\",stat=\"max\"} 12.5\n"));
+    assert!(!out.contains("sysinfo_pressure_stall_percent{resource=\"cpu\""));
+    assert!(out.contains(
+        "sysinfo_disk_used_bytes{device=\"/dev/sda1\",stat=\"avg\"} 20\n"));
+    // Statistics of an aggregation window
+    let mut windowed = schema.clone();
+    windowed.select_window("5m");
+    let out = render(&windowed);
+    assert!(out.contains("sysinfo_cpu_usage_percent{stat=\"max\"} 20\n"));
+    assert!(out.contains("sysinfo_cpu_usage_percent{stat=\"last\"} 2.5\n"));
+    assert!(out.contains(
+        "sysinfo_disk_used_bytes{device=\"/dev/sda1\",stat=\"avg\"} 20\n"));
+    let mut diskio = DiskIo::new();
+    diskio.await_ms.last = 1500.;
+    diskio.await_ms.available = true;
+    schema.diskio.insert("sda".to_string(), diskio);
+    let mut net = Net::new();
+    net.rx_bytes_total = 1024;
+    net.tx_drops.max = 2.5;
+    net.tx_drops.available = true;
+    net.address = "00:11:22:33:44:55".to_string();
+    schema.networks.insert("eth0".to_string(), net);
+    let out = render(&schema);
+    assert!(out.contains(
+        "sysinfo_disk_io_await_seconds{device=\"sda\",stat=\"last\"} 1.5\n"));
+    assert!(out.contains("# TYPE sysinfo_network_receive_bytes_total counter\n"));
+    assert!(out.contains(
+        "sysinfo_network_receive_bytes_total{interface=\"eth0\"} 1024\n"));
+    assert!(out.contains(
+        "sysinfo_network_transmit_drop_per_second{interface=\"eth0\",stat=\"max\"} 2.5\n"));
+    assert!(out.contains(
+        "sysinfo_network_info{interface=\"eth0\",address=\"00:11:22:33:44:55\"} 1\n"));
+}
@@ -1,51 +1,241 @@
 
-use std::sync::{mpsc, Arc};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, RwLock};
 use std::thread;
-use mpsc::{Sender, Receiver, channel};
+use mpsc::{Sender, channel};
 use actix_web::{dev::Server, middleware, rt, web, App,
                 HttpResponse, HttpServer};
-use crate::schema::DefaultSchemaBuilder;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use crate::SysinfoOpts;
+use crate::schema::{DefaultSchemaBuilder, WindowSelect};
+use crate::systats::SystatsData;
+use crate::tsdb::Tsdb;
+use crate::history::{self, HistoryQuery};
+use crate::http::prometheus;
+use crate::http::stream::{self as sse, StreamQuery};
 
 
-async fn route_full_info(schemab: web::Data<Arc<DefaultSchemaBuilder>>) -> HttpResponse {
-    if let Some(payload) = schemab.get_full_payload() {
-        return HttpResponse::Ok().json(payload);
+static DASHBOARD_HTML: &str = include_str!("static/dashboard.html");
+static DASHBOARD_JS: &str = include_str!("static/dashboard.js");
+
+
+/// Takes the statistics of the payloads from an aggregation window
+#[derive(Deserialize, Default, Debug)]
+struct WindowQuery {
+    window: Option<String>,
+}
+
+struct ServerConfig {
+    listen_addrs: Vec<SocketAddr>,
+    unix_socket: Option<PathBuf>,
+    unix_socket_mode: u32,
+    workers: usize,
+}
+
+
+async fn route_dashboard() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DASHBOARD_HTML)
+}
+
+async fn route_dashboard_js() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/javascript; charset=utf-8")
+        .body(DASHBOARD_JS)
+}
+
+fn unknown_window(name: &str) -> HttpResponse {
+    HttpResponse::BadRequest().body(format!("Unknown window: {}", name))
+}
+
+/// Responds with the payload, its statistics taken from the requested
+/// window if any
+fn windowed_json<T>(schemab: &DefaultSchemaBuilder, payload: Option<T>,
+                    params: &WindowQuery) -> HttpResponse
+where T: Serialize + WindowSelect {
+    let mut payload = match payload {
+        Some(payload) => payload,
+        None => {
+            return HttpResponse::ServiceUnavailable()
+                .body("Internal Error".to_string());
+        }
+    };
+    if let Some(window) = &params.window {
+        if !schemab.has_window(window) {
+            return unknown_window(window);
+        }
+        payload.select_window(window);
     }
-    HttpResponse::ServiceUnavailable().body("Internal Error".to_string())
+    HttpResponse::Ok().json(payload)
+}
+
+async fn route_full_info(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                         params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_full_payload(), &params)
+}
+
+async fn route_cpu(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                   params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_cpu_payload(), &params)
 }
 
-async fn route_cpu(schemab: web::Data<Arc<DefaultSchemaBuilder>>) -> HttpResponse {
-    if let Some(payload) = schemab.get_cpu_payload() {
-        return HttpResponse::Ok().json(payload);
+async fn route_cpu_core(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                        id: web::Path<usize>,
+                        params: web::Query<WindowQuery>) -> HttpResponse {
+    if let Some(payload) = schemab.get_cpu_core_payload(*id) {
+        return windowed_json(&schemab, Some(payload), &params);
     }
-    HttpResponse::ServiceUnavailable().body("Internal Error".to_string())
+    HttpResponse::NotFound().body(format!("Unknown CPU: {}", id))
+}
+
+async fn route_load(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                    params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_load_payload(), &params)
+}
+
+async fn route_pressure(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                        params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_pressure_payload(), &params)
+}
+
+async fn route_mem(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                   params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_mem_payload(), &params)
+}
+
+async fn route_disks(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                     params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_disks_payload(), &params)
+}
+
+async fn route_diskio(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                      params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_diskio_payload(), &params)
+}
+
+async fn route_networks(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                        params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_networks_payload(), &params)
+}
+
+async fn route_status(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                      params: web::Query<WindowQuery>) -> HttpResponse {
+    windowed_json(&schemab, schemab.get_status_payload(), &params)
 }
 
-async fn route_mem(schemab: web::Data<Arc<DefaultSchemaBuilder>>) -> HttpResponse {
-    if let Some(payload) = schemab.get_mem_payload() {
-        return HttpResponse::Ok().json(payload);
+async fn route_metrics(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                       params: web::Query<WindowQuery>) -> HttpResponse {
+    if let Some(mut payload) = schemab.get_full_payload() {
+        if let Some(window) = &params.window {
+            if !schemab.has_window(window) {
+                return unknown_window(window);
+            }
+            payload.select_window(window);
+        }
+        return HttpResponse::Ok()
+            .content_type(prometheus::CONTENT_TYPE)
+            .body(prometheus::render(&payload));
     }
     HttpResponse::ServiceUnavailable().body("Internal Error".to_string())
 }
 
-async fn route_disks(schemab: web::Data<Arc<DefaultSchemaBuilder>>) -> HttpResponse {
-    if let Some(payload) = schemab.get_disks_payload() {
-        return HttpResponse::Ok().json(payload);
+async fn route_history(systats: web::Data<Arc<RwLock<SystatsData>>>,
+                       tsdb: web::Data<Option<Arc<Tsdb>>>,
+                       metric: web::Path<String>,
+                       params: web::Query<HistoryQuery>) -> HttpResponse {
+    if let (Some(window), Ok(stats)) = (&params.window, systats.read()) {
+        if !stats.spec.windows.iter().any(|w| &*w.name == window) {
+            return unknown_window(window);
+        }
+    }
+    if let Some(tsdb) = tsdb.as_ref() {
+        match history::query_store(&systats, tsdb, &metric, &params) {
+            Ok(Some(payload)) => return HttpResponse::Ok().json(payload),
+            Ok(None) => (),
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Failed to read the store: {}", err));
+            },
+        }
+    }
+    if let Ok(stats) = systats.read() {
+        if let Some(payload) = history::query(&stats, &metric, &params) {
+            return HttpResponse::Ok().json(payload);
+        }
+        return HttpResponse::NotFound().body(format!("Unknown metric: {}", metric));
     }
     HttpResponse::ServiceUnavailable().body("Internal Error".to_string())
 }
 
-async fn route_networks(schemab: web::Data<Arc<DefaultSchemaBuilder>>) -> HttpResponse {
-    if let Some(payload) = schemab.get_networks_payload() {
-        return HttpResponse::Ok().json(payload);
+async fn route_stream(schemab: web::Data<Arc<DefaultSchemaBuilder>>,
+                      params: web::Query<StreamQuery>) -> HttpResponse {
+    let subsystems = match sse::parse_subsystems(&params) {
+        Ok(subsystems) => subsystems,
+        Err(name) => {
+            return HttpResponse::BadRequest()
+                .body(format!("Unknown subsystem: {}", name));
+        }
+    };
+    let window = params.into_inner().window;
+    if let Some(window) = &window {
+        if !schemab.has_window(window) {
+            return unknown_window(window);
+        }
     }
-    HttpResponse::ServiceUnavailable().body("Internal Error".to_string())
+    // Start with the current schema, then follow every new build
+    let current = schemab.get_full_payload().map(Arc::new);
+    let events = stream::iter(current)
+        .chain(schemab.subscribe())
+        .map(move |schema| {
+            let event = match &window {
+                Some(window) => {
+                    let mut schema = (*schema).clone();
+                    schema.select_window(window);
+                    sse::encode_event(&schema, &subsystems)
+                },
+                None => sse::encode_event(&schema, &subsystems),
+            };
+            Ok::<_, actix_web::Error>(web::Bytes::from(event))
+        });
+    HttpResponse::Ok()
+        .content_type(sse::CONTENT_TYPE)
+        .streaming(events)
 }
 
-fn run_app(tx: Sender<Server>, schemab: Arc<DefaultSchemaBuilder>)
-    -> std::io::Result<()> {
-    let mut sys = rt::System::new("test");
-    let srv = HttpServer::new(move || {
+fn bind_error(addr: &str, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("failed to bind {}: {}", addr, err))
+}
+
+/// Removes the socket left behind by an instance that exited, but not that
+/// of an instance still listening
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "socket in use by another process")),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn bind_app(config: &ServerConfig, schemab: Arc<DefaultSchemaBuilder>,
+            systats: Arc<RwLock<SystatsData>>, tsdb: Option<Arc<Tsdb>>)
+            -> io::Result<Server> {
+    let mut srv = HttpServer::new(move || {
         App::new()
             // enable logger
             .wrap(middleware::Logger::default())
@@ -53,37 +243,111 @@ fn run_app(tx: Sender<Server>, schemab: Arc<DefaultSchemaBuilder>)
             .data(web::JsonConfig::default().limit(4096))
             // set application data
             .data(schemab.clone())
-            .service(web::resource("/").to(|| async { "Hello world!" }))
+            .data(systats.clone())
+            .data(tsdb.clone())
+            .service(web::resource("/").route(web::get().to(route_dashboard)))
+            .service(web::resource("/dashboard.js").route(web::get().to(route_dashboard_js)))
             .service(web::resource("/full_info").route(web::get().to(route_full_info)))
             .service(web::resource("/cpu").route(web::get().to(route_cpu)))
+            .service(web::resource("/cpu/{id}").route(web::get().to(route_cpu_core)))
             .service(web::resource("/mem").route(web::get().to(route_mem)))
+            .service(web::resource("/load").route(web::get().to(route_load)))
+            .service(web::resource("/pressure").route(web::get().to(route_pressure)))
             .service(web::resource("/disks").route(web::get().to(route_disks)))
             .service(web::resource("/networks").route(web::get().to(route_networks)))
+            .service(web::resource("/diskio").route(web::get().to(route_diskio)))
+            .service(web::resource("/status").route(web::get().to(route_status)))
+            .service(web::resource("/metrics").route(web::get().to(route_metrics)))
+            .service(web::resource("/history/{metric}").route(web::get().to(route_history)))
+            .service(web::resource("/stream").route(web::get().to(route_stream)))
     })
     // Set the number of threads for the server (default is nrcpu)
-    .workers(1)
-    .bind("127.0.0.1:8080")?
-    .run();
+    .workers(config.workers);
+    for addr in config.listen_addrs.iter() {
+        srv = srv.bind(addr)
+            .map_err(|e| bind_error(&addr.to_string(), e))?;
+    }
+    if let Some(path) = &config.unix_socket {
+        #[cfg(unix)]
+        {
+            let display = path.display().to_string();
+            remove_stale_socket(path).map_err(|e| bind_error(&display, e))?;
+            use std::os::unix::fs::PermissionsExt;
+            srv = srv.bind_uds(path).map_err(|e| bind_error(&display, e))?;
+            // Set once bound, the umask being shared by the other threads
+            let mode = std::fs::Permissions::from_mode(config.unix_socket_mode);
+            std::fs::set_permissions(path, mode)
+                .map_err(|e| bind_error(&display, e))?;
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unix sockets are not supported: {}", path.display())));
+    }
+    Ok(srv.run())
+}
+
+fn run_app(tx: Sender<io::Result<Server>>, config: ServerConfig,
+           schemab: Arc<DefaultSchemaBuilder>, systats: Arc<RwLock<SystatsData>>,
+           tsdb: Option<Arc<Tsdb>>) {
+    let mut sys = rt::System::new("sysinfo-reader");
+    let srv = match bind_app(&config, schemab, systats, tsdb) {
+        Ok(srv) => srv,
+        Err(e) => {
+            let _ = tx.send(Err(e));
+            return;
+        }
+    };
     // send server controller to main thread
-    let _ = tx.send(srv.clone());
+    let _ = tx.send(Ok(srv.clone()));
     // run future
-    sys.block_on(srv)
+    let _ = sys.block_on(srv);
+    if let Some(path) = &config.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
 }
 
-pub fn start_server(schemab: Arc<DefaultSchemaBuilder>) -> Receiver<Server> {
+/// Binds every configured address and starts serving on a dedicated
+/// thread. Fails if any of the addresses could not be bound. The history
+/// is also queried from `tsdb` if any
+pub fn start_server(opts: &SysinfoOpts, schemab: Arc<DefaultSchemaBuilder>,
+                    systats: Arc<RwLock<SystatsData>>,
+                    tsdb: Option<Arc<Tsdb>>) -> io::Result<Server> {
     std::env::set_var("RUST_LOG", "actix_web=info,actix_server=trace");
     env_logger::init();
 
     let (tx, rx) = channel();
+    let config = ServerConfig {
+        listen_addrs: opts.listen_addrs.clone(),
+        unix_socket: opts.unix_socket.clone(),
+        unix_socket_mode: opts.unix_socket_mode,
+        workers: opts.workers,
+    };
 
     thread::spawn(move || {
-        let _ = run_app(tx, schemab);
+        run_app(tx, config, schemab, systats, tsdb);
     });
 
-    rx
+    rx.recv().unwrap_or_else(|_| Err(io::Error::other(
+        "HTTP server thread exited unexpectedly")))
 }
 
-pub fn stop_server(server_handler: &Receiver<Server>) {
-    let srv = server_handler.recv().unwrap();
+pub fn stop_server(srv: &Server) {
     rt::System::new("").block_on(srv.stop(true));
 }
+
+#[cfg(unix)]
+#[test]
+fn test_remove_stale_socket() {
+    use std::os::unix::net::UnixListener;
+    let path = std::env::temp_dir()
+        .join(format!("sysinfo-{}.sock", std::process::id()));
+    let listener = UnixListener::bind(&path).unwrap();
+    let err = remove_stale_socket(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    assert!(path.exists());
+    // Left behind once closed
+    drop(listener);
+    remove_stale_socket(&path).unwrap();
+    assert!(!path.exists());
+}
@@ -0,0 +1,273 @@
+// Dashboard of sysinfo-reader: loads the collected history of every
+// metric and then follows the live stream of samples
+
+"use strict";
+
+const MAX_POINTS = 3600;
+const COLORS = ["#1e88e5", "#e53935", "#43a047", "#fb8c00", "#8e24aa", "#00897b"];
+
+const charts = {};
+
+function formatBytes(value) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (Math.abs(value) >= 1024 && i < units.length - 1) {
+    value /= 1024;
+    i++;
+  }
+  return value.toFixed(1) + " " + units[i];
+}
+
+function formatUptime(seconds) {
+  const d = Math.floor(seconds / 86400);
+  const h = Math.floor((seconds % 86400) / 3600);
+  const m = Math.floor((seconds % 3600) / 60);
+  return "up " + d + "d " + h + "h " + m + "m";
+}
+
+class Chart {
+  constructor(id, title, format) {
+    this.format = format;
+    this.series = new Map();
+    const panel = document.createElement("div");
+    panel.className = "panel";
+    const header = document.createElement("h2");
+    header.textContent = title;
+    this.canvas = document.createElement("canvas");
+    panel.appendChild(header);
+    panel.appendChild(this.canvas);
+    document.getElementById("panels").appendChild(panel);
+    charts[id] = this;
+  }
+
+  push(label, ts, value) {
+    // Unavailable metrics are serialized without their last value
+    if (!Number.isFinite(value)) {
+      return;
+    }
+    if (!this.series.has(label)) {
+      this.series.set(label, []);
+    }
+    const points = this.series.get(label);
+    if (points.length > 0 && points[points.length - 1][0] >= ts) {
+      return;
+    }
+    points.push([ts, value]);
+    if (points.length > MAX_POINTS) {
+      points.shift();
+    }
+  }
+
+  draw() {
+    const canvas = this.canvas;
+    const ratio = window.devicePixelRatio || 1;
+    canvas.width = canvas.clientWidth * ratio;
+    canvas.height = canvas.clientHeight * ratio;
+    const ctx = canvas.getContext("2d");
+    ctx.scale(ratio, ratio);
+    const width = canvas.clientWidth;
+    const height = canvas.clientHeight;
+    const left = 70;
+    const bottom = 16;
+    let tMin = Infinity, tMax = -Infinity, vMin = 0, vMax = -Infinity;
+    for (const points of this.series.values()) {
+      for (const [ts, value] of points) {
+        tMin = Math.min(tMin, ts);
+        tMax = Math.max(tMax, ts);
+        vMin = Math.min(vMin, value);
+        vMax = Math.max(vMax, value);
+      }
+    }
+    ctx.clearRect(0, 0, width, height);
+    ctx.font = "11px sans-serif";
+    ctx.fillStyle = "#78909c";
+    if (!isFinite(tMin)) {
+      ctx.fillText("no data", left, height / 2);
+      return;
+    }
+    if (vMax <= vMin) {
+      vMax = vMin + 1;
+    }
+    if (tMax <= tMin) {
+      tMax = tMin + 1;
+    }
+    const x = (ts) => left + (ts - tMin) / (tMax - tMin) * (width - left - 4);
+    const y = (value) => (height - bottom) - (value - vMin) / (vMax - vMin) * (height - bottom - 4);
+    ctx.fillText(this.format(vMax), 2, 12);
+    ctx.fillText(this.format(vMin), 2, height - bottom);
+    ctx.fillText(new Date(tMin).toLocaleTimeString(), left, height - 2);
+    const end = new Date(tMax).toLocaleTimeString();
+    ctx.fillText(end, width - ctx.measureText(end).width - 4, height - 2);
+    ctx.strokeStyle = "#cfd8dc";
+    ctx.beginPath();
+    ctx.moveTo(left, 4);
+    ctx.lineTo(left, height - bottom);
+    ctx.lineTo(width - 4, height - bottom);
+    ctx.stroke();
+    let i = 0;
+    const legend = [];
+    for (const [label, points] of this.series) {
+      const color = COLORS[i++ % COLORS.length];
+      ctx.strokeStyle = color;
+      ctx.beginPath();
+      points.forEach(([ts, value], j) => {
+        if (j === 0) {
+          ctx.moveTo(x(ts), y(value));
+        } else {
+          ctx.lineTo(x(ts), y(value));
+        }
+      });
+      ctx.stroke();
+      if (points.length > 0) {
+        legend.push([label, color, points[points.length - 1][1]]);
+      }
+    }
+    let offset = left + 8;
+    for (const [label, color, value] of legend) {
+      const text = label + ": " + this.format(value);
+      ctx.fillStyle = color;
+      ctx.fillText(text, offset, 14);
+      offset += ctx.measureText(text).width + 12;
+    }
+  }
+}
+
+function diskioChart(name) {
+  const id = "diskio:" + name;
+  return charts[id] || new Chart(id, "Disk I/O " + name, (v) => formatBytes(v) + "/s");
+}
+
+function netChart(name) {
+  const id = "net:" + name;
+  return charts[id] || new Chart(id, "Network " + name, (v) => formatBytes(v) + "/s");
+}
+
+async function fetchJson(url) {
+  const response = await fetch(url);
+  if (!response.ok) {
+    throw new Error(url + ": " + response.status);
+  }
+  return response.json();
+}
+
+async function loadHistory(metric, name) {
+  let url = "/history/" + metric;
+  if (name !== undefined) {
+    url += "?name=" + encodeURIComponent(name);
+  }
+  try {
+    return (await fetchJson(url)).samples;
+  } catch (e) {
+    return [];
+  }
+}
+
+function pushSample(sample, ts) {
+  const cpu = sample.cpu;
+  charts.cpu_usage.push("usage", ts, cpu.cpu_usage.last);
+  charts.cpu_freq.push("freq", ts, cpu.cpu_freq.last);
+  for (const [id, core] of Object.entries(cpu.cores)) {
+    charts.cpu_cores.push("cpu" + id, ts, core.cpu_usage.last);
+  }
+  charts.load.push("1m", ts, sample.load.load1.last);
+  charts.load.push("5m", ts, sample.load.load5.last);
+  charts.load.push("15m", ts, sample.load.load15.last);
+  const mem = sample.mem;
+  charts.mem.push("used", ts, mem.mem_used.last * 1024);
+  charts.mem.push("available", ts, mem.mem_available.last * 1024);
+  charts.mem.push("free", ts, mem.mem_free.last * 1024);
+  charts.mem.push("buff/cache", ts, mem.mem_buffer.last * 1024);
+  charts.mem.push("swap", ts, mem.swap_used.last * 1024);
+  for (const [name, disk] of Object.entries(sample.disks)) {
+    charts.disks.push(name, ts, disk.last);
+  }
+  for (const [name, io] of Object.entries(sample.diskio)) {
+    diskioChart(name).push("read", ts, io.read_bytes.last);
+    diskioChart(name).push("write", ts, io.write_bytes.last);
+  }
+  for (const [name, net] of Object.entries(sample.networks)) {
+    netChart(name).push("rx", ts, net.rx_bytes.last);
+    netChart(name).push("tx", ts, net.tx_bytes.last);
+  }
+  document.getElementById("host").textContent = sample.system.hostname;
+  document.getElementById("uptime").textContent = formatUptime(sample.system.uptime);
+}
+
+function drawAll() {
+  for (const chart of Object.values(charts)) {
+    chart.draw();
+  }
+}
+
+async function init() {
+  new Chart("cpu_usage", "CPU usage", (v) => v.toFixed(1) + " %");
+  new Chart("cpu_cores", "CPU usage per core", (v) => v.toFixed(1) + " %");
+  new Chart("cpu_freq", "CPU frequency", (v) => v.toFixed(0) + " MHz");
+  new Chart("load", "Load average", (v) => v.toFixed(2));
+  new Chart("mem", "Memory", formatBytes);
+  new Chart("disks", "Disk usage", formatBytes);
+
+  const info = await fetchJson("/full_info");
+  // Requested at once, then pushed in order so that the charts created on
+  // the fly keep the order of the disks and interfaces
+  const loads = [];
+  const load = (metric, name, push) => {
+    loads.push(loadHistory(metric, name).then((samples) => () => {
+      for (const [ts, value] of samples) {
+        push(ts, value);
+      }
+    }));
+  };
+  load("cpu_usage", undefined, (ts, value) => charts.cpu_usage.push("usage", ts, value));
+  for (const id of Object.keys(info.cpu.cores).sort((a, b) => a - b)) {
+    load("core_usage", id, (ts, value) => charts.cpu_cores.push("cpu" + id, ts, value));
+  }
+  load("cpu_freq", undefined, (ts, value) => charts.cpu_freq.push("freq", ts, value));
+  for (const [metric, label] of [["load1", "1m"], ["load5", "5m"], ["load15", "15m"]]) {
+    load(metric, undefined, (ts, value) => charts.load.push(label, ts, value));
+  }
+  for (const [metric, label] of [["mem_used", "used"], ["mem_available", "available"],
+                                 ["mem_free", "free"], ["mem_buffer", "buff/cache"],
+                                 ["swap_used", "swap"]]) {
+    load(metric, undefined, (ts, value) => charts.mem.push(label, ts, value * 1024));
+  }
+  for (const name of Object.keys(info.disks)) {
+    load("disk_usage", name, (ts, value) => charts.disks.push(name, ts, value));
+  }
+  for (const name of Object.keys(info.diskio)) {
+    load("read_bytes", name, (ts, value) => diskioChart(name).push("read", ts, value));
+    load("write_bytes", name, (ts, value) => diskioChart(name).push("write", ts, value));
+  }
+  for (const name of Object.keys(info.networks)) {
+    load("rx_bytes", name, (ts, value) => netChart(name).push("rx", ts, value));
+    load("tx_bytes", name, (ts, value) => netChart(name).push("tx", ts, value));
+  }
+  for (const push of await Promise.all(loads)) {
+    push();
+  }
+  drawAll();
+
+  const status = document.getElementById("status");
+  const source = new EventSource("/stream");
+  // The stream starts with the current sample, already part of the history
+  let skip = true;
+  source.onopen = () => {
+    status.textContent = "live";
+    skip = true;
+  };
+  source.onerror = () => { status.textContent = "disconnected, retrying..."; };
+  source.addEventListener("sample", (event) => {
+    if (skip) {
+      skip = false;
+      return;
+    }
+    const sample = JSON.parse(event.data);
+    pushSample(sample, sample.timestamp);
+    drawAll();
+  });
+  window.addEventListener("resize", drawAll);
+}
+
+init().catch((e) => {
+  document.getElementById("status").textContent = "error: " + e.message;
+});
@@ -0,0 +1,81 @@
+//! Encoding of the SysinfoSchema as Server-Sent Events, optionally
+//! restricted to a subset of its subsystems
+
+use serde::Deserialize;
+use crate::schema::SysinfoSchema;
+
+
+pub const CONTENT_TYPE: &str = "text/event-stream";
+
+const SUBSYSTEMS: [&str; 9] = ["cpu", "mem", "system", "load", "pressure",
+                               "disks", "networks", "diskio", "status"];
+
+#[derive(Deserialize, Default, Debug)]
+pub struct StreamQuery {
+    /// Comma separated list of subsystems, all of them if not present
+    pub subsystems: Option<String>,
+    /// Aggregation window the statistics are taken from
+    pub window: Option<String>,
+}
+
+/// Parses the requested subsystems, returning the first unknown one as
+/// error
+pub fn parse_subsystems(query: &StreamQuery) -> Result<Vec<String>, String> {
+    let list = match &query.subsystems {
+        Some(list) => list,
+        None => return Ok(SUBSYSTEMS.iter().map(|s| s.to_string()).collect()),
+    };
+    let mut subsystems: Vec<String> = Vec::new();
+    for name in list.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        if !SUBSYSTEMS.contains(&name) {
+            return Err(name.to_string());
+        }
+        subsystems.push(name.to_string());
+    }
+    Ok(subsystems)
+}
+
+/// Encodes the selected subsystems of the schema as one `sample` event,
+/// along with the Unix time of the sample in milliseconds
+pub fn encode_event(schema: &SysinfoSchema, subsystems: &[String]) -> String {
+    let mut fields = vec![format!("\"timestamp\":{}", schema.timestamp)];
+    fields.extend(subsystems.iter()
+        .filter_map(|name| {
+            let json = match name.as_str() {
+                "cpu" => serde_json::to_string(&schema.cpu),
+                "mem" => serde_json::to_string(&schema.mem),
+                "system" => serde_json::to_string(&schema.system),
+                "load" => serde_json::to_string(&schema.load),
+                "pressure" => serde_json::to_string(&schema.pressure),
+                "disks" => serde_json::to_string(&schema.disks),
+                "networks" => serde_json::to_string(&schema.networks),
+                "diskio" => serde_json::to_string(&schema.diskio),
+                "status" => serde_json::to_string(&schema.status),
+                _ => return None,
+            };
+            json.ok().map(|json| format!("\"{}\":{}", name, json))
+        }));
+    format!("event: sample\ndata: {{{}}}\n\n", fields.join(","))
+}
+
+#[test]
+fn test_encode_event() {
+    let query = StreamQuery { subsystems: Some("cpu, system".to_string()),
+                                ..Default::default() };
+    let subsystems = parse_subsystems(&query).unwrap();
+    assert_eq!(subsystems, vec!["cpu", "system"]);
+    let query = StreamQuery { subsystems: Some("cpu,gpu".to_string()),
+                                ..Default::default() };
+    assert_eq!(parse_subsystems(&query), Err("gpu".to_string()));
+    let mut schema = SysinfoSchema::new();
+    schema.system.name = "Linux".to_string();
+    schema.system.hostname = "host".to_string();
+    schema.timestamp = 1500;
+    let event = encode_event(&schema, &["system".to_string()]);
+    assert_eq!(event, "event: sample\ndata: {\"timestamp\":1500,\
+                       \"system\":{\"uptime\":0,\"name\":\"Linux\",\
+                       \"hostname\":\"host\"}}\n\n");
+    // Metrics without samples keep their type, without statistics
+    let event = encode_event(&schema, &["cpu".to_string()]);
+    assert!(event.contains("\"cpu_usage\":{\"available\":false}"));
+}
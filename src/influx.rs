@@ -0,0 +1,355 @@
+//! This module implements a sink serializing the samples to the InfluxDB
+//! line protocol, written to a file, sent over UDP or posted to the
+//! `/write` endpoint of an InfluxDB server. Lines are sent in batches, kept
+//! and retried with an increasing delay when the target fails
+
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::sink::{self, Batch, Sink};
+
+
+/// Milliseconds of lines kept in memory before being sent
+pub const BATCH_SPAN: u64 = 10*1000;
+/// Lines kept in memory before being sent, whatever their span
+pub const BATCH_LINES: usize = 5000;
+/// Lines kept while the target fails, the oldest being dropped first
+pub const MAX_PENDING: usize = 100_000;
+/// Size of the UDP datagrams, below the usual MTU
+pub const MAX_DATAGRAM: usize = 1400;
+const MIN_BACKOFF: u64 = 1000;
+const MAX_BACKOFF: u64 = 60*1000;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InfluxTarget {
+    /// File the lines are appended to
+    File(PathBuf),
+    /// Address of a UDP listener, as HOST:PORT
+    Udp(String),
+    /// Address of an HTTP server, as HOST:PORT, and the path of the write
+    /// endpoint along with its query
+    Http { addr: String, path: String },
+}
+
+impl FromStr for InfluxTarget {
+    type Err = String;
+
+    /// Parses `udp://HOST:PORT`, `http://HOST:PORT/write?db=NAME` or the
+    /// path of a file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("udp://") {
+            if addr.is_empty() || addr.contains('/') {
+                return Err(format!("invalid UDP address: {}", s));
+            }
+            return Ok(InfluxTarget::Udp(addr.to_string()));
+        }
+        if let Some(rest) = s.strip_prefix("http://") {
+            let (addr, path) = match rest.find('/') {
+                Some(index) => rest.split_at(index),
+                None => (rest, "/write"),
+            };
+            if addr.is_empty() {
+                return Err(format!("invalid HTTP address: {}", s));
+            }
+            return Ok(InfluxTarget::Http { addr: addr.to_string(),
+                                           path: path.to_string() });
+        }
+        if s.contains("://") {
+            return Err(format!("unsupported target: {}", s));
+        }
+        if s.is_empty() {
+            return Err("empty target".to_string());
+        }
+        Ok(InfluxTarget::File(PathBuf::from(s)))
+    }
+}
+
+/// Escapes the characters in `special` with a backslash
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Serializes the samples of the batch, one line each, tagged by host and
+/// by disk or interface. Timestamps are in nanoseconds
+pub fn encode(batch: &Batch) -> Vec<String> {
+    let mut lines = Vec::with_capacity(batch.samples.len());
+    for sample in batch.samples.iter() {
+        let mut tags = Vec::new();
+        if !batch.host.is_empty() {
+            tags.push(("host", batch.host.as_str()));
+        }
+        if let Some(label) = sample.label.as_deref().filter(|l| !l.is_empty()) {
+            tags.push((sample.label_name, label));
+        }
+        // Sorted as InfluxDB expects them
+        tags.sort();
+        let mut line = escape(sample.metric, &[',', ' ']);
+        for (key, value) in tags {
+            line.push(',');
+            line.push_str(&escape(key, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&escape(value, &[',', '=', ' ']));
+        }
+        line.push_str(&format!(" value={} {}", sample.value,
+                               batch.ts*1_000_000));
+        lines.push(line);
+    }
+    lines
+}
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound,
+                       format!("no address for {}", addr))
+    })
+}
+
+pub struct InfluxSink {
+    target: InfluxTarget,
+    pending: Vec<String>,
+    /// Timestamp of the oldest pending line
+    since: Option<u64>,
+    /// Delay before the next attempt after a failure, and its timestamp
+    backoff: u64,
+    retry_at: Option<u64>,
+}
+
+impl InfluxSink {
+    pub fn new(target: InfluxTarget) -> Self {
+        InfluxSink {
+            target,
+            pending: Vec::new(),
+            since: None,
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    fn write_file(path: &Path, lines: &[String]) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut content = lines.join("\n");
+        content.push('\n');
+        file.write_all(content.as_bytes())
+    }
+
+    fn send_udp(addr: &str, lines: &[String]) -> io::Result<()> {
+        let addr = resolve(addr)?;
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        for payload in sink::pack(lines, MAX_DATAGRAM) {
+            socket.send_to(payload.as_bytes(), addr)?;
+        }
+        Ok(())
+    }
+
+    /// Posts the lines, a client error dropping them as they would be
+    /// rejected again
+    fn post_http(addr: &str, path: &str, lines: &[String]) -> io::Result<bool> {
+        let mut body = lines.join("\n");
+        body.push('\n');
+        let mut stream = TcpStream::connect_timeout(&resolve(addr)?,
+                                                    HTTP_TIMEOUT)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\n\
+                        Content-Type: text/plain; charset=utf-8\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n",
+               path, addr, body.len())?;
+        stream.write_all(body.as_bytes())?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        let code = status.split_whitespace().nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                          "invalid HTTP response"))?;
+        match code {
+            200..=299 => Ok(true),
+            400..=499 => Ok(false),
+            _ => Err(io::Error::other(format!("HTTP status {}", code))),
+        }
+    }
+
+    /// Sends the pending lines, kept if the target failed
+    fn send(&mut self, ts: u64) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let result = match &self.target {
+            InfluxTarget::File(path) => Self::write_file(path, &self.pending),
+            InfluxTarget::Udp(addr) => Self::send_udp(addr, &self.pending),
+            InfluxTarget::Http { addr, path } => {
+                match Self::post_http(addr, path, &self.pending) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => {
+                        let dropped = self.pending.len();
+                        self.pending.clear();
+                        self.since = None;
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} lines rejected", dropped)));
+                    },
+                    Err(err) => Err(err),
+                }
+            },
+        };
+        match result {
+            Ok(()) => {
+                self.pending.clear();
+                self.since = None;
+                self.backoff = MIN_BACKOFF;
+                self.retry_at = None;
+                Ok(())
+            },
+            Err(err) => {
+                self.retry_at = Some(ts + self.backoff);
+                self.backoff = (self.backoff*2).min(MAX_BACKOFF);
+                Err(err)
+            },
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    fn push(&mut self, batch: &Batch) -> io::Result<()> {
+        if batch.samples.is_empty() {
+            return Ok(());
+        }
+        self.pending.extend(encode(batch));
+        if self.pending.len() > MAX_PENDING {
+            let excess = self.pending.len() - MAX_PENDING;
+            self.pending.drain(..excess);
+        }
+        let since = *self.since.get_or_insert(batch.ts);
+        if let Some(retry_at) = self.retry_at {
+            if batch.ts < retry_at {
+                // Still failing as far as the executor is concerned
+                return Err(io::Error::other(format!(
+                    "{} lines pending, retrying in {} ms",
+                    self.pending.len(), retry_at - batch.ts)));
+            }
+        } else if batch.ts.saturating_sub(since) < BATCH_SPAN &&
+            self.pending.len() < BATCH_LINES {
+            return Ok(());
+        }
+        self.send(batch.ts)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let ts = self.since.unwrap_or(0);
+        self.send(ts)
+    }
+}
+
+#[test]
+fn test_influx_sink() {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use crate::sink::Sample;
+    let batch = |ts, samples| Batch { ts, host: "my host".to_string(),
+                                      samples };
+    let cpu = |value| Sample { metric: "cpu_usage", label: None,
+                               label_name: "", value };
+    let disk = Sample { metric: "disk_usage", label: Some("/mnt/my disk,1".to_string()),
+                        label_name: "disk", value: 12.5 };
+    assert_eq!(encode(&batch(1000, vec![cpu(20.), disk.clone()])), vec![
+        "cpu_usage,host=my\\ host value=20 1000000000".to_string(),
+        "disk_usage,disk=/mnt/my\\ disk\\,1,host=my\\ host value=12.5 1000000000"
+            .to_string(),
+    ]);
+    assert_eq!("udp://localhost:8089".parse(),
+               Ok(InfluxTarget::Udp("localhost:8089".to_string())));
+    assert_eq!("http://localhost:8086".parse(),
+               Ok(InfluxTarget::Http { addr: "localhost:8086".to_string(),
+                                       path: "/write".to_string() }));
+    assert!("tcp://localhost:8086".parse::<InfluxTarget>().is_err());
+
+    // File, written by batches spanning 10 seconds
+    let path = std::env::temp_dir()
+        .join(format!("sysinfo-{}.influx", std::process::id()));
+    let mut sink = InfluxSink::new(InfluxTarget::File(path.clone()));
+    for i in 0..12 {
+        sink.push(&batch(i*1000, vec![cpu(i as f64)])).unwrap();
+    }
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().count(), 11);
+    sink.flush().unwrap();
+    let content = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(content.lines().last(),
+               Some("cpu_usage,host=my\\ host value=11 11000000000"));
+
+    // UDP, split in datagrams at line boundaries
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut sink = InfluxSink::new(InfluxTarget::Udp(addr));
+    let samples: Vec<Sample> = (0..50).map(|i| cpu(i as f64)).collect();
+    let lines = encode(&batch(0, samples.clone()));
+    sink.push(&batch(0, samples)).unwrap();
+    sink.flush().unwrap();
+    let mut received = String::new();
+    let mut buf = [0u8; 2048];
+    while received.lines().count() < lines.len() {
+        let len = listener.recv(&mut buf).unwrap();
+        assert!(len <= MAX_DATAGRAM);
+        assert!(buf[..len].ends_with(b"\n"));
+        received.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+    }
+    assert_eq!(received.lines().collect::<Vec<_>>(), lines);
+
+    // HTTP, retried after the server was down
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let mut sink = InfluxSink::new(
+        InfluxTarget::Http { addr: addr.clone(), path: "/write?db=test".into() });
+    sink.push(&batch(0, vec![cpu(1.)])).unwrap();
+    assert!(sink.push(&batch(10000, vec![cpu(2.)])).is_err());
+    // Waiting 1 second before the next attempt
+    assert!(sink.push(&batch(10500, vec![cpu(3.)])).is_err());
+    let listener = TcpListener::bind(&addr).unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        // Reads up to the end of the body, as announced
+        loop {
+            let len = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..len]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length: usize = text.lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap().parse().unwrap();
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        String::from_utf8(request).unwrap()
+    });
+    sink.push(&batch(11000, vec![cpu(4.)])).unwrap();
+    let request = server.join().unwrap();
+    assert!(request.starts_with("POST /write?db=test HTTP/1.1\r\n"));
+    let body = request.split("\r\n\r\n").nth(1).unwrap();
+    assert_eq!(body.lines().map(|line| line.rsplit(' ').nth(1).unwrap())
+               .collect::<Vec<_>>(),
+               vec!["value=1", "value=2", "value=3", "value=4"]);
+    assert!(sink.pending.is_empty());
+}
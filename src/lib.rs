@@ -3,47 +3,121 @@
 //! This package was built for the purpose of displaying system's information
 //! in a very simple way but with relevant and real-time data
 
+pub mod error;
 pub mod utils;
 pub mod tasks;
 pub mod ringbuf;
+pub mod counter;
 pub mod http;
 pub mod schema;
 pub mod systats;
+pub mod collector;
+pub mod cpu;
+pub mod disk;
+pub mod net;
+pub mod history;
+pub mod rollup;
+pub mod diskio;
+pub mod mem;
+pub mod load;
+pub mod psi;
+pub mod state;
+pub mod gorilla;
+pub mod tsdb;
+pub mod sink;
+pub mod influx;
+pub mod statsd;
+pub mod graphite;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 extern crate sysinfo;
 extern crate num_traits;
 extern crate getopts;
 
 use std::io::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use signal_hook::consts::signal::*;
 use signal_hook::iterator::Signals;
 use getopts::{Matches, Options};
-use sysinfo::{System, SystemExt};
-use crate::systats::SystatsExecutor;
+use crate::systats::{SystatsData, SystatsExecutor};
+use crate::collector::{Backend, FsRoots};
+use crate::ringbuf::{BufferSpec, Window};
 use crate::schema::DefaultSchemaBuilder;
 use crate::http::server;
+use crate::tsdb::Tsdb;
+use crate::influx::{InfluxSink, InfluxTarget};
+use crate::statsd::{StatsdConfig, StatsdSink};
+use crate::graphite::{GraphiteConfig, GraphiteSink};
 
 
-// const CAPACITY: usize = 120;
-const CAPACITY: usize = 4;
 const DEFAULT_WINDOW: u32 = 60*60; // 1 hour in seconds
 const MIN_WINDOW: u32 = 8;
 // const MIN_WINDOW: u32 = 10*60; // 10 minutes
 const MAX_WINDOW: u32 = 24*60*60; // 24 hours
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+// Shortest interval the CPU usage is refreshed at by sysinfo
+const MIN_INTERVAL: Duration = Duration::from_millis(200);
+// Samples kept by each buffer, with the times they were taken at, bounds
+// the memory used to about 90 KB per metric
+const MIN_CAPACITY: usize = 2;
+const MAX_CAPACITY: usize = 3600;
+const DEFAULT_WINDOWS: &str = "1m,5m,1h,24h";
+const DEFAULT_EWMA: &str = "1m,5m,15m";
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+const DEFAULT_WORKERS: usize = 1;
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+const DEFAULT_STATE_INTERVAL: u64 = 60;
+const DEFAULT_TSDB_RETENTION: &str = "7d";
+#[cfg(feature = "sqlite")]
+const DEFAULT_SQLITE_RETENTION: &str = "30d";
 
 
 #[derive(Default, Debug)]
 pub struct SysinfoOpts {
-    pub sampling_freq: u32,
+    pub sampling_interval: Duration,
     pub time_window: u32,
+    /// Number of samples kept by the buffers, time_window/sampling_interval
+    pub capacity: usize,
+    /// Aggregation windows besides the time window
+    pub windows: Vec<Window>,
+    /// Time constants of the moving averages
+    pub ewma: Vec<(Arc<str>, Duration)>,
     pub reset_flag: bool,
+    pub listen_addrs: Vec<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_mode: u32,
+    pub workers: usize,
+    pub backend: Backend,
+    pub roots: FsRoots,
+    /// File the buffers are saved to every state_interval and on exit
+    pub state_file: Option<PathBuf>,
+    pub state_interval: Duration,
+    /// Directory of the on-disk store, its retention and size limit in bytes
+    pub tsdb_dir: Option<PathBuf>,
+    pub tsdb_retention: Duration,
+    pub tsdb_max_size: Option<u64>,
+    /// SQLite database every sample is written to, and its retention
+    #[cfg(feature = "sqlite")]
+    pub sqlite: Option<PathBuf>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite_retention: Duration,
+    /// Where the samples are sent in the InfluxDB line protocol
+    pub influx: Option<InfluxTarget>,
+    /// StatsD agent the samples are pushed to as gauges
+    pub statsd: Option<StatsdConfig>,
+    /// Graphite carbon endpoint the schema is pushed to
+    pub graphite: Option<GraphiteConfig>,
 }
 
 impl PartialEq for SysinfoOpts {
     fn eq(&self, other: &Self) -> bool {
-        self.sampling_freq == other.sampling_freq &&
-            self.time_window == other.time_window
+        self.sampling_interval == other.sampling_interval &&
+            self.time_window == other.time_window &&
+            self.capacity == other.capacity
     }
 }
 
@@ -52,20 +126,23 @@ fn print_usage(program: &str, opts: &Options) {
     print!("{}", opts.usage(&brief));
 }
 
-fn handle_signals(run_flag: Arc<RwLock<bool>>) -> Result<(), Error> {
-    let mut signals = Signals::new(&[
+/// Stops the executor upon a signal, saving the buffers to the state file
+/// if any
+fn handle_signals(run_flag: Arc<RwLock<bool>>,
+                  systats: Arc<RwLock<SystatsData>>,
+                  state_file: Option<&Path>) -> Result<(), Error> {
+    let mut signals = Signals::new([
         SIGHUP,
         SIGTERM,
         SIGINT,
         SIGQUIT,
     ])?;
-    for signal in signals.forever() {
+    if let Some(signal) = signals.forever().next() {
         match signal as libc::c_int {
             SIGHUP | SIGTERM | SIGINT | SIGQUIT => {
-                {
-                    let mut flag = run_flag.write().unwrap();
-                    *flag = false;
-                    break;
+                *run_flag.write().unwrap() = false;
+                if let Some(path) = state_file {
+                    state::save_shared(&systats, path);
                 }
             },
             _ => unreachable!(),
@@ -74,20 +151,133 @@ fn handle_signals(run_flag: Arc<RwLock<bool>>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Parses a period such as 90s, 5m, 1h or 1d
+fn parse_span(value: &str) -> Option<Duration> {
+    let unit = match value.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60*60,
+        'd' => 24*60*60,
+        _ => return None,
+    };
+    let count = value[..value.len() - 1].parse::<u64>().ok()?;
+    if count == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(count*unit))
+}
+
+/// Parses a comma separated list of periods, named as given. `kind`
+/// describes them in the errors
+fn parse_spans(list: &str, kind: &str)
+    -> Result<Vec<(Arc<str>, Duration)>, String> {
+    let mut spans: Vec<(Arc<str>, Duration)> = Vec::new();
+    for name in list.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let span = parse_span(name)
+            .ok_or_else(|| format!("Invalid {}: {}", kind, name))?;
+        if spans.iter().any(|(other, _)| &**other == name) {
+            return Err(format!("Duplicated {}: {}", kind, name));
+        }
+        spans.push((name.into(), span));
+    }
+    Ok(spans)
+}
+
+/// Parses a comma separated list of aggregation windows, each of them
+/// spanning at least one sampling interval and at most MAX_WINDOW. Their
+/// samples are summed up in WINDOW_BUCKETS buckets, so their memory does
+/// not grow with their length
+fn parse_windows(list: &str, interval: Duration) -> Result<Vec<Window>, String> {
+    let mut windows: Vec<Window> = Vec::new();
+    for (name, span) in parse_spans(list, "window")? {
+        if span < interval {
+            return Err(format!("Window {} is shorter than the sampling \
+                               interval", name));
+        }
+        if span > Duration::from_secs(MAX_WINDOW.into()) {
+            return Err(format!("Window {} is longer than {:?}", name,
+                               Duration::from_secs(MAX_WINDOW.into())));
+        }
+        let len = (span.as_nanos()/interval.as_nanos()) as usize;
+        windows.push(Window { name, span, len });
+    }
+    Ok(windows)
+}
+
 pub fn init_opts(args: &[String]) -> Option<SysinfoOpts> {
-    if args.len() == 0 {
+    if args.is_empty() {
         return None;
     }
     let program = args[0].clone();
     let mut sysopts = SysinfoOpts::default();
     let mut opts = Options::new();
     opts.optopt("t", "time", "time window period", "MINUTES");
+    opts.optopt("i", "interval", "sampling interval, may be fractional \
+                (default 1, longer if the time window requires it)",
+                "SECONDS");
     opts.optflag("r", "reset", "reset max and min upon new time window");
+    opts.optopt("", "windows", "comma separated aggregation windows besides \
+                the time window (default 1m,5m,1h,24h)", "LIST");
+    opts.optopt("", "ewma", "comma separated time constants of the moving \
+                averages (default 1m,5m,15m)", "LIST");
+    opts.optmulti("l", "listen", "TCP address to listen on, may be repeated \
+                  (default 127.0.0.1:8080)", "ADDR:PORT");
+    opts.optopt("u", "unix-socket", "Unix domain socket to listen on", "PATH");
+    opts.optopt("", "socket-mode", "file permissions of the Unix socket \
+                (default 660)", "OCTAL");
+    opts.optopt("w", "workers", "number of HTTP worker threads (default 1)",
+                "N");
+    opts.optopt("b", "backend", "source of the CPU, memory, disks and \
+                networks statistics: sysinfo (default) or procfs", "NAME");
+    opts.optopt("", "proc-root", "mount point of procfs read by the native \
+                collectors (default /proc)", "PATH");
+    opts.optopt("", "sys-root", "mount point of sysfs read by the native \
+                collectors (default /sys)", "PATH");
+    opts.optopt("", "state-file", "file the samples are saved to, \
+                periodically and on exit, and restored from on start", "PATH");
+    opts.optopt("", "state-interval", "seconds between saves of the state \
+                file (default 60)", "SECONDS");
+    opts.optopt("", "tsdb-dir", "directory of the on-disk store the samples \
+                are appended to, for history beyond the time window", "PATH");
+    opts.optopt("", "tsdb-retention", "period the on-disk store keeps, such \
+                as 36h or 30d (default 7d)", "SPAN");
+    opts.optopt("", "tsdb-max-size", "size limit of the on-disk store, the \
+                oldest samples being deleted first", "MB");
+    #[cfg(feature = "sqlite")]
+    {
+        opts.optopt("", "sqlite", "SQLite database every sample is written \
+                    to", "PATH");
+        opts.optopt("", "sqlite-retention", "period the SQLite database \
+                    keeps (default 30d)", "SPAN");
+    }
+    opts.optopt("", "influx", "file, udp://HOST:PORT or \
+                http://HOST:PORT/write?db=NAME the samples are sent to in \
+                the InfluxDB line protocol", "TARGET");
+    opts.optopt("", "statsd", "StatsD agent the samples are pushed to as \
+                gauges on every tick", "HOST:PORT");
+    opts.optopt("", "statsd-format", "statsd (default) or dogstatsd, \
+                sending the host and labels as tags", "NAME");
+    opts.optopt("", "statsd-prefix", "prefix of the StatsD metric names",
+                "PREFIX");
+    opts.optopt("", "statsd-tags", "comma separated tags added to every \
+                gauge, with the dogstatsd format", "KEY:VALUE,...");
+    opts.optopt("", "statsd-mtu", "largest payload of the StatsD datagrams \
+                (default 1432)", "BYTES");
+    opts.optopt("", "graphite", "Graphite carbon endpoint the statistics \
+                are pushed to", "HOST:PORT");
+    opts.optopt("", "graphite-protocol", "plaintext (default) or pickle",
+                "NAME");
+    opts.optopt("", "graphite-template", "path of the Graphite metrics, \
+                {host} and {path} being replaced by the host name and the \
+                path in the schema (default sysinfo.{host}.{path})",
+                "TEMPLATE");
+    opts.optopt("", "graphite-interval", "seconds between pushes to \
+                Graphite (default 10)", "SECONDS");
     opts.optflag("h", "help", "print this help menu");
     let matches: Matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
-            println!("{}", f.to_string());
+            println!("{}", f);
             print_usage(&program, &opts);
             return None;
         }
@@ -96,11 +286,7 @@ pub fn init_opts(args: &[String]) -> Option<SysinfoOpts> {
         print_usage(&program, &opts);
         return None;
     }
-    if matches.opt_present("r") {
-        sysopts.reset_flag = true;
-    } else {
-        sysopts.reset_flag = false;
-    }
+    sysopts.reset_flag = matches.opt_present("r");
     if let Some(str_val) = matches.opt_str("t") {
         if let Ok(val) = str_val.parse::<u32>() {
             // let val = val*60;
@@ -117,7 +303,229 @@ pub fn init_opts(args: &[String]) -> Option<SysinfoOpts> {
     } else {
         sysopts.time_window = DEFAULT_WINDOW;
     }
-    sysopts.sampling_freq = sysopts.time_window/(CAPACITY as u32);
+    let window = Duration::from_secs(sysopts.time_window.into());
+    if let Some(str_val) = matches.opt_str("i") {
+        match str_val.parse::<f64>() {
+            Ok(val) if val.is_finite() && val > 0. &&
+                       val <= f64::from(MAX_WINDOW) => {
+                sysopts.sampling_interval = Duration::from_secs_f64(val);
+            },
+            _ => {
+                println!("Invalid sampling interval: {}", str_val);
+                return None;
+            }
+        }
+        if sysopts.sampling_interval < MIN_INTERVAL {
+            println!("Sampling interval must be at least {:?}", MIN_INTERVAL);
+            return None;
+        }
+    } else {
+        // Sample less often when the window would not fit in the buffers
+        sysopts.sampling_interval = DEFAULT_INTERVAL
+            .max(window/(MAX_CAPACITY as u32));
+    }
+    sysopts.capacity = (window.as_nanos()/
+                        sysopts.sampling_interval.as_nanos()) as usize;
+    if sysopts.capacity < MIN_CAPACITY {
+        println!("Sampling interval of {:?} is too long for a time window \
+                 of {:?}", sysopts.sampling_interval, window);
+        return None;
+    }
+    if sysopts.capacity > MAX_CAPACITY {
+        println!("Sampling interval of {:?} is too short for a time window \
+                 of {:?}, at most {} samples are kept",
+                 sysopts.sampling_interval, window, MAX_CAPACITY);
+        return None;
+    }
+    let windows = matches.opt_str("windows")
+        .unwrap_or_else(|| DEFAULT_WINDOWS.to_string());
+    match parse_windows(&windows, sysopts.sampling_interval) {
+        Ok(windows) => sysopts.windows = windows,
+        Err(err) => {
+            println!("{}", err);
+            return None;
+        }
+    }
+    let ewma = matches.opt_str("ewma")
+        .unwrap_or_else(|| DEFAULT_EWMA.to_string());
+    match parse_spans(&ewma, "time constant") {
+        Ok(ewma) => sysopts.ewma = ewma,
+        Err(err) => {
+            println!("{}", err);
+            return None;
+        }
+    }
+    for str_val in matches.opt_strs("l") {
+        match str_val.parse::<SocketAddr>() {
+            Ok(addr) => sysopts.listen_addrs.push(addr),
+            Err(_) => {
+                println!("Invalid listen address: {}", str_val);
+                return None;
+            }
+        }
+    }
+    sysopts.unix_socket = matches.opt_str("u").map(PathBuf::from);
+    if sysopts.listen_addrs.is_empty() && sysopts.unix_socket.is_none() {
+        sysopts.listen_addrs.push(DEFAULT_LISTEN.parse().unwrap());
+    }
+    sysopts.unix_socket_mode = DEFAULT_SOCKET_MODE;
+    if let Some(str_val) = matches.opt_str("socket-mode") {
+        match u32::from_str_radix(&str_val, 8) {
+            Ok(mode) if mode <= 0o777 => sysopts.unix_socket_mode = mode,
+            _ => {
+                println!("Invalid socket mode: {}", str_val);
+                return None;
+            }
+        }
+    }
+    sysopts.workers = DEFAULT_WORKERS;
+    if let Some(str_val) = matches.opt_str("w") {
+        match str_val.parse::<usize>() {
+            Ok(val) if val > 0 => sysopts.workers = val,
+            _ => {
+                println!("Invalid number of workers: {}", str_val);
+                return None;
+            }
+        }
+    }
+    if let Some(str_val) = matches.opt_str("b") {
+        match str_val.parse::<Backend>() {
+            Ok(backend) => sysopts.backend = backend,
+            Err(err) => {
+                println!("Invalid backend: {}", err);
+                return None;
+            }
+        }
+    }
+    sysopts.roots = FsRoots::new(
+        &matches.opt_str("proc-root")
+            .unwrap_or_else(|| collector::DEFAULT_PROC_ROOT.to_string()),
+        &matches.opt_str("sys-root")
+            .unwrap_or_else(|| collector::DEFAULT_SYS_ROOT.to_string()));
+    sysopts.state_file = matches.opt_str("state-file").map(PathBuf::from);
+    sysopts.state_interval = Duration::from_secs(DEFAULT_STATE_INTERVAL);
+    if let Some(str_val) = matches.opt_str("state-interval") {
+        match str_val.parse::<u64>() {
+            Ok(val) if val > 0 => {
+                sysopts.state_interval = Duration::from_secs(val);
+            },
+            _ => {
+                println!("Invalid state interval: {}", str_val);
+                return None;
+            }
+        }
+    }
+    sysopts.tsdb_dir = matches.opt_str("tsdb-dir").map(PathBuf::from);
+    let retention = matches.opt_str("tsdb-retention")
+        .unwrap_or_else(|| DEFAULT_TSDB_RETENTION.to_string());
+    match parse_span(&retention) {
+        Some(retention) => sysopts.tsdb_retention = retention,
+        None => {
+            println!("Invalid retention: {}", retention);
+            return None;
+        }
+    }
+    if let Some(str_val) = matches.opt_str("tsdb-max-size") {
+        match str_val.parse::<u64>() {
+            Ok(val) if val > 0 => sysopts.tsdb_max_size = Some(val << 20),
+            _ => {
+                println!("Invalid size limit: {}", str_val);
+                return None;
+            }
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    {
+        sysopts.sqlite = matches.opt_str("sqlite").map(PathBuf::from);
+        let retention = matches.opt_str("sqlite-retention")
+            .unwrap_or_else(|| DEFAULT_SQLITE_RETENTION.to_string());
+        match parse_span(&retention) {
+            Some(retention) => sysopts.sqlite_retention = retention,
+            None => {
+                println!("Invalid retention: {}", retention);
+                return None;
+            }
+        }
+    }
+    if let Some(str_val) = matches.opt_str("influx") {
+        match str_val.parse::<InfluxTarget>() {
+            Ok(target) => sysopts.influx = Some(target),
+            Err(err) => {
+                println!("Invalid InfluxDB target: {}", err);
+                return None;
+            }
+        }
+    }
+    if let Some(addr) = matches.opt_str("statsd") {
+        let mut config = StatsdConfig {
+            addr,
+            format: Default::default(),
+            prefix: matches.opt_str("statsd-prefix")
+                .filter(|prefix| !prefix.is_empty()),
+            tags: matches.opt_str("statsd-tags").unwrap_or_default()
+                .split(',').map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect(),
+            mtu: statsd::DEFAULT_MTU,
+        };
+        if let Some(str_val) = matches.opt_str("statsd-format") {
+            match str_val.parse() {
+                Ok(format) => config.format = format,
+                Err(err) => {
+                    println!("Invalid StatsD format: {}", err);
+                    return None;
+                }
+            }
+        }
+        if !config.tags.is_empty() &&
+            config.format != statsd::StatsdFormat::Dogstatsd {
+            println!("StatsD tags require the dogstatsd format");
+            return None;
+        }
+        if let Some(str_val) = matches.opt_str("statsd-mtu") {
+            match str_val.parse::<usize>() {
+                Ok(val) if val >= 64 => config.mtu = val,
+                _ => {
+                    println!("Invalid StatsD MTU: {}", str_val);
+                    return None;
+                }
+            }
+        }
+        sysopts.statsd = Some(config);
+    }
+    if let Some(addr) = matches.opt_str("graphite") {
+        let mut config = GraphiteConfig {
+            addr,
+            protocol: Default::default(),
+            template: matches.opt_str("graphite-template")
+                .unwrap_or_else(|| graphite::DEFAULT_TEMPLATE.to_string()),
+            interval: graphite::DEFAULT_INTERVAL,
+        };
+        if let Some(str_val) = matches.opt_str("graphite-protocol") {
+            match str_val.parse() {
+                Ok(protocol) => config.protocol = protocol,
+                Err(err) => {
+                    println!("Invalid Graphite protocol: {}", err);
+                    return None;
+                }
+            }
+        }
+        if !config.template.contains("{path}") {
+            println!("Invalid Graphite template, {{path}} is missing: {}",
+                     config.template);
+            return None;
+        }
+        if let Some(str_val) = matches.opt_str("graphite-interval") {
+            match str_val.parse::<u64>() {
+                Ok(val) if val > 0 => config.interval = Duration::from_secs(val),
+                _ => {
+                    println!("Invalid Graphite interval: {}", str_val);
+                    return None;
+                }
+            }
+        }
+        sysopts.graphite = Some(config);
+    }
     println!("{:?}", sysopts);
 
     Some(sysopts)
@@ -127,22 +535,106 @@ pub fn init_opts(args: &[String]) -> Option<SysinfoOpts> {
 
 pub fn run_systats_reader(opts: SysinfoOpts) -> Result<(), Error> {
     let run_flag: Arc<RwLock<bool>> = Arc::new(RwLock::new(true));
-    let sysinfo = System::new_all();
     let schema: Arc<DefaultSchemaBuilder> = Arc::new(DefaultSchemaBuilder::new());
-    let systats_executor = SystatsExecutor::new(CAPACITY,
-                                                opts.sampling_freq.into(),
-                                                opts.reset_flag,
-                                                Arc::clone(&schema)); 
-    let systats_handler = systats_executor.run_executor(sysinfo,
-                                                        Arc::clone(&run_flag));
-    let server_handler = server::start_server(Arc::clone(&schema));
-
-    handle_signals(Arc::clone(&run_flag))?;
+    let spec = BufferSpec {
+        capacity: opts.capacity,
+        rst: opts.reset_flag,
+        windows: opts.windows.clone(),
+        ewma: opts.ewma.clone(),
+        interval: opts.sampling_interval,
+    };
+    let mut systats_executor = SystatsExecutor::new(&spec,
+                                                opts.sampling_interval,
+                                                collector::collectors(opts.backend, &opts.roots),
+                                                Arc::clone(&schema));
+    if let Some(path) = &opts.state_file {
+        systats_executor.set_state_file(path.clone(), opts.state_interval);
+    }
+    let tsdb = match &opts.tsdb_dir {
+        Some(dir) => Some(Arc::new(Tsdb::open(dir, opts.tsdb_retention,
+                                              opts.tsdb_max_size)?)),
+        None => None,
+    };
+    if let Some(tsdb) = &tsdb {
+        systats_executor.set_tsdb(Arc::clone(tsdb));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &opts.sqlite {
+        let sink = sqlite::SqliteSink::open(path, opts.sqlite_retention)
+            .map_err(|err| Error::other(format!("failed to open {}: {}",
+                                                path.display(), err)))?;
+        systats_executor.add_sink(Box::new(sink));
+    }
+    if let Some(target) = &opts.influx {
+        systats_executor.add_sink(Box::new(InfluxSink::new(target.clone())));
+    }
+    if let Some(config) = &opts.statsd {
+        systats_executor.add_sink(Box::new(StatsdSink::new(config.clone())));
+    }
+    if let Some(config) = &opts.graphite {
+        systats_executor.add_sink(Box::new(
+            GraphiteSink::new(config.clone(), Arc::clone(&schema))));
+    }
+    let systats = systats_executor.get_systats();
+    let server_handler = server::start_server(&opts, Arc::clone(&schema),
+                                              Arc::clone(&systats), tsdb)?;
+    let systats_handler = systats_executor.run_executor(Arc::clone(&run_flag));
+
+    handle_signals(Arc::clone(&run_flag), systats,
+                   opts.state_file.as_deref())?;
     let _ = systats_handler.join().unwrap();
     server::stop_server(&server_handler);
     Ok(())
 }
 
+#[test]
+fn test_init_opts_listen() {
+    let program = "sysinfo".to_string();
+    // Default listen address and workers
+    let opts = init_opts(std::slice::from_ref(&program)).unwrap();
+    assert_eq!(opts.listen_addrs, vec![DEFAULT_LISTEN.parse().unwrap()]);
+    assert_eq!(opts.unix_socket, None);
+    assert_eq!(opts.workers, DEFAULT_WORKERS);
+    // Several TCP addresses, including IPv6
+    let t1 = [program.clone(), "-l".to_string(), "0.0.0.0:9000".to_string(),
+              "--listen".to_string(), "[::1]:9001".to_string(),
+              "-w".to_string(), "4".to_string()];
+    let opts = init_opts(&t1).unwrap();
+    assert_eq!(opts.listen_addrs, vec!["0.0.0.0:9000".parse().unwrap(),
+                                       "[::1]:9001".parse().unwrap()]);
+    assert_eq!(opts.workers, 4);
+    // Unix socket only disables the default TCP address
+    let t2 = [program.clone(), "-u".to_string(), "/tmp/sysinfo.sock".to_string(),
+              "--socket-mode".to_string(), "600".to_string()];
+    let opts = init_opts(&t2).unwrap();
+    assert!(opts.listen_addrs.is_empty());
+    assert_eq!(opts.unix_socket, Some(PathBuf::from("/tmp/sysinfo.sock")));
+    assert_eq!(opts.unix_socket_mode, 0o600);
+    // Invalid values
+    let t3 = [program.clone(), "-l".to_string(), "localhost".to_string()];
+    assert!(init_opts(&t3).is_none());
+    let t4 = [program.clone(), "-w".to_string(), "0".to_string()];
+    assert!(init_opts(&t4).is_none());
+    let t5 = [program.clone(), "--socket-mode".to_string(), "999".to_string()];
+    assert!(init_opts(&t5).is_none());
+    // Backend
+    assert_eq!(init_opts(std::slice::from_ref(&program)).unwrap().backend,
+               Backend::Sysinfo);
+    let t6 = [program.clone(), "-b".to_string(), "procfs".to_string()];
+    assert_eq!(init_opts(&t6).unwrap().backend, Backend::Procfs);
+    let t7 = [program.clone(), "--backend".to_string(), "wmi".to_string()];
+    assert!(init_opts(&t7).is_none());
+    // Roots of procfs and sysfs
+    assert!(init_opts(std::slice::from_ref(&program)).unwrap().roots.is_default());
+    let t8 = [program, "--proc-root".to_string(), "/host/proc/".to_string(),
+              "--sys-root".to_string(), "/host/sys".to_string()];
+    let roots = init_opts(&t8).unwrap().roots;
+    assert_eq!(roots.path("/proc/stat"), "/host/proc/stat");
+    assert_eq!(roots.path("/sys/class/net"), "/host/sys/class/net");
+    assert_eq!(roots.path("/etc/os-release"), "/etc/os-release");
+    assert_eq!(roots.path("/system"), "/system");
+}
+
 #[test]
 fn test_init_opts() {
     // Empty args
@@ -161,25 +653,146 @@ fn test_init_opts() {
     // Test valid option with invalid data
     let t3 = [a[0].clone(), a[2].clone(), "str".to_string()];
     assert!(init_opts(&t3).is_none());
-    // Test valid option upper bound
+    // Test valid option upper bound, sampled less often to fit the buffers
     let max = MAX_WINDOW + 9999;
-    let freq = MAX_WINDOW/(CAPACITY as u32);
-    let window = MAX_WINDOW;
     let t4 = [a[0].clone(), a[2].clone(), max.to_string()];
     assert_eq!(init_opts(&t4),
-               Some(SysinfoOpts { sampling_freq: freq, time_window: window, reset_flag: true }));
+               Some(SysinfoOpts { sampling_interval: Duration::from_secs(24),
+                                  time_window: MAX_WINDOW,
+                                  capacity: MAX_CAPACITY,
+                                  ..Default::default() }));
     // Test valid option lower bound
     let min = MIN_WINDOW - 4;
-    let freq = MIN_WINDOW/(CAPACITY as u32);
-    let window = MIN_WINDOW;
     let t5 = [a[0].clone(), a[2].clone(), min.to_string()];
     assert_eq!(init_opts(&t5),
-               Some(SysinfoOpts { sampling_freq: freq, time_window: window, reset_flag: true }));
+               Some(SysinfoOpts { sampling_interval: DEFAULT_INTERVAL,
+                                  time_window: MIN_WINDOW,
+                                  capacity: MIN_WINDOW as usize,
+                                  ..Default::default() }));
     // Test allowed values
     let val: u32 = (MAX_WINDOW - MIN_WINDOW)/2;
-    let freq = val/(CAPACITY as u32);
-    let window = val;
     let t6 = [a[0].clone(), a[2].clone(), val.to_string()];
-    assert_eq!(init_opts(&t6),
-               Some(SysinfoOpts { sampling_freq: freq, time_window: window, reset_flag: true }));
+    let opts = init_opts(&t6).unwrap();
+    assert_eq!(opts.time_window, val);
+    assert_eq!(opts.capacity, MAX_CAPACITY);
+    // Sub-second sampling interval
+    let t7 = [a[0].clone(), a[2].clone(), "60".to_string(),
+              "-i".to_string(), "0.25".to_string()];
+    assert_eq!(init_opts(&t7),
+               Some(SysinfoOpts { sampling_interval: Duration::from_millis(250),
+                                  time_window: 60, capacity: 240,
+                                  ..Default::default() }));
+    // Nonsensical combinations
+    for interval in ["0", "-1", "nan", "0.01", "31", "0.5x"] {
+        let t8 = [a[0].clone(), a[2].clone(), "60".to_string(),
+                  "--interval".to_string(), interval.to_string()];
+        assert!(init_opts(&t8).is_none(), "interval {}", interval);
+    }
+    // Too many samples for the window
+    let t9 = [a[0].clone(), "-i".to_string(), "0.5".to_string()];
+    assert!(init_opts(&t9).is_none());
+    // Aggregation windows
+    let names: Vec<String> = init_opts(&a[..1]).unwrap().windows.iter()
+        .map(|window| window.name.to_string())
+        .collect();
+    assert_eq!(names, vec!["1m", "5m", "1h", "24h"]);
+    let t10 = [a[0].clone(), "-i".to_string(), "2".to_string(),
+               "--windows".to_string(), "90s, 1d".to_string()];
+    let windows = init_opts(&t10).unwrap().windows;
+    assert_eq!(windows[0], Window { name: "90s".into(),
+                                    span: Duration::from_secs(90), len: 45 });
+    assert_eq!(windows[1].len, 43200);
+    for list in ["5x", "0m", "1s", "1m,1m", "m", "25h"] {
+        let t11 = [a[0].clone(), "-i".to_string(), "2".to_string(),
+                   "--windows".to_string(), list.to_string()];
+        assert!(init_opts(&t11).is_none(), "windows {}", list);
+    }
+    // Time constants of the moving averages
+    let ewma = init_opts(&a[..1]).unwrap().ewma;
+    assert_eq!(ewma[2], ("15m".into(), Duration::from_secs(15*60)));
+    let t12 = [a[0].clone(), "--ewma".to_string(), "10s,1s".to_string()];
+    assert_eq!(init_opts(&t12).unwrap().ewma.len(), 2);
+    let t13 = [a[0].clone(), "--ewma".to_string(), "10s,10s".to_string()];
+    assert!(init_opts(&t13).is_none());
+    // State file, saved every minute unless told otherwise
+    let opts = init_opts(&a[..1]).unwrap();
+    assert_eq!((opts.state_file, opts.state_interval),
+               (None, Duration::from_secs(DEFAULT_STATE_INTERVAL)));
+    let t14 = [a[0].clone(), "--state-file".to_string(),
+               "/var/lib/sysinfo.json".to_string(),
+               "--state-interval".to_string(), "300".to_string()];
+    let opts = init_opts(&t14).unwrap();
+    assert_eq!(opts.state_file, Some(PathBuf::from("/var/lib/sysinfo.json")));
+    assert_eq!(opts.state_interval, Duration::from_secs(300));
+    let t15 = [a[0].clone(), "--state-interval".to_string(), "0".to_string()];
+    assert!(init_opts(&t15).is_none());
+    // On-disk store
+    let opts = init_opts(&a[..1]).unwrap();
+    assert_eq!((opts.tsdb_dir, opts.tsdb_retention, opts.tsdb_max_size),
+               (None, Duration::from_secs(7*24*60*60), None));
+    let t16 = [a[0].clone(), "--tsdb-dir".to_string(), "/var/lib/tsdb".to_string(),
+               "--tsdb-retention".to_string(), "30d".to_string(),
+               "--tsdb-max-size".to_string(), "512".to_string()];
+    let opts = init_opts(&t16).unwrap();
+    assert_eq!(opts.tsdb_dir, Some(PathBuf::from("/var/lib/tsdb")));
+    assert_eq!(opts.tsdb_retention, Duration::from_secs(30*24*60*60));
+    assert_eq!(opts.tsdb_max_size, Some(512*1024*1024));
+    for (opt, val) in [("--tsdb-retention", "0d"), ("--tsdb-max-size", "0")] {
+        let t17 = [a[0].clone(), opt.to_string(), val.to_string()];
+        assert!(init_opts(&t17).is_none(), "{} {}", opt, val);
+    }
+    // SQLite sink, only built with its feature
+    let t18 = [a[0].clone(), "--sqlite".to_string(), "/tmp/samples.db".to_string()];
+    #[cfg(feature = "sqlite")]
+    {
+        let opts = init_opts(&t18).unwrap();
+        assert_eq!(opts.sqlite, Some(PathBuf::from("/tmp/samples.db")));
+        assert_eq!(opts.sqlite_retention, Duration::from_secs(30*24*60*60));
+    }
+    #[cfg(not(feature = "sqlite"))]
+    assert!(init_opts(&t18).is_none());
+    // InfluxDB sink
+    let t19 = [a[0].clone(), "--influx".to_string(),
+               "http://localhost:8086/write?db=sysinfo".to_string()];
+    assert_eq!(init_opts(&t19).unwrap().influx,
+               Some(InfluxTarget::Http { addr: "localhost:8086".to_string(),
+                                         path: "/write?db=sysinfo".to_string() }));
+    let t20 = [a[0].clone(), "--influx".to_string(), "tcp://localhost".to_string()];
+    assert!(init_opts(&t20).is_none());
+    // StatsD sink
+    let t21 = [a[0].clone(), "--statsd".to_string(), "127.0.0.1:8125".to_string(),
+               "--statsd-format".to_string(), "dogstatsd".to_string(),
+               "--statsd-prefix".to_string(), "sysinfo".to_string(),
+               "--statsd-tags".to_string(), "env:prod, team:ops".to_string()];
+    assert_eq!(init_opts(&t21).unwrap().statsd, Some(StatsdConfig {
+        addr: "127.0.0.1:8125".to_string(),
+        format: statsd::StatsdFormat::Dogstatsd,
+        prefix: Some("sysinfo".to_string()),
+        tags: vec!["env:prod".to_string(), "team:ops".to_string()],
+        mtu: statsd::DEFAULT_MTU,
+    }));
+    for (opt, val) in [("--statsd-tags", "env:prod"), ("--statsd-mtu", "10"),
+                       ("--statsd-format", "graphite")] {
+        let t22 = [a[0].clone(), "--statsd".to_string(),
+                   "127.0.0.1:8125".to_string(), opt.to_string(), val.to_string()];
+        assert!(init_opts(&t22).is_none(), "{} {}", opt, val);
+    }
+    // Graphite sink
+    let t23 = [a[0].clone(), "--graphite".to_string(), "carbon:2004".to_string(),
+               "--graphite-protocol".to_string(), "pickle".to_string(),
+               "--graphite-template".to_string(),
+               "servers.{host}.{path}".to_string()];
+    assert_eq!(init_opts(&t23).unwrap().graphite, Some(GraphiteConfig {
+        addr: "carbon:2004".to_string(),
+        protocol: graphite::GraphiteProtocol::Pickle,
+        template: "servers.{host}.{path}".to_string(),
+        interval: Duration::from_secs(10),
+    }));
+    for (opt, val) in [("--graphite-template", "servers.{host}"),
+                       ("--graphite-interval", "0"),
+                       ("--graphite-protocol", "json")] {
+        let t24 = [a[0].clone(), "--graphite".to_string(),
+                   "carbon:2003".to_string(), opt.to_string(), val.to_string()];
+        assert!(init_opts(&t24).is_none(), "{} {}", opt, val);
+    }
 }
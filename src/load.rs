@@ -0,0 +1,187 @@
+//! This module collects the load of the system from /proc/loadavg and
+//! the scheduler activity counters from /proc/stat
+
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::Result;
+use crate::counter::CounterBuffer;
+use crate::ringbuf::{BufferSpec, RestoreSeries, RingStatsBuffer, SampleSeries};
+use crate::systats::SystatsData;
+use crate::utils;
+
+
+pub static LOAD_AVG: &str = "/proc/loadavg";
+pub static PROC_STAT: &str = "/proc/stat";
+
+#[derive(Default, Debug, PartialEq)]
+pub struct LoadAvg {
+    pub load1: f64,
+    pub load5: f64,
+    pub load15: f64,
+}
+
+pub fn parse_loadavg(content: &str) -> Option<LoadAvg> {
+    let mut fields = content.split_whitespace()
+        .map(|field| field.parse::<f64>().ok());
+    Some(LoadAvg {
+        load1: fields.next()??,
+        load5: fields.next()??,
+        load15: fields.next()??,
+    })
+}
+
+/// Scheduler counters of /proc/stat
+#[derive(Default, Debug, PartialEq)]
+pub struct ProcStat {
+    pub ctxt: u64,
+    pub intr: u64,
+    pub processes: u64,
+    pub procs_running: u64,
+    pub procs_blocked: u64,
+}
+
+pub fn parse_proc_stat(content: &str) -> Option<ProcStat> {
+    let value = |key: &str| -> Option<u64> {
+        let line = content.lines().find(|line| {
+            line.split_whitespace().next() == Some(key)
+        })?;
+        // The first value of intr is the total of interrupts
+        line.split_whitespace().nth(1)?.parse::<u64>().ok()
+    };
+    Some(ProcStat {
+        ctxt: value("ctxt")?,
+        intr: value("intr")?,
+        processes: value("processes")?,
+        procs_running: value("procs_running")?,
+        procs_blocked: value("procs_blocked")?,
+    })
+}
+
+pub struct LoadStats {
+    pub load1: RingStatsBuffer<f64>,
+    pub load5: RingStatsBuffer<f64>,
+    pub load15: RingStatsBuffer<f64>,
+    pub procs_running: RingStatsBuffer<u64>,
+    pub procs_blocked: RingStatsBuffer<u64>,
+    pub forks: CounterBuffer,
+    pub context_switches: CounterBuffer,
+    pub interrupts: CounterBuffer,
+}
+
+impl LoadStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        LoadStats {
+            load1: RingStatsBuffer::with_spec(spec),
+            load5: RingStatsBuffer::with_spec(spec),
+            load15: RingStatsBuffer::with_spec(spec),
+            procs_running: RingStatsBuffer::with_spec(spec),
+            procs_blocked: RingStatsBuffer::with_spec(spec),
+            forks: CounterBuffer::new(spec),
+            context_switches: CounterBuffer::new(spec),
+            interrupts: CounterBuffer::new(spec),
+        }
+    }
+
+    /// Lists the buffers along with the name of their metric. Counters are
+    /// listed by their rates per second
+    pub fn buffers(&self) -> [(&'static str, &dyn SampleSeries); 8] {
+        [
+            ("load1", &self.load1),
+            ("load5", &self.load5),
+            ("load15", &self.load15),
+            ("procs_running", &self.procs_running),
+            ("procs_blocked", &self.procs_blocked),
+            ("forks", &self.forks.rate),
+            ("context_switches", &self.context_switches.rate),
+            ("interrupts", &self.interrupts.rate),
+        ]
+    }
+
+    /// Same as buffers, to restore them
+    pub fn buffers_mut(&mut self) -> [(&'static str, &mut dyn RestoreSeries); 8] {
+        [
+            ("load1", &mut self.load1),
+            ("load5", &mut self.load5),
+            ("load15", &mut self.load15),
+            ("procs_running", &mut self.procs_running),
+            ("procs_blocked", &mut self.procs_blocked),
+            ("forks", &mut self.forks.rate),
+            ("context_switches", &mut self.context_switches.rate),
+            ("interrupts", &mut self.interrupts.rate),
+        ]
+    }
+
+    pub fn push_loadavg(&mut self, loadavg: LoadAvg) {
+        self.load1.push_back(loadavg.load1);
+        self.load5.push_back(loadavg.load5);
+        self.load15.push_back(loadavg.load15);
+    }
+
+    pub fn push_proc_stat(&mut self, stat: ProcStat, now: Instant) {
+        self.procs_running.push_back(stat.procs_running);
+        self.procs_blocked.push_back(stat.procs_blocked);
+        self.forks.push(stat.processes, now);
+        self.context_switches.push(stat.ctxt, now);
+        self.interrupts.push(stat.intr, now);
+    }
+
+    /// Reads and pushes both /proc/loadavg and /proc/stat
+    pub fn read(&mut self, roots: &FsRoots, now: Instant) -> Result<()> {
+        let loadavg = utils::parse_file(&roots.path(LOAD_AVG), parse_loadavg)?;
+        self.push_loadavg(loadavg);
+        let stat = utils::parse_file(&roots.path(PROC_STAT), parse_proc_stat)?;
+        self.push_proc_stat(stat, now);
+        Ok(())
+    }
+}
+
+pub struct LoadCollector {
+    roots: FsRoots,
+}
+
+impl LoadCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        LoadCollector { roots }
+    }
+}
+
+impl Collector for LoadCollector {
+    fn name(&self) -> &'static str {
+        "load"
+    }
+
+    fn collect(&mut self, systats: &mut SystatsData, now: Instant)
+        -> Result<()> {
+        systats.load.read(&self.roots, now)
+    }
+}
+
+#[test]
+fn test_load() {
+    use std::time::Duration;
+    assert_eq!(parse_loadavg("0.52 1.10 2.00 3/456 7890\n"),
+               Some(LoadAvg { load1: 0.52, load5: 1.1, load15: 2. }));
+    assert_eq!(parse_loadavg("0.52\n"), None);
+    let content = "\
+cpu  10 20 30 40 50 60 70 0 0 0\n\
+cpu0 10 20 30 40 50 60 70 0 0 0\n\
+intr 12345 1 2 3 4\n\
+ctxt 67890\n\
+btime 1600000000\n\
+processes 4321\n\
+procs_running 3\n\
+procs_blocked 1\n";
+    let stat = parse_proc_stat(content).unwrap();
+    assert_eq!(stat, ProcStat { ctxt: 67890, intr: 12345, processes: 4321,
+                                procs_running: 3, procs_blocked: 1 });
+    let start = Instant::now();
+    let mut load = LoadStats::new(&BufferSpec::new(4, false));
+    load.push_proc_stat(stat, start);
+    let stat = ProcStat { ctxt: 68890, intr: 12845, processes: 4331,
+                          procs_running: 5, procs_blocked: 0 };
+    load.push_proc_stat(stat, start + Duration::from_secs(5));
+    assert_eq!(load.context_switches.rate.get_last(), Some(200.));
+    assert_eq!(load.interrupts.rate.get_last(), Some(100.));
+    assert_eq!(load.forks.rate.get_last(), Some(2.));
+    assert_eq!(load.procs_running.get_last(), Some(5));
+}
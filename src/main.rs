@@ -10,7 +10,9 @@ fn main() -> Result<(), Error> {
         None => std::process::exit(1)
     };
 
-    run_systats_reader(opts)?;
+    if let Err(e) = run_systats_reader(opts) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
     Ok(())
 }
-
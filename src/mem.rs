@@ -1,111 +1,265 @@
-//! This module implements the SysInfo trait for the RAM subsystem,
-//! allowing to collect and display data from RAM
+//! This module collects the breakdown of the RAM and swap usage from
+//! /proc/meminfo and the swapping activity from /proc/vmstat, along with
+//! the totals of the memory for the native backend
 
-use std::default::Default;
-use super::SysInfo;
+use std::collections::HashMap;
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::Result;
+use crate::counter::CounterBuffer;
+use crate::ringbuf::{BufferSpec, RestoreSeries, RingStatsBuffer, SampleSeries};
+use crate::systats::SystatsData;
 use crate::utils;
 
-static MEM_INFO: &str = "/proc/meminfo";
+pub static MEM_INFO: &str = "/proc/meminfo";
+pub static VM_STAT: &str = "/proc/vmstat";
 
-#[derive(Default)]
+/// Values of /proc/meminfo, in kB except for the huge pages counts
+#[derive(Default, Debug, PartialEq)]
 pub struct MemInfo {
-    total: u32,
-    used: u32,
-    free: u32,
-    avail: u32,
-    buff: u32,
-    swapt: u32,
-    swapu: u32,
-    swapf: u32,
+    pub total: u64,
+    pub free: u64,
+    pub avail: u64,
+    pub buffers: u64,
+    pub cached: u64,
+    pub shared: u64,
+    pub slab: u64,
+    pub sreclaimable: u64,
+    pub dirty: u64,
+    pub writeback: u64,
+    pub swapt: u64,
+    pub swapf: u64,
+    pub hugepages_total: u64,
+    pub hugepages_free: u64,
+    pub hugepage_size: u64,
 }
 
 impl MemInfo {
-    fn read_mem_info(&mut self) {
-        let content: String = utils::open_and_read(MEM_INFO);
-        let trim: &[_] = &['\n', ' ', ':', 'k', 'B'];
-        let total_str = utils::parse_key_from_text(&content,
-            "MemTotal", "\n", Some(trim));
-        if total_str.is_some() {
-            self.total = match total_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
+    pub fn parse(content: &str) -> Self {
+        let values: HashMap<&str, u64> = content.lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let value = value.trim().trim_end_matches("kB").trim();
+                Some((key, value.parse::<u64>().ok()?))
+            })
+            .collect();
+        let value = |key: &str| values.get(key).copied().unwrap_or(0);
+        MemInfo {
+            total: value("MemTotal"),
+            free: value("MemFree"),
+            avail: value("MemAvailable"),
+            buffers: value("Buffers"),
+            cached: value("Cached"),
+            shared: value("Shmem"),
+            slab: value("Slab"),
+            sreclaimable: value("SReclaimable"),
+            dirty: value("Dirty"),
+            writeback: value("Writeback"),
+            swapt: value("SwapTotal"),
+            swapf: value("SwapFree"),
+            hugepages_total: value("HugePages_Total"),
+            hugepages_free: value("HugePages_Free"),
+            hugepage_size: value("Hugepagesize"),
         }
-        let free_str = utils::parse_key_from_text(&content,
-            "MemFree", "\n", Some(trim));
-        if free_str.is_some() {
-            self.free = match free_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
-        }
-        let avail_str = utils::parse_key_from_text(&content,
-            "MemAvailable", "\n", Some(trim));
-        if avail_str.is_some() {
-            self.avail = match avail_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
-        }
-        let cached_str = utils::parse_key_from_text(&content,
-            "Cached", "\n", Some(trim));
-        let buff_str = utils::parse_key_from_text(&content,
-            "Buffers", "\n", Some(trim));
-        let mut cached: u32 = 0;
-        if cached_str.is_some() {
-            cached = match cached_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
-        }
-        if buff_str.is_some() {
-            self.buff = cached + match buff_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
-        }
-        let swapt_str = utils::parse_key_from_text(&content,
-            "SwapTotal", "\n", Some(trim));
-        if swapt_str.is_some() {
-            self.swapt = match swapt_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
-        }
-        let swapf_str = utils::parse_key_from_text(&content,
-            "SwapFree", "\n", Some(trim));
-        if swapf_str.is_some() {
-            self.swapf = match swapf_str.unwrap().parse::<u32>() {
-                Ok(m) => m,
-                _ => 0
-            };
-        }
-        self.used = self.total - self.free - self.buff;
-        self.swapu = self.swapt - self.swapf;
     }
+
+    /// Memory used by buffers and page cache, as reported by free(1)
+    pub fn buff_cache(&self) -> u64 {
+        self.buffers + self.cached
+    }
+
+    /// Memory used, as computed by the sysinfo backend
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.free + self.buff_cache() +
+                                  self.sreclaimable)
+    }
+
+    pub fn swap_used(&self) -> u64 {
+        self.swapt.saturating_sub(self.swapf)
+    }
+}
+
+/// Pages swapped in and out since boot, from /proc/vmstat
+#[derive(Default, Debug, PartialEq)]
+pub struct VmStat {
+    pub pswpin: u64,
+    pub pswpout: u64,
+}
+
+pub fn parse_vmstat(content: &str) -> Option<VmStat> {
+    let value = |key: &str| -> Option<u64> {
+        content.lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))?
+            .trim().parse::<u64>().ok()
+    };
+    Some(VmStat {
+        pswpin: value("pswpin")?,
+        pswpout: value("pswpout")?,
+    })
+}
+
+fn page_size() -> u64 {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 { size as u64 } else { 4096 }
 }
 
-impl SysInfo for MemInfo {
-    fn new() -> Self {
-        MemInfo::default()
+pub struct MemStats {
+    pub buff_cache: RingStatsBuffer<u64>,
+    pub buffers: RingStatsBuffer<u64>,
+    pub cached: RingStatsBuffer<u64>,
+    pub shared: RingStatsBuffer<u64>,
+    pub slab: RingStatsBuffer<u64>,
+    pub dirty: RingStatsBuffer<u64>,
+    pub writeback: RingStatsBuffer<u64>,
+    pub swap_used: RingStatsBuffer<u64>,
+    /// Bytes swapped in and out, as rates per second
+    pub swap_in: CounterBuffer,
+    pub swap_out: CounterBuffer,
+    pub hugepages_total: RingStatsBuffer<u64>,
+    pub hugepages_free: RingStatsBuffer<u64>,
+    pub hugepage_size: u64,
+    page_size: u64,
+}
+
+impl MemStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        MemStats {
+            buff_cache: RingStatsBuffer::with_spec(spec),
+            buffers: RingStatsBuffer::with_spec(spec),
+            cached: RingStatsBuffer::with_spec(spec),
+            shared: RingStatsBuffer::with_spec(spec),
+            slab: RingStatsBuffer::with_spec(spec),
+            dirty: RingStatsBuffer::with_spec(spec),
+            writeback: RingStatsBuffer::with_spec(spec),
+            swap_used: RingStatsBuffer::with_spec(spec),
+            swap_in: CounterBuffer::new(spec),
+            swap_out: CounterBuffer::new(spec),
+            hugepages_total: RingStatsBuffer::with_spec(spec),
+            hugepages_free: RingStatsBuffer::with_spec(spec),
+            hugepage_size: 0,
+            page_size: page_size(),
+        }
+    }
+
+    /// Lists the buffers along with the name of their metric
+    pub fn buffers(&self) -> [(&'static str, &dyn SampleSeries); 12] {
+        [
+            ("mem_buffer", &self.buff_cache),
+            ("mem_buffers", &self.buffers),
+            ("mem_cached", &self.cached),
+            ("mem_shared", &self.shared),
+            ("mem_slab", &self.slab),
+            ("mem_dirty", &self.dirty),
+            ("mem_writeback", &self.writeback),
+            ("swap_used", &self.swap_used),
+            ("swap_in", &self.swap_in.rate),
+            ("swap_out", &self.swap_out.rate),
+            ("hugepages_total", &self.hugepages_total),
+            ("hugepages_free", &self.hugepages_free),
+        ]
+    }
+
+    /// Same as buffers, to restore them
+    pub fn buffers_mut(&mut self) -> [(&'static str, &mut dyn RestoreSeries); 12] {
+        [
+            ("mem_buffer", &mut self.buff_cache),
+            ("mem_buffers", &mut self.buffers),
+            ("mem_cached", &mut self.cached),
+            ("mem_shared", &mut self.shared),
+            ("mem_slab", &mut self.slab),
+            ("mem_dirty", &mut self.dirty),
+            ("mem_writeback", &mut self.writeback),
+            ("swap_used", &mut self.swap_used),
+            ("swap_in", &mut self.swap_in.rate),
+            ("swap_out", &mut self.swap_out.rate),
+            ("hugepages_total", &mut self.hugepages_total),
+            ("hugepages_free", &mut self.hugepages_free),
+        ]
+    }
+
+    pub fn push_meminfo(&mut self, meminfo: MemInfo) {
+        self.buff_cache.push_back(meminfo.buff_cache());
+        self.buffers.push_back(meminfo.buffers);
+        self.cached.push_back(meminfo.cached);
+        self.shared.push_back(meminfo.shared);
+        self.slab.push_back(meminfo.slab);
+        self.dirty.push_back(meminfo.dirty);
+        self.writeback.push_back(meminfo.writeback);
+        self.swap_used.push_back(meminfo.swap_used());
+        self.hugepages_total.push_back(meminfo.hugepages_total);
+        self.hugepages_free.push_back(meminfo.hugepages_free);
+        self.hugepage_size = meminfo.hugepage_size;
     }
 
-    fn read(&mut self) {
-        self.read_mem_info();
+    pub fn push_vmstat(&mut self, vmstat: VmStat, now: Instant) {
+        self.swap_in.push(vmstat.pswpin*self.page_size, now);
+        self.swap_out.push(vmstat.pswpout*self.page_size, now);
     }
+}
+
+/// Reads the breakdown of the memory and the swapping activity, along
+/// with the totals and usage of the RAM when `totals` is set
+pub struct MemCollector {
+    totals: bool,
+    roots: FsRoots,
+}
 
-    fn display(&self) {
-        println!("|{:=^85}|", " MEM INFO ");
-        println!("| {:19} | {:18} | {:18} | {:19} |", "total (kB)", "used (kB)",
-                 "free (kB)", "available (kB)");
-        println!("| {:19} | {:18} | {:18} | {:19} |", self.total, self.used,
-                 self.free, self.avail);
-        println!("|{:-^85}|", "-");
-        println!("| {:19} | {:18} | {:18} | {:19} |", "buff/cached (kB)",
-                "swap total (kB)", "swap used (kB)", "swap free (kB)");
-        println!("| {:19} | {:18} | {:18} | {:19} |", self.buff,  self.swapt,
-                 self.swapu, self.swapf);
+impl MemCollector {
+    pub fn new(totals: bool, roots: FsRoots) -> Self {
+        MemCollector { totals, roots }
     }
 }
 
+impl Collector for MemCollector {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
 
+    fn collect(&mut self, systats: &mut SystatsData, now: Instant)
+        -> Result<()> {
+        let meminfo = utils::read_file(&self.roots.path(MEM_INFO))
+            .map(|content| MemInfo::parse(&content))?;
+        if self.totals {
+            systats.total_mem = meminfo.total;
+            systats.total_swap = meminfo.swapt;
+            systats.mem_free.push_back(meminfo.free);
+            systats.mem_used.push_back(meminfo.used());
+            systats.mem_available.push_back(meminfo.avail);
+        }
+        systats.memory.push_meminfo(meminfo);
+        let vmstat = utils::parse_file(&self.roots.path(VM_STAT), parse_vmstat)?;
+        systats.memory.push_vmstat(vmstat, now);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_meminfo() {
+    let content = "\
+MemTotal:        8000000 kB\n\
+MemFree:         1000000 kB\n\
+MemAvailable:    5000000 kB\n\
+Buffers:          200000 kB\n\
+Cached:          3000000 kB\n\
+SwapCached:         1000 kB\n\
+SwapTotal:       2000000 kB\n\
+SwapFree:        1500000 kB\n\
+Dirty:               120 kB\n\
+Writeback:             8 kB\n\
+Shmem:             50000 kB\n\
+Slab:             400000 kB\n\
+HugePages_Total:      16\n\
+HugePages_Free:        4\n\
+Hugepagesize:       2048 kB\n";
+    let meminfo = MemInfo::parse(content);
+    assert_eq!(meminfo.cached, 3000000);
+    assert_eq!(meminfo.buff_cache(), 3200000);
+    assert_eq!(meminfo.swap_used(), 500000);
+    assert_eq!(meminfo.used(), 8000000 - 1000000 - 3200000);
+    assert_eq!(meminfo.shared, 50000);
+    assert_eq!(meminfo.hugepages_free, 4);
+    assert_eq!(meminfo.hugepage_size, 2048);
+    let content = "nr_free_pages 100\npswpin 10\npswpout 25\npswpout_zero 1\n";
+    assert_eq!(parse_vmstat(content), Some(VmStat { pswpin: 10, pswpout: 25 }));
+    assert_eq!(parse_vmstat("nr_free_pages 100\n"), None);
+}
@@ -1,97 +1,96 @@
-//! This module implements the SysInfo trait for the Network subsystem,
-//! allowing to collect and display data from network interfaces
+//! This module collects the counters and the MAC address of the network
+//! interfaces from sysfs
 
-use std::default::Default;
-use std::io;
 use std::fs;
-use super::SysInfo;
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::{Error, Result};
+use crate::systats::{NetworkStats, SystatsData};
 use crate::utils;
 
 static NET_IFACES_DIR: &str = "/sys/class/net";
+pub static NET_STATISTICS: &str = "/sys/class/net/@/statistics";
 
-#[derive(Default)]
+/// Counters of an interface, as named in its statistics directory
+const COUNTERS: [&str; 8] = [
+    "rx_bytes", "tx_bytes", "rx_packets", "tx_packets",
+    "rx_errors", "tx_errors", "rx_dropped", "tx_dropped",
+];
+
+#[derive(Default, Debug, PartialEq)]
 pub struct IfaceInfo {
-    name: String,
-    addr: String,
-    tx_bytes: u64,
-    rx_bytes: u64,
+    pub name: String,
+    /// Values of the counters, in the order of COUNTERS
+    pub counters: [Option<u64>; 8],
+}
+
+/// MAC address of the interface, empty if it has none
+pub fn read_address(roots: &FsRoots, name: &str) -> String {
+    let iface_dir = format!("{}/{}", roots.path(NET_IFACES_DIR), name);
+    utils::read_file(&format!("{}/address", iface_dir))
+        .unwrap_or_default().trim().to_string()
 }
 
-#[derive(Default)]
-pub struct NetInfo {
-    ifaces: Vec<IfaceInfo>,
+pub fn read_iface(roots: &FsRoots, name: &str) -> IfaceInfo {
+    let stats_dir = roots.path(&NET_STATISTICS.replace("@", name));
+    let mut counters = [None; 8];
+    for (value, counter) in counters.iter_mut().zip(COUNTERS.iter()) {
+        *value = utils::read_u64(&format!("{}/{}", stats_dir, counter)).ok();
+    }
+    IfaceInfo { name: name.to_string(), counters }
 }
 
-impl NetInfo {
-    fn read_net_info(&mut self) -> Result<(), io::Error> {
-        for entry in fs::read_dir(NET_IFACES_DIR)? {
-            let entry = entry?;
-            let mut iface_info = IfaceInfo::default();
-            let oss_path = entry.path();
-            let str_path = oss_path.to_str().unwrap();
-            let oss_fname = entry.file_name();
-            let str_fname = oss_fname.to_str().unwrap();
-            let addr = utils::open_and_read(&format!("{}/address", str_path));
-            let addr = addr.trim();
-            let tx_str = utils::open_and_read(&format!("{}/statistics/tx_bytes",
-                                                       str_path));
-            let tx_str = tx_str.trim();
-            let tx = match tx_str.parse::<u64>() {
-                Ok(val) => val>>10,
-                _ => 0
-            };
-            let rx_str = utils::open_and_read(&format!("{}/statistics/rx_bytes",
-                                                       str_path));
-            let rx_str = rx_str.trim();
-            let rx = match rx_str.parse::<u64>() {
-                Ok(val) => val>>10,
-                _ => 0
-            };
-            iface_info.name.push_str(str_fname);
-            iface_info.addr.push_str(addr);
-            iface_info.tx_bytes = tx;
-            iface_info.rx_bytes = rx;
-            self.ifaces.push(iface_info);
+pub fn read_net_info(roots: &FsRoots) -> Result<Vec<IfaceInfo>> {
+    let path = roots.path(NET_IFACES_DIR);
+    let entries = fs::read_dir(&path)
+        .map_err(|source| Error::Io { path: path.clone(), source })?;
+    let mut ifaces = Vec::new();
+    for entry in entries.flatten() {
+        if let Some(name) = entry.file_name().to_str() {
+            ifaces.push(read_iface(roots, name));
         }
-        Ok(())
     }
+    Ok(ifaces)
+}
+
+pub struct NetCollector {
+    roots: FsRoots,
 }
 
-impl SysInfo for NetInfo {
-    fn new() -> Self {
-        NetInfo::default()
+impl NetCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        NetCollector { roots }
     }
+}
 
-    fn read(&mut self) {
-        let _ = self.read_net_info();
+impl Collector for NetCollector {
+    fn name(&self) -> &'static str {
+        "network"
     }
 
-    fn display(&self) {
-        println!("|{:=^85}|", " NET INFO ");
-        let mut j = 0;
-        let end = self.ifaces.len();
-        for i in (0..end-1).step_by(2) {
-            let iface1 = &self.ifaces[i];
-            let iface2 = &self.ifaces[i+1];
-            print!("|{:42}|", format!(" {} ({})", iface1.name, iface1.addr));
-            println!("{:42}|", format!(" {} ({})", iface2.name, iface2.addr));
-            print!("|  tx: {:11} kB | rx: {:11} kB |", iface1.tx_bytes,
-                     iface1.rx_bytes);
-            println!("  tx: {:11} kB | rx: {:11} kB |", iface2.tx_bytes,
-                     iface2.rx_bytes);
-            j += 2;
-            if j < self.ifaces.len() - 1 {
-                println!("|{:-^85}|", "-");
+    fn collect(&mut self, systats: &mut SystatsData, now: Instant)
+        -> Result<()> {
+        let ifaces = read_net_info(&self.roots)?;
+        let spec = systats.spec.clone();
+        for iface in ifaces {
+            // The address is read once, when the interface appears
+            let netstat = systats.networks.entry(iface.name)
+                .or_insert_with_key(|name| NetworkStats {
+                    address: read_address(&self.roots, name),
+                    ..NetworkStats::new(&spec)
+                });
+            let mut buffers = [
+                &mut netstat.rx_bytes, &mut netstat.tx_bytes,
+                &mut netstat.rx_packets, &mut netstat.tx_packets,
+                &mut netstat.rx_errors, &mut netstat.tx_errors,
+                &mut netstat.rx_drops, &mut netstat.tx_drops,
+            ];
+            for (counter, value) in buffers.iter_mut().zip(iface.counters.iter()) {
+                if let Some(value) = *value {
+                    counter.push(value, now);
+                }
             }
         }
-        if end % 2 == 1 {
-            let iface = &self.ifaces[end-1];
-            println!("|{:42}|", format!(" {} ({})", iface.name, iface.addr));
-            println!("|  tx: {:11} kB | rx: {:11} kB |", iface.tx_bytes,
-                   iface.rx_bytes);
-        }
-
+        Ok(())
     }
 }
-
-
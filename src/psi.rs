@@ -0,0 +1,246 @@
+//! This module collects the pressure stall information (PSI) of the CPU,
+//! memory and I/O from /proc/pressure. Kernels built without PSI do not
+//! have these files, in which case the resources are marked unavailable
+
+use std::io::ErrorKind;
+use std::time::Instant;
+use crate::collector::{Collector, FsRoots};
+use crate::error::{Error, Result};
+use crate::counter::counter_delta;
+use crate::ringbuf::{BufferSpec, RestoreSeries, RingStatsBuffer, SampleSeries};
+use crate::systats::SystatsData;
+use crate::utils;
+
+
+pub static PRESSURE: &str = "/proc/pressure/@";
+
+pub const RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+/// One line of a pressure file, `total` being the stall time in
+/// microseconds
+#[derive(Default, Debug, PartialEq)]
+pub struct PressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: u64,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Pressure {
+    pub some: PressureLine,
+    /// Not reported for the CPU by older kernels
+    pub full: Option<PressureLine>,
+}
+
+fn parse_line(line: &str) -> Option<PressureLine> {
+    let mut pressure = PressureLine::default();
+    for field in line.split_whitespace().skip(1) {
+        let (key, value) = field.split_once('=')?;
+        match key {
+            "avg10" => pressure.avg10 = value.parse().ok()?,
+            "avg60" => pressure.avg60 = value.parse().ok()?,
+            "avg300" => pressure.avg300 = value.parse().ok()?,
+            "total" => pressure.total = value.parse().ok()?,
+            _ => (),
+        }
+    }
+    Some(pressure)
+}
+
+pub fn parse_pressure(content: &str) -> Option<Pressure> {
+    let mut some = None;
+    let mut full = None;
+    for line in content.lines() {
+        if line.starts_with("some ") {
+            some = parse_line(line);
+        } else if line.starts_with("full ") {
+            full = parse_line(line);
+        }
+    }
+    Some(Pressure { some: some?, full })
+}
+
+pub struct PressureLineStats {
+    pub avg10: RingStatsBuffer<f64>,
+    pub avg60: RingStatsBuffer<f64>,
+    pub avg300: RingStatsBuffer<f64>,
+    /// Microseconds stalled since the previous sample
+    pub stall_us: RingStatsBuffer<u64>,
+    last_total: Option<u64>,
+}
+
+impl PressureLineStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        PressureLineStats {
+            avg10: RingStatsBuffer::with_spec(spec),
+            avg60: RingStatsBuffer::with_spec(spec),
+            avg300: RingStatsBuffer::with_spec(spec),
+            stall_us: RingStatsBuffer::with_spec(spec),
+            last_total: None,
+        }
+    }
+
+    pub fn push(&mut self, line: PressureLine) {
+        self.avg10.push_back(line.avg10);
+        self.avg60.push_back(line.avg60);
+        self.avg300.push_back(line.avg300);
+        if let Some(last) = self.last_total {
+            self.stall_us.push_back(counter_delta(last, line.total));
+        }
+        self.last_total = Some(line.total);
+    }
+}
+
+pub struct PressureStats {
+    pub available: bool,
+    pub some: PressureLineStats,
+    pub full: PressureLineStats,
+}
+
+impl PressureStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        PressureStats {
+            available: false,
+            some: PressureLineStats::new(spec),
+            full: PressureLineStats::new(spec),
+        }
+    }
+
+    /// Lists the buffers along with the name of their metric
+    pub fn buffers(&self) -> [(&'static str, &dyn SampleSeries); 8] {
+        [
+            ("psi_some_avg10", &self.some.avg10),
+            ("psi_some_avg60", &self.some.avg60),
+            ("psi_some_avg300", &self.some.avg300),
+            ("psi_some_stall_us", &self.some.stall_us),
+            ("psi_full_avg10", &self.full.avg10),
+            ("psi_full_avg60", &self.full.avg60),
+            ("psi_full_avg300", &self.full.avg300),
+            ("psi_full_stall_us", &self.full.stall_us),
+        ]
+    }
+
+    /// Same as buffers, to restore them
+    pub fn buffers_mut(&mut self) -> [(&'static str, &mut dyn RestoreSeries); 8] {
+        [
+            ("psi_some_avg10", &mut self.some.avg10),
+            ("psi_some_avg60", &mut self.some.avg60),
+            ("psi_some_avg300", &mut self.some.avg300),
+            ("psi_some_stall_us", &mut self.some.stall_us),
+            ("psi_full_avg10", &mut self.full.avg10),
+            ("psi_full_avg60", &mut self.full.avg60),
+            ("psi_full_avg300", &mut self.full.avg300),
+            ("psi_full_stall_us", &mut self.full.stall_us),
+        ]
+    }
+
+    pub fn push(&mut self, pressure: Pressure) {
+        self.available = true;
+        self.some.push(pressure.some);
+        if let Some(full) = pressure.full {
+            self.full.push(full);
+        }
+    }
+}
+
+pub struct PsiStats {
+    pub cpu: PressureStats,
+    pub memory: PressureStats,
+    pub io: PressureStats,
+}
+
+impl PsiStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        PsiStats {
+            cpu: PressureStats::new(spec),
+            memory: PressureStats::new(spec),
+            io: PressureStats::new(spec),
+        }
+    }
+
+    /// Lists the resources along with their name
+    pub fn resources(&self) -> [(&'static str, &PressureStats); 3] {
+        [("cpu", &self.cpu), ("memory", &self.memory), ("io", &self.io)]
+    }
+
+    pub fn resources_mut(&mut self) -> [(&'static str, &mut PressureStats); 3] {
+        [("cpu", &mut self.cpu), ("memory", &mut self.memory),
+         ("io", &mut self.io)]
+    }
+
+    /// Reads the pressure of every resource. Those whose file could not
+    /// be read or parsed are marked as unavailable, the first error being
+    /// returned. Missing files are not errors, but kernels without PSI
+    pub fn read(&mut self, roots: &FsRoots) -> Result<()> {
+        let mut result = Ok(());
+        let stats = [&mut self.cpu, &mut self.memory, &mut self.io];
+        for (resource, stats) in RESOURCES.iter().zip(stats) {
+            let filename = roots.path(&PRESSURE.replace("@", resource));
+            match utils::parse_file(&filename, parse_pressure) {
+                Ok(pressure) => stats.push(pressure),
+                Err(Error::Io { ref source, .. })
+                    if source.kind() == ErrorKind::NotFound => {
+                    stats.available = false;
+                },
+                Err(err) => {
+                    stats.available = false;
+                    result = result.and(Err(err));
+                },
+            }
+        }
+        result
+    }
+}
+
+pub struct PsiCollector {
+    roots: FsRoots,
+}
+
+impl PsiCollector {
+    pub fn new(roots: FsRoots) -> Self {
+        PsiCollector { roots }
+    }
+}
+
+impl Collector for PsiCollector {
+    fn name(&self) -> &'static str {
+        "pressure"
+    }
+
+    fn collect(&mut self, systats: &mut SystatsData, _now: Instant)
+        -> Result<()> {
+        systats.pressure.read(&self.roots)
+    }
+}
+
+#[test]
+fn test_pressure() {
+    let content = "\
+some avg10=1.50 avg60=0.75 avg300=0.10 total=1000000\n\
+full avg10=0.50 avg60=0.25 avg300=0.00 total=400000\n";
+    let pressure = parse_pressure(content).unwrap();
+    assert_eq!(pressure.some, PressureLine { avg10: 1.5, avg60: 0.75,
+                                             avg300: 0.1, total: 1000000 });
+    assert_eq!(pressure.full.as_ref().unwrap().total, 400000);
+    let cpu_only = parse_pressure("some avg10=0.00 avg60=0.00 avg300=0.00 total=5\n");
+    assert!(cpu_only.unwrap().full.is_none());
+    assert!(parse_pressure("").is_none());
+    assert!(parse_pressure("some avg10=x\n").is_none());
+    let mut stats = PressureStats::new(&BufferSpec::new(4, false));
+    assert!(!stats.available);
+    stats.push(pressure);
+    stats.push(parse_pressure("\
+some avg10=2.00 avg60=1.00 avg300=0.20 total=1250000\n\
+full avg10=1.00 avg60=0.50 avg300=0.10 total=450000\n").unwrap());
+    assert!(stats.available);
+    assert_eq!(stats.some.avg10.get_last(), Some(2.));
+    assert_eq!(stats.some.stall_us.get_last(), Some(250000));
+    assert_eq!(stats.full.stall_us.get_last(), Some(50000));
+    // Unsupported without the pressure files
+    let mut psi = PsiStats::new(&BufferSpec::new(4, false));
+    psi.cpu.push(parse_pressure(content).unwrap());
+    let roots = FsRoots::new("/nonexistent/proc", "/nonexistent/sys");
+    assert!(psi.read(&roots).is_ok());
+    assert!(!psi.cpu.available);
+}
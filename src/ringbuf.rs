@@ -1,21 +1,237 @@
 use std::fmt::{Formatter, Debug, Display, Result as ResultFmt};
-use std::cmp::PartialOrd;
+use std::cmp::{Ordering, PartialOrd};
 use std::ops::{Div, AddAssign};
 use std::collections::VecDeque;
 use std::collections::vec_deque::Iter;
-use num_traits::{Num, NumCast};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use num_traits::{Num, NumCast, ToPrimitive};
 use std::iter::Sum;
+use serde::{Deserialize, Serialize};
 
 
+/// Type-erased, read-only access to the samples of a buffer
+pub trait SampleSeries {
+    fn len(&self) -> usize;
+    fn get_f64(&self, index: usize) -> Option<f64>;
+    /// Number of samples ever pushed
+    fn pushed(&self) -> u64;
+    /// Samples and running statistics, to be saved across restarts
+    fn state(&self) -> BufferState;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Restores a buffer saved by SampleSeries::state
+pub trait RestoreSeries {
+    /// Replays the last samples of `state`, each taken at the instant of
+    /// `times` it is aligned with by the last element, then restores the
+    /// running statistics. Nothing is restored if no sample is kept
+    fn restore(&mut self, state: &BufferState, times: &[Instant]);
+}
+
+/// Samples and running statistics of a buffer, as f64
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BufferState {
+    pub samples: Vec<f64>,
+    pub cur_max: f64,
+    pub cur_min: f64,
+    pub max: f64,
+    pub min: f64,
+    pub avg: f64,
+    pub ite: usize,
+    pub fresh: bool,
+}
+
+
+/// An aggregation window of `len` samples, spanning `span`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    pub name: Arc<str>,
+    pub span: Duration,
+    pub len: usize,
+}
+
+/// Shape of the buffers: the samples kept, whether max and min are reset
+/// upon a new time window, the windows aggregated besides it, the time
+/// constants of the moving averages and the interval between samples,
+/// zero if unknown
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BufferSpec {
+    pub capacity: usize,
+    pub rst: bool,
+    pub windows: Vec<Window>,
+    pub ewma: Vec<(Arc<str>, Duration)>,
+    pub interval: Duration,
+}
+
+impl BufferSpec {
+    pub fn new(capacity: usize, rst: bool) -> Self {
+        BufferSpec { capacity, rst, ..Default::default() }
+    }
+}
+
+/// Percentiles computed upon a new time window
+pub const PERCENTILES: [f64; 4] = [50., 90., 95., 99.];
+
+/// Distribution of the samples of the last time window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Distribution<T> {
+    /// Values of PERCENTILES, by nearest rank
+    pub percentiles: [T; 4],
+    pub stddev: f64,
+    /// Change per second from the first to the last sample, by the times
+    /// they were taken at, none if they were taken at once
+    pub rate: Option<f64>,
+}
+
+/// Exponentially weighted moving average with time constant `tau`. Each
+/// sample is weighted by the time elapsed since the previous one, so
+/// irregular spacing does not skew it
+#[derive(Clone, Debug)]
+pub struct Ewma {
+    pub name: Arc<str>,
+    pub tau: Duration,
+    value: Option<f64>,
+    last: Option<Instant>,
+}
+
+impl Ewma {
+    pub fn new(name: Arc<str>, tau: Duration) -> Self {
+        Ewma { name, tau, value: None, last: None }
+    }
+
+    pub fn update(&mut self, sample: f64, now: Instant) {
+        self.value = match (self.value, self.last) {
+            (Some(value), Some(last)) => {
+                let elapsed = now.saturating_duration_since(last).as_secs_f64();
+                let alpha = 1. - (-elapsed/self.tau.as_secs_f64()).exp();
+                Some(value + alpha*(sample - value))
+            },
+            _ => Some(sample),
+        };
+        self.last = Some(now);
+    }
+
+    /// Average so far, none before the first sample
+    pub fn get(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Converts to T, rounding to the nearest value for integer types
+fn from_f64<T>(value: f64) -> Option<T> where T: NumCast {
+    let cast: T = NumCast::from(value)?;
+    let rounded: T = NumCast::from(value.round())?;
+    let error = |v: &T| v.to_f64().map_or(f64::INFINITY, |v| (v - value).abs());
+    if error(&rounded) < error(&cast) { Some(rounded) } else { Some(cast) }
+}
+
+/// Buckets a window is split in, bounding its memory whatever its length
+pub const WINDOW_BUCKETS: usize = 60;
+
+/// Statistics of consecutive samples of a window
+#[derive(Clone, Copy, Debug)]
+struct Bucket<T> {
+    count: usize,
+    sum: f64,
+    max: T,
+    min: T,
+}
+
+/// Statistics of a sliding window, computed as the samples are pushed
+/// instead of from the buffer, so windows may be longer than it. The
+/// samples are summed up in buckets of a WINDOW_BUCKETS-th of the window,
+/// which slides by a bucket at a time. Until the window fills up, the
+/// statistics of the samples so far are reported
+pub struct WindowStats<T> {
+    pub window: Window,
+    /// Samples per bucket
+    size: usize,
+    /// Buckets of the last samples of the window, the last one being
+    /// filled, and their number of samples
+    buckets: VecDeque<Bucket<T>>,
+    count: usize,
+}
+
+impl<T> WindowStats<T> where T: PartialOrd + Copy + NumCast {
+    pub fn new(window: Window) -> Self {
+        let size = window.len.div_ceil(WINDOW_BUCKETS).max(1);
+        WindowStats {
+            window,
+            size,
+            buckets: VecDeque::with_capacity(WINDOW_BUCKETS + 1),
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        let value = item.to_f64().unwrap_or(0.);
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.count < self.size => {
+                if item > bucket.max { bucket.max = item }
+                if item < bucket.min { bucket.min = item }
+                bucket.sum += value;
+                bucket.count += 1;
+            },
+            _ => self.buckets.push_back(Bucket { count: 1, sum: value,
+                                                 max: item, min: item }),
+        }
+        self.count += 1;
+        // Slides once the other buckets hold the whole window
+        while let Some(oldest) = self.buckets.front() {
+            if self.count - oldest.count < self.window.len {
+                break;
+            }
+            self.count -= oldest.count;
+            let _ = self.buckets.pop_front();
+        }
+    }
+
+    fn fold(&self, pick: fn(&Bucket<T>, T) -> T) -> T {
+        match self.buckets.front() {
+            Some(first) => self.buckets.iter().fold(first.max, |acc, bucket| {
+                pick(bucket, acc)
+            }),
+            None => NumCast::from(0).unwrap(),
+        }
+    }
+
+    pub fn get_max(&self) -> T {
+        self.fold(|bucket, max| if bucket.max > max { bucket.max } else { max })
+    }
+
+    pub fn get_min(&self) -> T {
+        self.fold(|bucket, min| if bucket.min < min { bucket.min } else { min })
+    }
+
+    pub fn get_avg(&self) -> T {
+        let sum: f64 = self.buckets.iter().map(|bucket| bucket.sum).sum();
+        let avg = if self.count > 0 { sum/self.count as f64 } else { 0. };
+        from_f64(avg).unwrap_or_else(|| self.get_min())
+    }
+}
+
 pub struct RingStatsBuffer<T> {
     buff: VecDeque<T>,
+    /// When the samples of `buff` were taken
+    times: VecDeque<Instant>,
+    cap: usize,
     cur_max: T,
     cur_min: T,
     max: T,
     min: T,
     avg: T,
+    dist: Option<Distribution<T>>,
     ite: usize,
     rst: bool,
+    /// Whether max and min were reset, and are set by the next sample
+    fresh: bool,
+    windows: Vec<WindowStats<T>>,
+    ewma: Vec<Ewma>,
+    pushed: u64,
 }
 
 impl<T> RingStatsBuffer<T> 
@@ -24,56 +240,99 @@ where T: Default + PartialOrd + Copy + Num + NumCast + AddAssign + Sum
     pub fn new(capacity: usize, rst: bool) -> Self {
         RingStatsBuffer{
             buff: VecDeque::with_capacity(capacity),
-            avg: NumCast::from(u32::MIN).unwrap(),
-            cur_max: NumCast::from(u32::MIN).unwrap(),
-            cur_min: NumCast::from(u32::MAX).unwrap(),
+            times: VecDeque::with_capacity(capacity),
+            cap: capacity,
+            avg: NumCast::from(0).unwrap(),
+            cur_max: NumCast::from(0).unwrap(),
+            cur_min: NumCast::from(0).unwrap(),
             max: NumCast::from(0).unwrap(),
             min: NumCast::from(0).unwrap(),
+            dist: None,
             ite: 0,
-            rst: rst,
+            rst,
+            fresh: true,
+            windows: Vec::new(),
+            ewma: Vec::new(),
+            pushed: 0,
         }
     }
 
+    pub fn with_spec(spec: &BufferSpec) -> Self {
+        let mut buf = Self::new(spec.capacity, spec.rst);
+        buf.windows = spec.windows.iter().cloned().map(WindowStats::new)
+            .collect();
+        buf.ewma = spec.ewma.iter()
+            .map(|(name, tau)| Ewma::new(Arc::clone(name), *tau))
+            .collect();
+        buf
+    }
+
     pub fn len(&self) -> usize {
         self.buff.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.buff.is_empty()
+    }
+
+    /// Number of samples kept, the allocation may be larger
     pub fn capacity(&self) -> usize {
-        self.buff.capacity()
+        self.cap
     }
 
     pub fn has_reset_flag(&self) -> bool {
-        self.rst == true
+        self.rst
     }
 
-    pub fn iter(&self) -> Iter<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
         self.buff.iter()
     }
 
+    pub fn windows(&self) -> &[WindowStats<T>] {
+        &self.windows
+    }
+
+    pub fn ewma(&self) -> &[Ewma] {
+        &self.ewma
+    }
+
     pub fn push_back(&mut self, item: T) {
-        if self.buff.len() >= self.buff.capacity() {
-            let _ = self.buff.pop_front();
+        self.push_back_at(item, Instant::now());
+    }
+
+    /// Pushes a sample taken at `now`, as weighted by the moving averages
+    pub fn push_back_at(&mut self, item: T, now: Instant) {
+        if self.buff.len() >= self.cap {
+            let _ = self.pop_front();
         }
-        if item > self.cur_max { self.cur_max = item }
-        if item < self.cur_min { self.cur_min = item }
+        if self.fresh || item > self.cur_max { self.cur_max = item }
+        if self.fresh || item < self.cur_min { self.cur_min = item }
+        self.fresh = false;
         self.buff.push_back(item);
+        self.times.push_back(now);
+        for window in self.windows.iter_mut() {
+            window.push(item);
+        }
+        if let Some(value) = item.to_f64() {
+            for ewma in self.ewma.iter_mut() {
+                ewma.update(value, now);
+            }
+        }
+        self.pushed += 1;
         self.ite += 1;
-        if self.ite >= self.buff.capacity() {
+        if self.ite >= self.cap {
             self.calc_stats();
             self.ite = 0;
         }
     }
 
     pub fn pop_front(&mut self) -> Option<T> {
+        let _ = self.times.pop_front();
         self.buff.pop_front()
     }
 
     pub fn get_last(&self) -> Option<T> {
-        if self.buff.len() == 0 {
-            return None;
-        }
-        let val = self.buff.get(self.buff.len()-1);
-        val.copied()
+        self.buff.back().copied()
     }
 
     pub fn as_slices(&self) -> (&[T], &[T]) {
@@ -100,25 +359,115 @@ where T: Default + PartialOrd + Copy + Num + NumCast + AddAssign + Sum
         self.avg
     }
 
+    /// Percentiles, standard deviation and rate of the last time window,
+    /// none until it completes
+    pub fn get_distribution(&self) -> Option<Distribution<T>> {
+        self.dist
+    }
+
     pub fn calc_stats(&mut self) {
-        if self.buff.len() > 0 {
-            let sum: T = self.buff.iter().copied().sum();
-            self.avg = sum/NumCast::from(self.buff.len()).unwrap();
-            self.max = self.cur_max;
-            self.min = self.cur_min;
-            if self.rst {
-                self.reset_stats();
-            }
+        if self.buff.is_empty() {
+            return;
+        }
+        // Accumulated in f64, sums of large counters overflow in T
+        let len = self.buff.len() as f64;
+        let values: Vec<f64> = self.buff.iter()
+            .map(|val| val.to_f64().unwrap_or(0.))
+            .collect();
+        let mean = values.iter().sum::<f64>()/len;
+        let variance = values.iter()
+            .map(|val| (val - mean)*(val - mean))
+            .sum::<f64>()/len;
+        self.avg = from_f64(mean).unwrap_or(self.cur_min);
+        self.max = self.cur_max;
+        self.min = self.cur_min;
+        let mut sorted: Vec<T> = self.buff.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let mut percentiles = [sorted[0]; 4];
+        for (value, p) in percentiles.iter_mut().zip(PERCENTILES.iter()) {
+            let rank = (p/100.*len).ceil() as usize;
+            *value = sorted[rank.clamp(1, sorted.len()) - 1];
+        }
+        let elapsed = match (self.times.front(), self.times.back()) {
+            (Some(first), Some(last)) => (*last - *first).as_secs_f64(),
+            _ => 0.,
+        };
+        let rate = match (values.first(), values.last()) {
+            (Some(first), Some(last)) if elapsed > 0. => {
+                Some((last - first)/elapsed)
+            },
+            _ => None,
+        };
+        self.dist = Some(Distribution {
+            percentiles,
+            stddev: variance.sqrt(),
+            rate,
+        });
+        if self.rst {
+            self.reset_stats();
         }
     }
 
     fn reset_stats(&mut self) {
-        self.cur_min = NumCast::from(u32::MAX).unwrap();
-        self.cur_max = NumCast::from(u32::MIN).unwrap();
+        self.fresh = true;
     }
 
 }
 
+impl<T> SampleSeries for RingStatsBuffer<T> where T: ToPrimitive + Copy {
+    fn len(&self) -> usize {
+        self.buff.len()
+    }
+
+    fn get_f64(&self, index: usize) -> Option<f64> {
+        self.buff.get(index).and_then(|val| val.to_f64())
+    }
+
+    fn pushed(&self) -> u64 {
+        self.pushed
+    }
+
+    fn state(&self) -> BufferState {
+        let f64_of = |val: T| val.to_f64().unwrap_or(0.);
+        BufferState {
+            samples: self.buff.iter().map(|val| f64_of(*val)).collect(),
+            cur_max: f64_of(self.cur_max),
+            cur_min: f64_of(self.cur_min),
+            max: f64_of(self.max),
+            min: f64_of(self.min),
+            avg: f64_of(self.avg),
+            ite: self.ite,
+            fresh: self.fresh,
+        }
+    }
+}
+
+impl<T> RestoreSeries for RingStatsBuffer<T>
+where T: Default + PartialOrd + Copy + Num + NumCast + AddAssign + Sum
+{
+    fn restore(&mut self, state: &BufferState, times: &[Instant]) {
+        let kept = state.samples.len().min(times.len());
+        if kept == 0 {
+            return;
+        }
+        let samples = &state.samples[state.samples.len() - kept..];
+        for (value, now) in samples.iter().zip(&times[times.len() - kept..]) {
+            if let Some(item) = from_f64(*value) {
+                self.push_back_at(item, *now);
+            }
+        }
+        let restore = |value: f64, current: T| from_f64(value).unwrap_or(current);
+        self.cur_max = restore(state.cur_max, self.cur_max);
+        self.cur_min = restore(state.cur_min, self.cur_min);
+        self.max = restore(state.max, self.max);
+        self.min = restore(state.min, self.min);
+        self.avg = restore(state.avg, self.avg);
+        // The capacity may have changed since the state was saved
+        self.ite = if state.ite < self.cap { state.ite } else { 0 };
+        self.fresh = state.fresh;
+    }
+}
+
 impl<T> Debug for RingStatsBuffer<T> 
 where T: Debug + Display + Copy + PartialOrd + NumCast + Div<Output=T> {
     fn fmt(&self, f: &mut Formatter) -> ResultFmt {
@@ -164,5 +513,74 @@ fn test_ring_stats_buffer() {
     assert_eq!(sts.get_min(), 2);
     assert_eq!(sts.get_last_max(), 5);
     assert_eq!(sts.get_last_min(), 1);
+    // The capacity is kept even if the allocation is rounded up
+    let mut sts: RingStatsBuffer<u32> = RingStatsBuffer::new(5, false);
+    for val in 0..10 {
+        sts.push_back(val);
+    }
+    assert_eq!(sts.len(), 5);
+    assert_eq!(sts.get_avg(), 7);
+    // Windows longer than the buffer
+    let window = Window { name: "5s".into(), span: Duration::from_secs(5),
+                          len: 5 };
+    let spec = BufferSpec { capacity: 2, rst: false, windows: vec![window],
+                            ..Default::default() };
+    let mut sts: RingStatsBuffer<u32> = RingStatsBuffer::with_spec(&spec);
+    sts.push_back(4);
+    sts.push_back(2);
+    let stats = &sts.windows()[0];
+    assert_eq!((stats.get_min(), stats.get_max(), stats.get_avg()), (2, 4, 3));
+    for val in [10, 6, 8, 1] {
+        sts.push_back(val);
+    }
+    let stats = &sts.windows()[0];
+    assert_eq!(sts.len(), 2);
+    // Sliding over the last 5 samples
+    assert_eq!((stats.get_min(), stats.get_max(), stats.get_avg()), (1, 10, 5));
+    // Summed up in buckets, sliding by one of them
+    let window = Window { name: "2m".into(), span: Duration::from_secs(120),
+                          len: 120 };
+    let mut stats: WindowStats<u32> = WindowStats::new(window);
+    for val in 0..125 {
+        stats.push(val);
+    }
+    assert_eq!(stats.buckets.len(), WINDOW_BUCKETS + 1);
+    assert_eq!((stats.get_min(), stats.get_max(), stats.get_avg()),
+               (4, 124, 64));
+    // Distribution, with large values and the average rounded
+    let mut sts: RingStatsBuffer<u64> = RingStatsBuffer::new(5, false);
+    let start = Instant::now();
+    // Unevenly spaced, the rate is over the 8s between the first and last
+    for (val, secs) in [(10, 0), (20, 1), (31, 2), (40, 6), (1000, 8)] {
+        sts.push_back_at(5_000_000_000 + val,
+                         start + Duration::from_secs(secs));
+    }
+    assert_eq!(sts.get_min(), 5_000_000_010);
+    assert_eq!(sts.get_avg(), 5_000_000_220);
+    let dist = sts.get_distribution().unwrap();
+    assert_eq!(dist.percentiles, [5_000_000_031, 5_000_001_000,
+                                  5_000_001_000, 5_000_001_000]);
+    assert!((dist.stddev - 390.03).abs() < 0.01);
+    assert_eq!(dist.rate, Some(123.75));
+    let mut sts: RingStatsBuffer<u64> = RingStatsBuffer::new(2, false);
+    sts.push_back_at(u64::MAX - 1, start);
+    sts.push_back_at(u64::MAX - 1, start);
+    assert!(sts.get_avg() > u64::MAX - 4096);
+    assert_eq!(sts.get_distribution().unwrap().rate, None);
+    // Moving averages weighted by the time elapsed
+    let spec = BufferSpec { capacity: 4,
+                            ewma: vec![("10s".into(), Duration::from_secs(10))],
+                            ..Default::default() };
+    let mut sts: RingStatsBuffer<f64> = RingStatsBuffer::with_spec(&spec);
+    let start = Instant::now();
+    sts.push_back_at(0., start);
+    assert_eq!(sts.ewma()[0].get(), Some(0.));
+    sts.push_back_at(100., start + Duration::from_secs(10));
+    let value = sts.ewma()[0].get().unwrap();
+    assert!((value - 63.21).abs() < 0.01);
+    // Twice as long since the previous sample weights it more
+    sts.push_back_at(0., start + Duration::from_secs(30));
+    let value = sts.ewma()[0].get().unwrap();
+    assert!((value - 63.21*(-2f64).exp()).abs() < 0.01);
 }
 
@@ -0,0 +1,161 @@
+//! This module consolidates the samples into rollups of coarser
+//! resolutions, kept much longer than the raw samples as RRD does
+
+use std::collections::VecDeque;
+use std::collections::vec_deque::Iter;
+
+
+/// Resolution in milliseconds and number of rollups kept of each tier:
+/// 1 minute rollups for a day, then 15 minutes rollups for a week
+pub const TIERS: [(u64, usize); 2] = [
+    (60*1000, 24*60),
+    (15*60*1000, 7*24*4),
+];
+
+/// Statistics of the samples within a period starting at `start`, Unix
+/// time in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rollup {
+    pub start: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Rollup {
+    fn new(start: u64, value: f64) -> Self {
+        Rollup { start, min: value, max: value, sum: value, count: 1 }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum/self.count as f64
+    }
+}
+
+/// Rollups of a single resolution, the last one being still filled
+pub struct RollupTier {
+    pub resolution: u64,
+    capacity: usize,
+    rollups: VecDeque<Rollup>,
+}
+
+impl RollupTier {
+    pub fn new(resolution: u64, capacity: usize) -> Self {
+        RollupTier { resolution, capacity, rollups: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, ts: u64, value: f64) {
+        let start = ts - ts % self.resolution;
+        match self.rollups.back_mut() {
+            Some(rollup) if rollup.start == start => rollup.add(value),
+            _ => {
+                if self.rollups.len() >= self.capacity {
+                    let _ = self.rollups.pop_front();
+                }
+                self.rollups.push_back(Rollup::new(start, value));
+            }
+        }
+    }
+
+    /// Start of the oldest rollup kept
+    pub fn oldest(&self) -> Option<u64> {
+        self.rollups.front().map(|rollup| rollup.start)
+    }
+
+    pub fn iter(&self) -> Iter<'_, Rollup> {
+        self.rollups.iter()
+    }
+}
+
+/// Every tier of a series, along with the number of samples of its
+/// buffer already consolidated
+pub struct RollupSeries {
+    pub tiers: Vec<RollupTier>,
+    pushed: u64,
+}
+
+impl Default for RollupSeries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RollupSeries {
+    pub fn new() -> Self {
+        RollupSeries {
+            tiers: TIERS.iter()
+                .map(|(resolution, capacity)| RollupTier::new(*resolution,
+                                                              *capacity))
+                .collect(),
+            pushed: 0,
+        }
+    }
+
+    /// Consolidates the last sample of a buffer, unless no sample was
+    /// pushed to it since the previous call
+    pub fn update(&mut self, pushed: u64, ts: u64, value: Option<f64>) {
+        if pushed == self.pushed {
+            return;
+        }
+        self.pushed = pushed;
+        if let Some(value) = value.filter(|value| value.is_finite()) {
+            for tier in self.tiers.iter_mut() {
+                tier.push(ts, value);
+            }
+        }
+    }
+
+    /// Returns the finest tier still holding the samples since `from`. If
+    /// none does, the finest one holding every sample consolidated so far,
+    /// that is starting within the oldest rollup of the coarsest tier
+    pub fn tier_since(&self, from: u64) -> Option<&RollupTier> {
+        let coarsest = self.tiers.last()?;
+        let end = coarsest.oldest()? + coarsest.resolution;
+        self.tiers.iter()
+            .find(|tier| tier.oldest()
+                  .is_some_and(|oldest| oldest <= from || oldest < end))
+    }
+}
+
+#[test]
+fn test_rollup_series() {
+    let mut series = RollupSeries::new();
+    // 1 sample every 20 seconds, for 20 minutes
+    for i in 0..60u64 {
+        series.update(i + 1, i*20*1000, Some(i as f64));
+    }
+    // Nothing new pushed to the buffer
+    series.update(60, 1200*1000, Some(100.));
+    let minutes: Vec<&Rollup> = series.tiers[0].iter().collect();
+    assert_eq!(minutes.len(), 20);
+    assert_eq!(*minutes[1], Rollup { start: 60*1000, min: 3., max: 5., sum: 12.,
+                                     count: 3 });
+    assert_eq!(minutes[1].avg(), 4.);
+    let quarters: Vec<&Rollup> = series.tiers[1].iter().collect();
+    assert_eq!(quarters.len(), 2);
+    assert_eq!((quarters[0].min, quarters[0].max, quarters[0].count),
+               (0., 44., 45));
+    assert_eq!(series.tier_since(0).unwrap().resolution, 60*1000);
+    // Younger than `from`, still from the finest tier holding everything
+    let mut series = RollupSeries::new();
+    for i in 0..30u64 {
+        series.update(i + 1, (7*60 + i*20)*1000, Some(i as f64));
+    }
+    assert_eq!(series.tier_since(0).unwrap().resolution, 60*1000);
+    assert!(RollupSeries::new().tier_since(0).is_none());
+    // The oldest rollups are discarded
+    let mut tier = RollupTier::new(1000, 2);
+    for ts in [0, 1500, 2500, 2900] {
+        tier.push(ts, 1.);
+    }
+    assert_eq!(tier.oldest(), Some(1000));
+    assert_eq!(tier.iter().last().unwrap().count, 2);
+}
@@ -1,7 +1,13 @@
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, RwLock};
 use std::collections::HashMap;
-use num_traits::NumCast;
+use std::hash::Hash;
+use std::iter::Sum;
+use std::ops::AddAssign;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use num_traits::{Num, NumCast};
 use serde::{ser::{Serializer, SerializeStruct}, Serialize};
+use crate::ringbuf::RingStatsBuffer;
+use crate::psi::{PressureLineStats, PressureStats};
 use crate::systats::SystatsData;
 
 
@@ -13,11 +19,48 @@ pub trait SystatsPayloadGetter<T> {
     fn get_full_payload(&self) -> Option<T>;
 }
 
+/// Payloads whose statistics can be taken from one of the aggregation
+/// windows instead of the time window
+pub trait WindowSelect {
+    fn select_window(&mut self, name: &str);
+}
+
+/// Statistics of a metric over an aggregation window
+#[derive(Serialize, Clone, Copy)]
+pub struct WindowMetric<T> {
+    pub max: T,
+    pub min: T,
+    pub avg: T,
+}
+
+/// Statistics of a buffer of samples. A metric whose buffer has no
+/// sample yet, as one not supported by the system, is unavailable and
+/// serialized with `available` false and no statistics. The percentiles,
+/// standard deviation and rate are only present once a time window
+/// completed
 pub struct Metric<T> {
     pub max: T,
     pub min: T,
     pub avg: T,
-    pub last: T
+    pub last: T,
+    pub p50: Option<T>,
+    pub p90: Option<T>,
+    pub p95: Option<T>,
+    pub p99: Option<T>,
+    pub stddev: Option<f64>,
+    /// Change per second over the time window
+    pub rate: Option<f64>,
+    pub available: bool,
+    /// Statistics of the aggregation windows, in the configured order
+    pub windows: Vec<(Arc<str>, WindowMetric<T>)>,
+    /// Moving averages, keyed by their time constant
+    pub ewma: Vec<(Arc<str>, f64)>,
+}
+
+impl<T> Default for Metric<T> where T: NumCast {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> Metric<T> where T: NumCast {
@@ -27,39 +70,187 @@ impl<T> Metric<T> where T: NumCast {
             min: NumCast::from(0).unwrap(),
             avg: NumCast::from(0).unwrap(),
             last: NumCast::from(0).unwrap(),
+            p50: None,
+            p90: None,
+            p95: None,
+            p99: None,
+            stddev: None,
+            rate: None,
+            available: false,
+            windows: Vec::new(),
+            ewma: Vec::new(),
+        }
+    }
+}
+
+impl<T> Metric<T>
+where T: Default + PartialOrd + Copy + Num + NumCast + AddAssign + Sum
+{
+    fn update(&mut self, buf: &RingStatsBuffer<T>) {
+        self.available = !buf.is_empty();
+        self.max = buf.get_max();
+        self.min = buf.get_min();
+        self.avg = buf.get_avg();
+        if let Some(last) = buf.get_last() {
+            self.last = last;
+        }
+        if let Some(dist) = buf.get_distribution() {
+            let [p50, p90, p95, p99] = dist.percentiles;
+            self.p50 = Some(p50);
+            self.p90 = Some(p90);
+            self.p95 = Some(p95);
+            self.p99 = Some(p99);
+            self.stddev = Some(dist.stddev);
+            self.rate = dist.rate;
+        }
+        self.ewma.clear();
+        for ewma in buf.ewma() {
+            if let Some(value) = ewma.get() {
+                self.ewma.push((Arc::clone(&ewma.name), value));
+            }
+        }
+        if self.windows.len() != buf.windows().len() {
+            self.windows = buf.windows().iter()
+                .map(|stats| (Arc::clone(&stats.window.name), WindowMetric {
+                    max: stats.get_max(),
+                    min: stats.get_min(),
+                    avg: stats.get_avg(),
+                }))
+                .collect();
+            return;
+        }
+        for ((_, metric), stats) in self.windows.iter_mut().zip(buf.windows()) {
+            metric.max = stats.get_max();
+            metric.min = stats.get_min();
+            metric.avg = stats.get_avg();
+        }
+    }
+}
+
+impl<T> WindowSelect for Metric<T> where T: Copy {
+    fn select_window(&mut self, name: &str) {
+        if let Some((_, metric)) = self.windows.iter()
+            .find(|(window, _)| &**window == name) {
+            self.max = metric.max;
+            self.min = metric.min;
+            self.avg = metric.avg;
+        }
+    }
+}
+
+impl<K, V> WindowSelect for HashMap<K, V> where K: Eq + Hash, V: WindowSelect {
+    fn select_window(&mut self, name: &str) {
+        for value in self.values_mut() {
+            value.select_window(name);
         }
     }
 }
 
+/// Serializes pairs as a map keyed by their name, as the windows
+struct NamedMap<'a, T>(&'a [(Arc<str>, T)]);
+
+impl<'a, T> Serialize for NamedMap<'a, T> where T: Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(self.0.iter()
+                               .map(|(name, metric)| (&**name, metric)))
+    }
+}
+
 impl<T> Serialize for Metric<T> where T: Serialize {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut s = serializer.serialize_struct("Metric", 4)?;
+        let mut s = serializer.serialize_struct("Metric", 13)?;
+        s.serialize_field("available", &self.available)?;
+        if !self.available {
+            // Without the statistics, to not be taken for zeros
+            return s.end();
+        }
         s.serialize_field("max", &self.max)?;
         s.serialize_field("min", &self.min)?;
         s.serialize_field("avg", &self.avg)?;
         s.serialize_field("last", &self.last)?;
+        let percentiles = [("p50", &self.p50), ("p90", &self.p90),
+                           ("p95", &self.p95), ("p99", &self.p99)];
+        for (name, value) in percentiles {
+            match value {
+                Some(value) => s.serialize_field(name, value)?,
+                None => s.skip_field(name)?,
+            }
+        }
+        for (name, value) in [("stddev", &self.stddev), ("rate", &self.rate)] {
+            match value {
+                Some(value) => s.serialize_field(name, value)?,
+                None => s.skip_field(name)?,
+            }
+        }
+        s.serialize_field("windows", &NamedMap(&self.windows))?;
+        s.serialize_field("ewma", &NamedMap(&self.ewma))?;
         s.end()
     }
 }
 
-impl<T> Copy for Metric<T> where T: Copy {}
-
 impl<T> Clone for Metric<T> where T: Copy {
     fn clone(&self) -> Self {
-        *self
+        Metric {
+            max: self.max,
+            min: self.min,
+            avg: self.avg,
+            last: self.last,
+            p50: self.p50,
+            p90: self.p90,
+            p95: self.p95,
+            p99: self.p99,
+            stddev: self.stddev,
+            rate: self.rate,
+            available: self.available,
+            windows: self.windows.clone(),
+            ewma: self.ewma.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Core {
+    pub cpu_freq: Metric<u64>,
+    pub cpu_usage: Metric<f32>,
+    /// Empty when the core has no cpufreq driver
+    pub governor: String,
+    pub driver: String,
+}
+
+impl Default for Core {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Core {
+    pub fn new() -> Self {
+        Core {
+            cpu_freq: Metric::new(),
+            cpu_usage: Metric::new(),
+            governor: String::new(),
+            driver: String::new(),
+        }
     }
 }
 
 #[derive(Serialize, Clone)]
 pub struct Cpu {
     pub cpu_cores: usize,
+    pub model: String,
     pub cpu_freq: Metric<u64>,
     pub cpu_usage: Metric<f32>,
+    /// Logical CPUs, keyed by their id
+    pub cores: HashMap<usize, Core>,
 }
 
+/// Memory in kB, swapping activity in bytes per second
 #[derive(Serialize, Clone)]
 pub struct Mem {
     pub total_mem: u64,
@@ -67,19 +258,141 @@ pub struct Mem {
     pub mem_free: Metric<u64>,
     pub mem_used: Metric<u64>,
     pub mem_available: Metric<u64>,
+    /// Buffers and page cache together
     pub mem_buffer: Metric<u64>,
+    pub mem_buffers: Metric<u64>,
+    pub mem_cached: Metric<u64>,
+    pub mem_shared: Metric<u64>,
+    pub mem_slab: Metric<u64>,
+    pub mem_dirty: Metric<u64>,
+    pub mem_writeback: Metric<u64>,
+    pub swap_used: Metric<u64>,
+    pub swap_in: Metric<f64>,
+    pub swap_out: Metric<f64>,
+    pub hugepages_total: Metric<u64>,
+    pub hugepages_free: Metric<u64>,
+    pub hugepage_size: u64,
+}
+
+/// Load averages, run queue and scheduler activity, counters as rates
+/// per second
+#[derive(Serialize, Clone)]
+pub struct Load {
+    pub load1: Metric<f64>,
+    pub load5: Metric<f64>,
+    pub load15: Metric<f64>,
+    pub procs_running: Metric<u64>,
+    pub procs_blocked: Metric<u64>,
+    pub forks: Metric<f64>,
+    pub context_switches: Metric<f64>,
+    pub interrupts: Metric<f64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PressureLine {
+    pub avg10: Metric<f64>,
+    pub avg60: Metric<f64>,
+    pub avg300: Metric<f64>,
+    /// Microseconds stalled between samples
+    pub stall_us: Metric<u64>,
+}
+
+impl Default for PressureLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PressureLine {
+    pub fn new() -> Self {
+        PressureLine {
+            avg10: Metric::new(),
+            avg60: Metric::new(),
+            avg300: Metric::new(),
+            stall_us: Metric::new(),
+        }
+    }
+
+    fn update(&mut self, stats: &PressureLineStats) {
+        self.avg10.update(&stats.avg10);
+        self.avg60.update(&stats.avg60);
+        self.avg300.update(&stats.avg300);
+        self.stall_us.update(&stats.stall_us);
+    }
+}
+
+/// Pressure of a resource, only meaningful when `available`
+#[derive(Serialize, Clone)]
+pub struct Pressure {
+    pub available: bool,
+    pub some: PressureLine,
+    pub full: PressureLine,
+}
+
+impl Default for Pressure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pressure {
+    pub fn new() -> Self {
+        Pressure {
+            available: false,
+            some: PressureLine::new(),
+            full: PressureLine::new(),
+        }
+    }
+
+    fn update(&mut self, stats: &PressureStats) {
+        self.available = stats.available;
+        self.some.update(&stats.some);
+        self.full.update(&stats.full);
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Psi {
+    pub cpu: Pressure,
+    pub memory: Pressure,
+    pub io: Pressure,
 }
 
 #[derive(Serialize, Clone)]
 pub struct Info {
     pub uptime: u64,
+    /// Name of the operating system
     pub name: String,
+    pub hostname: String,
 }
 
 #[derive(Serialize, Clone)]
+/// Per-second rates of the interface counters, along with their totals
 pub struct Net {
-    rx_bytes: Metric<u64>,
-    tx_bytes: Metric<u64>
+    pub rx_bytes: Metric<f64>,
+    pub tx_bytes: Metric<f64>,
+    pub rx_packets: Metric<f64>,
+    pub tx_packets: Metric<f64>,
+    pub rx_errors: Metric<f64>,
+    pub tx_errors: Metric<f64>,
+    pub rx_drops: Metric<f64>,
+    pub tx_drops: Metric<f64>,
+    pub rx_bytes_total: u64,
+    pub tx_bytes_total: u64,
+    pub rx_packets_total: u64,
+    pub tx_packets_total: u64,
+    pub rx_errors_total: u64,
+    pub tx_errors_total: u64,
+    pub rx_drops_total: u64,
+    pub tx_drops_total: u64,
+    /// MAC address, empty if the interface has none
+    pub address: String,
+}
+
+impl Default for Net {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Net {
@@ -87,20 +400,95 @@ impl Net {
         Net {
             rx_bytes: Metric::new(),
             tx_bytes: Metric::new(),
+            rx_packets: Metric::new(),
+            tx_packets: Metric::new(),
+            rx_errors: Metric::new(),
+            tx_errors: Metric::new(),
+            rx_drops: Metric::new(),
+            tx_drops: Metric::new(),
+            rx_bytes_total: 0,
+            tx_bytes_total: 0,
+            rx_packets_total: 0,
+            tx_packets_total: 0,
+            rx_errors_total: 0,
+            tx_errors_total: 0,
+            rx_drops_total: 0,
+            tx_drops_total: 0,
+            address: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct DiskIo {
+    pub read_iops: Metric<f64>,
+    pub write_iops: Metric<f64>,
+    pub read_bytes: Metric<f64>,
+    pub write_bytes: Metric<f64>,
+    pub await_ms: Metric<f64>,
+    pub queue_depth: Metric<f64>,
+    pub utilization: Metric<f64>,
+}
+
+impl Default for DiskIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskIo {
+    pub fn new() -> Self {
+        DiskIo {
+            read_iops: Metric::new(),
+            write_iops: Metric::new(),
+            read_bytes: Metric::new(),
+            write_bytes: Metric::new(),
+            await_ms: Metric::new(),
+            queue_depth: Metric::new(),
+            utilization: Metric::new(),
         }
     }
 }
 
-type Disk = HashMap<String, Metric<u64>>;
-type Networks = HashMap<String, Net>;
+/// State of a collector, `failures` counting its consecutive failed
+/// collections
+#[derive(Serialize, Clone)]
+pub struct CollectorStatus {
+    pub ok: bool,
+    pub error: Option<String>,
+    pub failures: u64,
+    pub last_success: Option<u64>,
+}
+
+pub type Disk = HashMap<String, Metric<u64>>;
+pub type DisksIo = HashMap<String, DiskIo>;
+pub type Networks = HashMap<String, Net>;
+pub type Status = HashMap<String, CollectorStatus>;
 
 #[derive(Serialize, Clone)]
 pub struct SysinfoSchema {
     pub cpu: Cpu,
     pub mem: Mem,
     pub system: Info,
+    pub load: Load,
+    pub pressure: Psi,
     pub disks: Disk,
     pub networks: Networks,
+    pub diskio: DisksIo,
+    pub status: Status,
+    /// Names of the aggregation windows
+    #[serde(skip)]
+    pub windows: Vec<Arc<str>>,
+    /// Unix time of the last sample, in milliseconds, only sent by the
+    /// stream
+    #[serde(skip)]
+    pub timestamp: u64,
+}
+
+impl Default for SysinfoSchema {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SysinfoSchema {
@@ -108,8 +496,10 @@ impl SysinfoSchema {
         SysinfoSchema {
             cpu: Cpu {
                 cpu_cores: 0,
+                model: String::new(),
                 cpu_freq: Metric::new(),
                 cpu_usage: Metric::new(),
+                cores: HashMap::new(),
             },
             mem: Mem {
                 total_mem: 0,
@@ -118,26 +508,199 @@ impl SysinfoSchema {
                 mem_used: Metric::new(),
                 mem_available: Metric::new(),
                 mem_buffer: Metric::new(),
+                mem_buffers: Metric::new(),
+                mem_cached: Metric::new(),
+                mem_shared: Metric::new(),
+                mem_slab: Metric::new(),
+                mem_dirty: Metric::new(),
+                mem_writeback: Metric::new(),
+                swap_used: Metric::new(),
+                swap_in: Metric::new(),
+                swap_out: Metric::new(),
+                hugepages_total: Metric::new(),
+                hugepages_free: Metric::new(),
+                hugepage_size: 0,
             },
             system: Info {
                 uptime: 0,
                 name: String::new(),
+                hostname: String::new(),
+            },
+            load: Load {
+                load1: Metric::new(),
+                load5: Metric::new(),
+                load15: Metric::new(),
+                procs_running: Metric::new(),
+                procs_blocked: Metric::new(),
+                forks: Metric::new(),
+                context_switches: Metric::new(),
+                interrupts: Metric::new(),
+            },
+            pressure: Psi {
+                cpu: Pressure::new(),
+                memory: Pressure::new(),
+                io: Pressure::new(),
             },
             disks: HashMap::new(),
             networks: HashMap::new(),
+            diskio: HashMap::new(),
+            status: HashMap::new(),
+            windows: Vec::new(),
+            timestamp: 0,
+        }
+    }
+}
+
+impl WindowSelect for Core {
+    fn select_window(&mut self, name: &str) {
+        self.cpu_freq.select_window(name);
+        self.cpu_usage.select_window(name);
+    }
+}
+
+impl WindowSelect for Cpu {
+    fn select_window(&mut self, name: &str) {
+        self.cpu_freq.select_window(name);
+        self.cpu_usage.select_window(name);
+        self.cores.select_window(name);
+    }
+}
+
+impl WindowSelect for Mem {
+    fn select_window(&mut self, name: &str) {
+        for metric in [&mut self.mem_free, &mut self.mem_used,
+                       &mut self.mem_available, &mut self.mem_buffer,
+                       &mut self.mem_buffers, &mut self.mem_cached,
+                       &mut self.mem_shared, &mut self.mem_slab,
+                       &mut self.mem_dirty, &mut self.mem_writeback,
+                       &mut self.swap_used, &mut self.hugepages_total,
+                       &mut self.hugepages_free] {
+            metric.select_window(name);
+        }
+        self.swap_in.select_window(name);
+        self.swap_out.select_window(name);
+    }
+}
+
+impl WindowSelect for Load {
+    fn select_window(&mut self, name: &str) {
+        for metric in [&mut self.load1, &mut self.load5, &mut self.load15,
+                       &mut self.forks, &mut self.context_switches,
+                       &mut self.interrupts] {
+            metric.select_window(name);
+        }
+        self.procs_running.select_window(name);
+        self.procs_blocked.select_window(name);
+    }
+}
+
+impl WindowSelect for PressureLine {
+    fn select_window(&mut self, name: &str) {
+        self.avg10.select_window(name);
+        self.avg60.select_window(name);
+        self.avg300.select_window(name);
+        self.stall_us.select_window(name);
+    }
+}
+
+impl WindowSelect for Psi {
+    fn select_window(&mut self, name: &str) {
+        for pressure in [&mut self.cpu, &mut self.memory, &mut self.io] {
+            pressure.some.select_window(name);
+            pressure.full.select_window(name);
         }
     }
 }
 
+impl WindowSelect for Net {
+    fn select_window(&mut self, name: &str) {
+        for metric in [&mut self.rx_bytes, &mut self.tx_bytes,
+                       &mut self.rx_packets, &mut self.tx_packets,
+                       &mut self.rx_errors, &mut self.tx_errors,
+                       &mut self.rx_drops, &mut self.tx_drops] {
+            metric.select_window(name);
+        }
+    }
+}
+
+impl WindowSelect for DiskIo {
+    fn select_window(&mut self, name: &str) {
+        for metric in [&mut self.read_iops, &mut self.write_iops,
+                       &mut self.read_bytes, &mut self.write_bytes,
+                       &mut self.await_ms, &mut self.queue_depth,
+                       &mut self.utilization] {
+            metric.select_window(name);
+        }
+    }
+}
+
+/// System information and collector states have no statistics
+impl WindowSelect for Info {
+    fn select_window(&mut self, _name: &str) {}
+}
+
+impl WindowSelect for CollectorStatus {
+    fn select_window(&mut self, _name: &str) {}
+}
+
+impl WindowSelect for SysinfoSchema {
+    fn select_window(&mut self, name: &str) {
+        self.cpu.select_window(name);
+        self.mem.select_window(name);
+        self.load.select_window(name);
+        self.pressure.select_window(name);
+        self.disks.select_window(name);
+        self.networks.select_window(name);
+        self.diskio.select_window(name);
+    }
+}
+
+/// Number of schemas queued per subscriber before new ones are dropped
+const SUBSCRIBER_BUFFER: usize = 4;
+
 pub struct DefaultSchemaBuilder {
     schema_lock: RwLock<SysinfoSchema>,
     //schema: SysinfoSchema,
+    subscribers: Mutex<Vec<Sender<Arc<SysinfoSchema>>>>,
+}
+
+impl Default for DefaultSchemaBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DefaultSchemaBuilder {
     pub fn new() -> Self {
         DefaultSchemaBuilder {
             schema_lock: RwLock::new(SysinfoSchema::new()),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns a channel that receives a copy of the schema every time it
+    /// is built. A subscriber that does not keep up misses schemas instead
+    /// of blocking the builder
+    pub fn subscribe(&self) -> Receiver<Arc<SysinfoSchema>> {
+        let (tx, rx) = channel(SUBSCRIBER_BUFFER);
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    fn publish(&self, schema: &SysinfoSchema) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            if subscribers.is_empty() {
+                return;
+            }
+            let payload = Arc::new(schema.clone());
+            subscribers.retain_mut(|tx| {
+                match tx.try_send(Arc::clone(&payload)) {
+                    Ok(()) => true,
+                    Err(e) => !e.is_disconnected(),
+                }
+            });
         }
     }
 
@@ -157,6 +720,29 @@ impl DefaultSchemaBuilder {
         None
     }
 
+    pub fn get_cpu_core_payload(&self, id: usize) -> Option<Core> {
+        if let Ok(schema) = self.schema_lock.read() {
+            return schema.cpu.cores.get(&id).cloned();
+        }
+        None
+    }
+
+    pub fn get_load_payload(&self) -> Option<Load> {
+        if let Ok(schema) = self.schema_lock.read() {
+            let payload = schema.load.clone();
+            return Some(payload);
+        }
+        None
+    }
+
+    pub fn get_pressure_payload(&self) -> Option<Psi> {
+        if let Ok(schema) = self.schema_lock.read() {
+            let payload = schema.pressure.clone();
+            return Some(payload);
+        }
+        None
+    }
+
     pub fn get_mem_payload(&self) -> Option<Mem> {
         if let Ok(schema) = self.schema_lock.read() {
             let payload = schema.mem.clone();
@@ -173,6 +759,14 @@ impl DefaultSchemaBuilder {
         None
     }
 
+    pub fn get_diskio_payload(&self) -> Option<DisksIo> {
+        if let Ok(schema) = self.schema_lock.read() {
+            let payload = schema.diskio.clone();
+            return Some(payload);
+        }
+        None
+    }
+
     pub fn get_networks_payload(&self) -> Option<Networks> {
         if let Ok(schema) = self.schema_lock.read() {
             let payload = schema.networks.clone();
@@ -181,64 +775,136 @@ impl DefaultSchemaBuilder {
         None
     }
 
+    /// Whether `name` is one of the aggregation windows
+    pub fn has_window(&self, name: &str) -> bool {
+        if let Ok(schema) = self.schema_lock.read() {
+            return schema.windows.iter().any(|window| &**window == name);
+        }
+        false
+    }
+
+    pub fn get_status_payload(&self) -> Option<Status> {
+        if let Ok(schema) = self.schema_lock.read() {
+            let payload = schema.status.clone();
+            return Some(payload);
+        }
+        None
+    }
+
 }
 
 impl SystatsSchemaBuilder for DefaultSchemaBuilder {
     fn build(&self, stats: &SystatsData) {
         if let Ok(mut schema) = self.schema_lock.write() {
             schema.system.uptime = stats.uptime;
+            schema.timestamp = stats.timestamp.get_last().unwrap_or(0);
             if schema.system.name.is_empty() {
                 schema.system.name = stats.name.clone();
             }
+            if schema.system.hostname.is_empty() {
+                schema.system.hostname = stats.hostname.clone();
+            }
+            if schema.windows.len() != stats.spec.windows.len() {
+                schema.windows = stats.spec.windows.iter()
+                    .map(|window| Arc::clone(&window.name))
+                    .collect();
+            }
             schema.cpu.cpu_cores = stats.cpu_cores;
+            if schema.cpu.model != stats.cpu_model {
+                schema.cpu.model = stats.cpu_model.clone();
+            }
             schema.mem.total_mem = stats.total_mem;
             schema.mem.total_swap = stats.total_swap;
-            schema.cpu.cpu_freq.max = stats.cpu_freq.get_max();
-            schema.cpu.cpu_freq.min = stats.cpu_freq.get_min();
-            schema.cpu.cpu_freq.avg = stats.cpu_freq.get_avg();
-            schema.cpu.cpu_freq.last = stats.cpu_freq.get_last().unwrap();
-            schema.cpu.cpu_usage.max = stats.cpu_usage.get_max();
-            schema.cpu.cpu_usage.min = stats.cpu_usage.get_min();
-            schema.cpu.cpu_usage.avg = stats.cpu_usage.get_avg();
-            schema.cpu.cpu_usage.last = stats.cpu_usage.get_last().unwrap();
-            schema.mem.mem_free.max = stats.mem_free.get_max();
-            schema.mem.mem_free.min = stats.mem_free.get_min();
-            schema.mem.mem_free.avg = stats.mem_free.get_avg();
-            schema.mem.mem_free.last = stats.mem_free.get_last().unwrap();
-            schema.mem.mem_used.max = stats.mem_used.get_max();
-            schema.mem.mem_used.min = stats.mem_used.get_min();
-            schema.mem.mem_used.avg = stats.mem_used.get_avg();
-            schema.mem.mem_used.last = stats.mem_used.get_last().unwrap();
-            schema.mem.mem_available.max = stats.mem_available.get_max();
-            schema.mem.mem_available.min = stats.mem_available.get_min();
-            schema.mem.mem_available.avg = stats.mem_available.get_avg();
-            schema.mem.mem_available.last = stats.mem_available.get_last().unwrap();
-            for (label, diskdata) in stats.disk_usage.iter() {
-                if !schema.disks.contains_key(label) {
-                    schema.disks.insert(label.clone(), Metric::new());
+            schema.cpu.cpu_freq.update(&stats.cpu_freq);
+            schema.cpu.cpu_usage.update(&stats.cpu_usage);
+            for (id, coredata) in stats.cpus.iter() {
+                let coreschema = schema.cpu.cores.entry(*id)
+                    .or_insert_with(Core::new);
+                coreschema.cpu_freq.update(&coredata.freq);
+                coreschema.cpu_usage.update(&coredata.usage);
+                if coreschema.governor != coredata.governor {
+                    coreschema.governor = coredata.governor.clone();
                 }
-                if let Some(diskschema) = schema.disks.get_mut(label) {
-                    diskschema.max = diskdata.get_max();
-                    diskschema.min = diskdata.get_min();
-                    diskschema.avg = diskdata.get_avg();
-                    diskschema.last = diskdata.get_last().unwrap();
+                if coreschema.driver != coredata.driver {
+                    coreschema.driver = coredata.driver.clone();
                 }
             }
+            schema.mem.mem_free.update(&stats.mem_free);
+            schema.mem.mem_used.update(&stats.mem_used);
+            schema.mem.mem_available.update(&stats.mem_available);
+            let memory = &stats.memory;
+            schema.mem.mem_buffer.update(&memory.buff_cache);
+            schema.mem.mem_buffers.update(&memory.buffers);
+            schema.mem.mem_cached.update(&memory.cached);
+            schema.mem.mem_shared.update(&memory.shared);
+            schema.mem.mem_slab.update(&memory.slab);
+            schema.mem.mem_dirty.update(&memory.dirty);
+            schema.mem.mem_writeback.update(&memory.writeback);
+            schema.mem.swap_used.update(&memory.swap_used);
+            schema.mem.swap_in.update(&memory.swap_in.rate);
+            schema.mem.swap_out.update(&memory.swap_out.rate);
+            schema.mem.hugepages_total.update(&memory.hugepages_total);
+            schema.mem.hugepages_free.update(&memory.hugepages_free);
+            schema.mem.hugepage_size = memory.hugepage_size;
+            schema.load.load1.update(&stats.load.load1);
+            schema.load.load5.update(&stats.load.load5);
+            schema.load.load15.update(&stats.load.load15);
+            schema.load.procs_running.update(&stats.load.procs_running);
+            schema.load.procs_blocked.update(&stats.load.procs_blocked);
+            schema.load.forks.update(&stats.load.forks.rate);
+            schema.load.context_switches.update(&stats.load.context_switches.rate);
+            schema.load.interrupts.update(&stats.load.interrupts.rate);
+            schema.pressure.cpu.update(&stats.pressure.cpu);
+            schema.pressure.memory.update(&stats.pressure.memory);
+            schema.pressure.io.update(&stats.pressure.io);
+            for (label, diskdata) in stats.disk_usage.iter() {
+                let diskschema = schema.disks.entry(label.clone())
+                    .or_insert_with(Metric::new);
+                diskschema.update(diskdata);
+            }
             for (label, netdata) in stats.networks.iter() {
-                if !schema.networks.contains_key(label) {
-                    schema.networks.insert(label.clone(), Net::new());
-                }
-                if let Some(netschema) = schema.networks.get_mut(label) {
-                    netschema.rx_bytes.max = netdata.rx_bytes.get_max();
-                    netschema.rx_bytes.min = netdata.rx_bytes.get_min();
-                    netschema.rx_bytes.avg = netdata.rx_bytes.get_avg();
-                    netschema.rx_bytes.last = netdata.rx_bytes.get_last().unwrap();
-                    netschema.tx_bytes.max = netdata.tx_bytes.get_max();
-                    netschema.tx_bytes.min = netdata.tx_bytes.get_min();
-                    netschema.tx_bytes.avg = netdata.tx_bytes.get_avg();
-                    netschema.tx_bytes.last = netdata.tx_bytes.get_last().unwrap();
+                let netschema = schema.networks.entry(label.clone())
+                    .or_insert_with(Net::new);
+                netschema.rx_bytes.update(&netdata.rx_bytes.rate);
+                netschema.tx_bytes.update(&netdata.tx_bytes.rate);
+                netschema.rx_packets.update(&netdata.rx_packets.rate);
+                netschema.tx_packets.update(&netdata.tx_packets.rate);
+                netschema.rx_errors.update(&netdata.rx_errors.rate);
+                netschema.tx_errors.update(&netdata.tx_errors.rate);
+                netschema.rx_drops.update(&netdata.rx_drops.rate);
+                netschema.tx_drops.update(&netdata.tx_drops.rate);
+                netschema.rx_bytes_total = netdata.rx_bytes.total;
+                netschema.tx_bytes_total = netdata.tx_bytes.total;
+                netschema.rx_packets_total = netdata.rx_packets.total;
+                netschema.tx_packets_total = netdata.tx_packets.total;
+                netschema.rx_errors_total = netdata.rx_errors.total;
+                netschema.tx_errors_total = netdata.tx_errors.total;
+                netschema.rx_drops_total = netdata.rx_drops.total;
+                netschema.tx_drops_total = netdata.tx_drops.total;
+                if netschema.address != netdata.address {
+                    netschema.address = netdata.address.clone();
                 }
             }
+            for (label, iodata) in stats.diskio.iter() {
+                let ioschema = schema.diskio.entry(label.clone())
+                    .or_insert_with(DiskIo::new);
+                ioschema.read_iops.update(&iodata.read_iops);
+                ioschema.write_iops.update(&iodata.write_iops);
+                ioschema.read_bytes.update(&iodata.read_bytes);
+                ioschema.write_bytes.update(&iodata.write_bytes);
+                ioschema.await_ms.update(&iodata.await_ms);
+                ioschema.queue_depth.update(&iodata.queue_depth);
+                ioschema.utilization.update(&iodata.utilization);
+            }
+            for (name, state) in stats.collectors.iter() {
+                schema.status.insert(name.to_string(), CollectorStatus {
+                    ok: state.error.is_none(),
+                    error: state.error.clone(),
+                    failures: state.failures,
+                    last_success: state.last_success,
+                });
+            }
+            self.publish(&schema);
         }
     }
 }
@@ -0,0 +1,226 @@
+//! This module defines the sinks, exporting the samples to other systems
+//! as they are collected. The executor takes the new samples out of the
+//! buffers after each read and hands them to every sink, each running on
+//! its own thread
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::{spawn, JoinHandle};
+use crate::systats::SystatsData;
+
+
+/// Batches queued for a sink still pushing the previous ones
+const QUEUE_LEN: usize = 64;
+
+
+/// Last sample of a series, labeled by disk or interface if any
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub metric: &'static str,
+    pub label: Option<String>,
+    /// What the label names, such as disk or interface, empty without one
+    pub label_name: &'static str,
+    pub value: f64,
+}
+
+/// Samples collected at `ts`, Unix time in milliseconds, on the host
+/// named `host`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    pub ts: u64,
+    pub host: String,
+    pub samples: Vec<Sample>,
+}
+
+/// Samples ever pushed to each buffer, to take only the new ones
+#[derive(Default)]
+pub struct SampleTracker {
+    pushed: HashMap<(&'static str, Option<String>), u64>,
+}
+
+impl SampleTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the last sample of every buffer pushed to since the previous
+    /// call. Non-finite samples are skipped
+    pub fn take(&mut self, systats: &SystatsData, ts: u64) -> Batch {
+        let mut samples = Vec::new();
+        for s in systats.series() {
+            let pushed = s.samples.pushed();
+            let last = s.samples.len().checked_sub(1)
+                .and_then(|index| s.samples.get_f64(index));
+            let key = (s.metric, s.label);
+            if self.pushed.insert(key.clone(), pushed) == Some(pushed) {
+                continue;
+            }
+            if let Some(value) = last.filter(|value| value.is_finite()) {
+                samples.push(Sample { metric: key.0, label: key.1,
+                                      label_name: s.label_name, value });
+            }
+        }
+        Batch { ts, host: systats.hostname.clone(), samples }
+    }
+
+    /// Skips the samples already in the buffers, as restored from a state
+    /// file
+    pub fn skip(&mut self, systats: &SystatsData) {
+        for s in systats.series() {
+            self.pushed.insert((s.metric, s.label), s.samples.pushed());
+        }
+    }
+}
+
+/// Packs the lines in payloads of at most `max` bytes, each line ending
+/// with a newline, unless a single line is longer
+pub fn pack(lines: &[String], max: usize) -> Vec<String> {
+    let mut payloads: Vec<String> = Vec::new();
+    let mut payload = String::new();
+    for line in lines {
+        if !payload.is_empty() && payload.len() + line.len() + 1 > max {
+            payloads.push(std::mem::take(&mut payload));
+        }
+        payload.push_str(line);
+        payload.push('\n');
+    }
+    if !payload.is_empty() {
+        payloads.push(payload);
+    }
+    payloads
+}
+
+/// Replaces the characters other than letters, digits, dashes and
+/// underscores, which would split a metric name in a path, such as those of
+/// the disk names
+pub fn sanitize(value: &str) -> String {
+    let value = value.trim_start_matches('/');
+    value.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c }
+             else { '_' })
+        .collect()
+}
+
+pub trait Sink: Send {
+    /// Name of the sink, as reported in its errors
+    fn name(&self) -> &'static str;
+
+    /// Exports the samples, possibly buffering them until a later call
+    fn push(&mut self, batch: &Batch) -> io::Result<()>;
+
+    /// Exports the samples still buffered, as on exit
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Runs a sink on its own thread, fed through a channel, so that a slow
+/// or unreachable endpoint does not delay the sampling
+pub struct SinkWorker {
+    name: &'static str,
+    sender: SyncSender<Batch>,
+    handle: JoinHandle<()>,
+    /// Batches dropped in a row while the queue was full
+    dropped: u64,
+}
+
+impl SinkWorker {
+    pub fn spawn(mut sink: Box<dyn Sink>) -> Self {
+        let name = sink.name();
+        let (sender, receiver) = sync_channel::<Batch>(QUEUE_LEN);
+        let handle = spawn(move || {
+            let mut failures = 0;
+            for batch in receiver {
+                match sink.push(&batch) {
+                    Ok(()) => failures = 0,
+                    Err(err) => {
+                        if failures == 0 {
+                            eprintln!("Sink {} failed: {}", name, err);
+                        }
+                        failures += 1;
+                    },
+                }
+            }
+            if let Err(err) = sink.flush() {
+                eprintln!("Sink {} failed: {}", name, err);
+            }
+        });
+        SinkWorker { name, sender, handle, dropped: 0 }
+    }
+
+    /// Queues the batch, dropped if the sink is too far behind
+    pub fn push(&mut self, batch: Batch) {
+        match self.sender.try_send(batch) {
+            Ok(()) => self.dropped = 0,
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    eprintln!("Sink {} is behind, dropping samples", self.name);
+                }
+                self.dropped += 1;
+            },
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// Waits for the queued batches to be pushed, then for the sink to be
+    /// flushed
+    pub fn stop(self) {
+        drop(self.sender);
+        let _ = self.handle.join();
+    }
+}
+
+#[test]
+fn test_sample_tracker() {
+    use crate::ringbuf::BufferSpec;
+    let mut systats = SystatsData::new(&BufferSpec::new(4, false));
+    systats.name = "Linux".to_string();
+    systats.hostname = "host".to_string();
+    systats.build_dynamic_values(0, &vec!["sda1"], &vec![]);
+    let mut tracker = SampleTracker::new();
+    systats.cpu_usage.push_back(10.);
+    systats.disk_usage.get_mut("sda1").unwrap().push_back(300);
+    tracker.skip(&systats);
+    systats.cpu_usage.push_back(20.);
+    let batch = tracker.take(&systats, 1000);
+    assert_eq!(batch, Batch {
+        ts: 1000,
+        host: "host".to_string(),
+        samples: vec![Sample { metric: "cpu_usage", label: None,
+                               label_name: "", value: 20. }],
+    });
+    systats.cpu_usage.push_back(f32::NAN);
+    systats.disk_usage.get_mut("sda1").unwrap().push_back(400);
+    let batch = tracker.take(&systats, 2000);
+    assert_eq!(batch.samples, vec![Sample { metric: "disk_usage",
+                                            label: Some("sda1".to_string()),
+                                            label_name: "disk",
+                                            value: 400. }]);
+    assert!(tracker.take(&systats, 3000).samples.is_empty());
+}
+
+#[test]
+fn test_sink_worker() {
+    use std::sync::{Arc, Mutex};
+    struct Recorder(Arc<Mutex<Vec<u64>>>);
+    impl Sink for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+        fn push(&mut self, batch: &Batch) -> io::Result<()> {
+            self.0.lock().unwrap().push(batch.ts);
+            Ok(())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push(0);
+            Ok(())
+        }
+    }
+    let pushed = Arc::new(Mutex::new(Vec::new()));
+    let mut worker = SinkWorker::spawn(Box::new(Recorder(Arc::clone(&pushed))));
+    for ts in [1000, 2000] {
+        worker.push(Batch { ts, host: "host".to_string(), samples: Vec::new() });
+    }
+    // Pushed in order, then flushed on stop
+    worker.stop();
+    assert_eq!(*pushed.lock().unwrap(), vec![1000, 2000, 0]);
+}
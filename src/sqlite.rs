@@ -0,0 +1,217 @@
+//! This module implements a sink writing every sample to a SQLite
+//! database, to be queried with SQL. Samples are inserted in batches, each
+//! in a transaction also deleting those older than the retention
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use crate::sink::{Batch, Sink};
+
+
+/// Milliseconds of samples kept in memory before being inserted
+pub const BATCH_SPAN: u64 = 10*1000;
+/// Samples kept in memory before being inserted, whatever their span
+pub const BATCH_SAMPLES: usize = 10000;
+/// Samples kept while the database fails, the oldest being dropped first
+pub const MAX_PENDING: usize = 100_000;
+
+/// Series are stored once and referenced by the samples, the `metrics`
+/// view joining both. Timestamps are Unix time in milliseconds
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS series (
+        id INTEGER PRIMARY KEY,
+        metric TEXT NOT NULL,
+        label TEXT
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS series_key
+        ON series (metric, ifnull(label, ''));
+    CREATE TABLE IF NOT EXISTS samples (
+        series_id INTEGER NOT NULL REFERENCES series (id),
+        ts INTEGER NOT NULL,
+        value REAL NOT NULL,
+        PRIMARY KEY (series_id, ts)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS samples_ts ON samples (ts);
+    CREATE VIEW IF NOT EXISTS metrics AS
+        SELECT samples.ts, series.metric, series.label, samples.value
+        FROM samples JOIN series ON series.id = samples.series_id;
+";
+
+type SeriesKey = (&'static str, Option<String>);
+
+pub struct SqliteSink {
+    conn: Connection,
+    retention: Duration,
+    /// Ids of the series already stored
+    ids: HashMap<SeriesKey, i64>,
+    pending: Vec<(u64, SeriesKey, f64)>,
+}
+
+fn sql_error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+impl SqliteSink {
+    /// Opens the database at `path`, created along with its schema if
+    /// needed
+    pub fn open(path: &Path, retention: Duration) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteSink {
+            conn,
+            retention,
+            ids: HashMap::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Id of the series, those stored by the transaction being added to
+    /// `new` as they are only known once it commits
+    fn series_id(tx: &Transaction, ids: &HashMap<SeriesKey, i64>,
+                 new: &mut HashMap<SeriesKey, i64>,
+                 key: &SeriesKey) -> rusqlite::Result<i64> {
+        if let Some(id) = ids.get(key).or_else(|| new.get(key)) {
+            return Ok(*id);
+        }
+        let (metric, label) = key;
+        let found = tx.query_row(
+            "SELECT id FROM series WHERE metric = ?1 AND label IS ?2",
+            params![metric, label], |row| row.get(0)).optional()?;
+        let id = match found {
+            Some(id) => id,
+            None => {
+                tx.execute("INSERT INTO series (metric, label) VALUES (?1, ?2)",
+                           params![metric, label])?;
+                tx.last_insert_rowid()
+            },
+        };
+        new.insert(key.clone(), id);
+        Ok(id)
+    }
+
+    /// Inserts the pending samples and deletes the expired ones in a single
+    /// transaction
+    fn write(&mut self) -> rusqlite::Result<()> {
+        let last = match self.pending.last() {
+            Some((ts, _, _)) => *ts,
+            None => return Ok(()),
+        };
+        let mut new = HashMap::new();
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO samples (series_id, ts, value) \
+                 VALUES (?1, ?2, ?3)")?;
+            for (ts, key, value) in self.pending.iter() {
+                let id = Self::series_id(&tx, &self.ids, &mut new, key)?;
+                insert.execute(params![id, *ts as i64, value])?;
+            }
+        }
+        let oldest = last.saturating_sub(self.retention.as_millis() as u64);
+        tx.execute("DELETE FROM samples WHERE ts < ?1", params![oldest as i64])?;
+        tx.commit()?;
+        self.ids.extend(new);
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Sink for SqliteSink {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn push(&mut self, batch: &Batch) -> io::Result<()> {
+        for sample in batch.samples.iter() {
+            self.pending.push((batch.ts, (sample.metric, sample.label.clone()),
+                               sample.value));
+        }
+        if self.pending.len() > MAX_PENDING {
+            let excess = self.pending.len() - MAX_PENDING;
+            self.pending.drain(..excess);
+        }
+        let first = match self.pending.first() {
+            Some((first, _, _)) => *first,
+            None => return Ok(()),
+        };
+        if batch.ts.saturating_sub(first) >= BATCH_SPAN ||
+            self.pending.len() >= BATCH_SAMPLES {
+            self.write().map_err(sql_error)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write().map_err(sql_error)
+    }
+}
+
+#[test]
+fn test_sqlite_sink() {
+    use crate::sink::Sample;
+    let path = std::env::temp_dir()
+        .join(format!("sysinfo-{}.sqlite", std::process::id()));
+    let mut sink = SqliteSink::open(&path, Duration::from_secs(60)).unwrap();
+    let sample = |metric, label: Option<&str>, value| Sample {
+        metric, label: label.map(|label| label.to_string()),
+        label_name: if label.is_some() { "disk" } else { "" }, value,
+    };
+    for i in 0..20u64 {
+        let batch = Batch {
+            ts: i*5000,
+            host: "host".to_string(),
+            samples: vec![sample("cpu_usage", None, i as f64),
+                          sample("disk_usage", Some("sda1"), 100.)],
+        };
+        sink.push(&batch).unwrap();
+    }
+    let count = |sink: &SqliteSink, sql: &str| -> i64 {
+        sink.conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    };
+    // Written by batches spanning 10 seconds, the samples older than a
+    // minute before the last one being deleted
+    assert_eq!(count(&sink, "SELECT max(ts) FROM samples"), 85000);
+    assert_eq!(count(&sink, "SELECT min(ts) FROM samples"), 25000);
+    sink.flush().unwrap();
+    assert_eq!(count(&sink, "SELECT max(ts) FROM samples"), 95000);
+    assert_eq!(count(&sink, "SELECT count(*) FROM samples"), 26);
+    assert_eq!(count(&sink, "SELECT count(*) FROM series"), 2);
+    // Reopened, the series are found again
+    drop(sink);
+    let mut sink = SqliteSink::open(&path, Duration::from_secs(60)).unwrap();
+    sink.push(&Batch {
+        ts: 100000,
+        host: "host".to_string(),
+        samples: vec![sample("disk_usage", Some("sda1"), 200.)],
+    }).unwrap();
+    sink.flush().unwrap();
+    assert_eq!(count(&sink, "SELECT count(*) FROM series"), 2);
+    assert_eq!(count(&sink, "SELECT CAST(value AS INTEGER) FROM metrics \
+                             WHERE label = 'sda1' ORDER BY ts DESC"), 200);
+    // Kept up to MAX_PENDING while the database fails
+    sink.conn.execute_batch("DROP VIEW metrics; DROP TABLE samples").unwrap();
+    // The id of a series stored by a failed write is not kept
+    sink.pending.push((110000, ("mem_usage", None), 1.));
+    assert!(sink.flush().is_err());
+    assert!(!sink.ids.contains_key(&("mem_usage", None)));
+    assert_eq!(count(&sink, "SELECT count(*) FROM series"), 2);
+    sink.pending.clear();
+    let batch = Batch {
+        ts: 110000,
+        host: "host".to_string(),
+        samples: (0..MAX_PENDING + 10)
+            .map(|i| sample("cpu_usage", None, i as f64))
+            .collect(),
+    };
+    assert!(sink.push(&batch).is_err());
+    assert_eq!(sink.pending.len(), MAX_PENDING);
+    assert_eq!(sink.pending[0].2, 10.);
+    drop(sink);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
@@ -0,0 +1,343 @@
+//! This module saves the sample buffers to a state file, so that their
+//! history and running statistics survive a restart
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::ringbuf::{BufferState, RingStatsBuffer, SampleSeries};
+use crate::diskio::DiskIoStats;
+use crate::systats::{CpuCore, NetworkStats, SeriesTimes, SystatsData};
+
+
+/// Bumped when the format of the state file changes
+pub const STATE_VERSION: u32 = 1;
+
+// Serializes the saves, done by both the executor and the signal handler
+static SAVING: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SavedSeries {
+    pub metric: String,
+    pub label: Option<String>,
+    /// Unix time of the samples, in milliseconds. Missing from older
+    /// files, the samples being aligned with `timestamps` instead
+    #[serde(default)]
+    pub times: Vec<u64>,
+    #[serde(flatten)]
+    pub state: BufferState,
+}
+
+/// Disks, interfaces and CPUs tracked when the state was saved
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct SavedEntries {
+    pub cpus: Vec<usize>,
+    pub disks: Vec<String>,
+    pub networks: Vec<String>,
+    pub diskio: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SavedState {
+    pub version: u32,
+    /// Unix time of each sample, in milliseconds
+    pub timestamps: Vec<u64>,
+    pub entries: SavedEntries,
+    pub series: Vec<SavedSeries>,
+}
+
+type SavedKey<'a> = (&'a str, Option<&'a str>);
+
+/// An entry of the maps of SystatsData
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    Cpu(usize),
+    Disk(String),
+    Network(String),
+    DiskIo(String),
+}
+
+/// Entries created by restore, along with the samples pushed to them,
+/// dropped by prune unless a collector pushed new ones since
+#[derive(Default, Debug)]
+pub struct Restored {
+    entries: Vec<(Entry, u64)>,
+}
+
+impl Entry {
+    /// Samples ever pushed to the buffers of the entry, none if it is not
+    /// tracked
+    fn pushed(&self, systats: &SystatsData) -> Option<u64> {
+        match self {
+            Entry::Cpu(id) => systats.cpus.get(id)
+                .map(|core| core.usage.pushed() + core.freq.pushed()),
+            Entry::Disk(name) => systats.disk_usage.get(name)
+                .map(|buf| buf.pushed()),
+            Entry::Network(name) => systats.networks.get(name)
+                .map(|netstat| netstat.counters().iter()
+                     .map(|(_, counter)| counter.rate.pushed()).sum()),
+            Entry::DiskIo(name) => systats.diskio.get(name)
+                .map(|iostat| iostat.buffers().iter()
+                     .map(|(_, buf)| buf.pushed()).sum()),
+        }
+    }
+
+    /// Starts tracking the entry, returning whether it was not already
+    fn insert(&self, systats: &mut SystatsData) -> bool {
+        if self.pushed(systats).is_some() {
+            return false;
+        }
+        let spec = systats.spec.clone();
+        match self {
+            Entry::Cpu(id) => {
+                systats.cpus.insert(*id, CpuCore::new(&spec));
+            },
+            Entry::Disk(name) => {
+                systats.disk_usage.insert(name.clone(),
+                                          RingStatsBuffer::with_spec(&spec));
+            },
+            Entry::Network(name) => {
+                systats.networks.insert(name.clone(), NetworkStats::new(&spec));
+            },
+            Entry::DiskIo(name) => {
+                systats.diskio.insert(name.clone(), DiskIoStats::new(&spec));
+            },
+        }
+        true
+    }
+
+    fn remove(&self, systats: &mut SystatsData) {
+        match self {
+            Entry::Cpu(id) => { systats.cpus.remove(id); },
+            Entry::Disk(name) => { systats.disk_usage.remove(name); },
+            Entry::Network(name) => { systats.networks.remove(name); },
+            Entry::DiskIo(name) => { systats.diskio.remove(name); },
+        }
+    }
+}
+
+impl SavedEntries {
+    fn list(&self) -> Vec<Entry> {
+        let cpus = self.cpus.iter().map(|id| Entry::Cpu(*id));
+        let disks = self.disks.iter().cloned().map(Entry::Disk);
+        let networks = self.networks.iter().cloned().map(Entry::Network);
+        let diskio = self.diskio.iter().cloned().map(Entry::DiskIo);
+        cpus.chain(disks).chain(networks).chain(diskio).collect()
+    }
+}
+
+pub fn snapshot(systats: &SystatsData) -> SavedState {
+    SavedState {
+        version: STATE_VERSION,
+        timestamps: systats.timestamp.iter().copied().collect(),
+        entries: SavedEntries {
+            cpus: systats.cpus.keys().copied().collect(),
+            disks: systats.disk_usage.keys().cloned().collect(),
+            networks: systats.networks.keys().cloned().collect(),
+            diskio: systats.diskio.keys().cloned().collect(),
+        },
+        series: systats.series().into_iter()
+            .map(|s| {
+                let times = systats.times.get(&(s.metric, s.label.clone()))
+                    .map(|times| times.iter().copied().collect())
+                    .unwrap_or_default();
+                SavedSeries {
+                    metric: s.metric.to_string(),
+                    label: s.label,
+                    times,
+                    state: s.samples.state(),
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Writes the state to a temporary file first, synced then renamed over
+/// `path` so that a crash never leaves a truncated state behind. The
+/// directory is synced as well for the rename to be durable
+pub fn save(state: &SavedState, path: &Path) -> io::Result<()> {
+    let _saving = SAVING.lock().unwrap_or_else(|err| err.into_inner());
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(state)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Saves the shared buffers, only read locked while taking the snapshot
+pub fn save_shared(systats: &RwLock<SystatsData>, path: &Path) {
+    let state = match systats.read() {
+        Ok(systats) => snapshot(&systats),
+        Err(_) => return,
+    };
+    if let Err(err) = save(&state, path) {
+        eprintln!("Failed to save the state to {}: {}", path.display(), err);
+    }
+}
+
+pub fn load(path: &Path) -> Result<SavedState> {
+    let display = path.display().to_string();
+    let content = fs::read(path)
+        .map_err(|source| Error::Io { path: display.clone(), source })?;
+    serde_json::from_slice::<SavedState>(&content).ok()
+        .filter(|state| state.version == STATE_VERSION)
+        .ok_or(Error::Parse { path: display })
+}
+
+/// Restores the samples taken less than the time window before `now_ms`,
+/// the Unix time in milliseconds at `now`. The entries saved and no
+/// longer tracked are created, to be pruned if they disappeared
+pub fn restore(systats: &mut SystatsData, state: &SavedState, now_ms: u64,
+               now: Instant) -> Restored {
+    let window = systats.spec.interval.as_millis() as u64*
+        systats.spec.capacity as u64;
+    let oldest = if window > 0 { now_ms.saturating_sub(window) } else { 0 };
+    let recent = |times: &[u64]| -> Vec<u64> {
+        times.iter().copied().filter(|ts| *ts >= oldest).collect()
+    };
+    let kept = recent(&state.timestamps);
+    let mut restored = Restored::default();
+    if kept.is_empty() {
+        return restored;
+    }
+    for ts in kept.iter() {
+        systats.timestamp.push_back(*ts);
+    }
+    for entry in state.entries.list() {
+        if entry.insert(systats) {
+            restored.entries.push((entry, 0));
+        }
+    }
+    // Times of the samples of each series, those of the samples of older
+    // files being the last timestamps
+    let saved: HashMap<SavedKey, (&BufferState, Vec<u64>)> = state.series.iter()
+        .map(|s| {
+            let times = if s.times.is_empty() { kept.clone() }
+                        else { recent(&s.times) };
+            ((s.metric.as_str(), s.label.as_deref()), (&s.state, times))
+        })
+        .collect();
+    for s in systats.series_mut() {
+        if let Some((buf, times)) = saved.get(&(s.metric, s.label.as_deref())) {
+            let instants: Vec<Instant> = times.iter()
+                .map(|ts| {
+                    let age = Duration::from_millis(now_ms.saturating_sub(*ts));
+                    now.checked_sub(age).unwrap_or(now)
+                })
+                .collect();
+            s.samples.restore(buf, &instants);
+        }
+    }
+    // Stamps and consolidates the samples restored, as if they were just
+    // pushed
+    let series: Vec<_> = systats.series().into_iter()
+        .map(|s| {
+            let times = saved.get(&(s.metric, s.label.as_deref()))
+                .map(|(_, times)| times.as_slice())
+                .unwrap_or(&[]);
+            let len = s.samples.len().min(times.len());
+            let skip = s.samples.len() - len;
+            let samples: Vec<(u64, Option<f64>)> = (0..len)
+                .map(|index| (times[times.len() - len + index],
+                              s.samples.get_f64(skip + index)))
+                .collect();
+            ((s.metric, s.label), s.samples.pushed(), samples)
+        })
+        .collect();
+    for (key, pushed, samples) in series {
+        let times: Vec<u64> = samples.iter().map(|(ts, _)| *ts).collect();
+        systats.times.insert(key.clone(), SeriesTimes::with_times(pushed,
+                                                                  &times));
+        let rollups = systats.rollups.entry(key).or_default();
+        for (pushed, (ts, value)) in samples.into_iter().enumerate() {
+            rollups.update(pushed as u64 + 1, ts, value);
+        }
+    }
+    for (entry, pushed) in restored.entries.iter_mut() {
+        *pushed = entry.pushed(systats).unwrap_or(0);
+    }
+    restored
+}
+
+/// Drops the entries restored to which no sample was pushed since, as the
+/// disk or interface disappeared
+pub fn prune(systats: &mut SystatsData, restored: Restored) {
+    let mut pruned = false;
+    for (entry, pushed) in restored.entries {
+        if entry.pushed(systats) == Some(pushed) {
+            entry.remove(systats);
+            pruned = true;
+        }
+    }
+    if pruned {
+        let keys: Vec<_> = systats.series().into_iter()
+            .map(|s| (s.metric, s.label))
+            .collect();
+        systats.rollups.retain(|key, _| keys.contains(key));
+        systats.times.retain(|key, _| keys.contains(key));
+    }
+}
+
+#[test]
+fn test_restore() {
+    use crate::ringbuf::BufferSpec;
+    let spec = BufferSpec { capacity: 4, interval: Duration::from_secs(1),
+                            ..Default::default() };
+    let mut systats = SystatsData::new(&spec);
+    systats.build_dynamic_values(1, &vec!["/dev/sda1", "/dev/sdb1"],
+                                 &vec!["eth0"]);
+    for (ts, used) in [(1000, 10u64), (2000, 20), (3000, 30), (4000, 40),
+                       (5000, 50)] {
+        systats.timestamp.push_back(ts);
+        systats.cpu_usage.push_back(used as f32);
+        // sda1 missed the fourth sample
+        if ts != 4000 {
+            systats.disk_usage.get_mut("/dev/sda1").unwrap().push_back(used);
+        }
+        systats.disk_usage.get_mut("/dev/sdb1").unwrap().push_back(used*2);
+        systats.push_times(ts);
+    }
+    let saved = snapshot(&systats);
+    let path = std::env::temp_dir()
+        .join(format!("sysinfo-state-{}.json", std::process::id()));
+    save(&saved, &path).unwrap();
+    let state = load(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    assert_eq!(state, saved);
+    // Restarted 2.5 seconds after the last sample, with a window of 4
+    // seconds, only sdb1 is still there
+    let mut systats = SystatsData::new(&spec);
+    systats.build_dynamic_values(1, &vec!["/dev/sdb1"], &vec![]);
+    let restored = restore(&mut systats, &state, 7500, Instant::now());
+    assert_eq!(systats.timestamp.iter().copied().collect::<Vec<_>>(),
+               vec![4000, 5000]);
+    let samples: Vec<u64> = systats.disk_usage["/dev/sda1"].iter().copied()
+        .collect();
+    assert_eq!(samples, vec![50]);
+    let times = &systats.times[&("disk_usage", Some("/dev/sda1".to_string()))];
+    assert_eq!(times.iter().copied().collect::<Vec<_>>(), vec![5000]);
+    assert_eq!(systats.cpu_usage.get_last(), Some(50.));
+    // Running stats of the 4 samples before the restart
+    assert_eq!(systats.disk_usage["/dev/sdb1"].get_avg(), 50);
+    assert_eq!(systats.disk_usage["/dev/sdb1"].get_max(), 100);
+    assert!(systats.networks.contains_key("eth0"));
+    assert!(systats.rollups.contains_key(&("disk_usage",
+                                           Some("/dev/sda1".to_string()))));
+    systats.disk_usage.get_mut("/dev/sdb1").unwrap().push_back(120);
+    prune(&mut systats, restored);
+    assert!(!systats.disk_usage.contains_key("/dev/sda1"));
+    assert!(!systats.networks.contains_key("eth0"));
+    assert!(systats.disk_usage.contains_key("/dev/sdb1"));
+    assert!(!systats.rollups.contains_key(&("disk_usage",
+                                            Some("/dev/sda1".to_string()))));
+}
@@ -0,0 +1,205 @@
+//! This module implements a sink pushing the samples as gauges to a StatsD
+//! or DogStatsD agent over UDP on every tick, packed in datagrams fitting
+//! the MTU
+
+use std::fmt;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use crate::sink::{self, Batch, Sample, Sink};
+
+
+/// Payload of the datagrams by default, as advised by DogStatsD for a
+/// 1500 bytes MTU
+pub const DEFAULT_MTU: usize = 1432;
+
+/// Format of the gauges sent
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum StatsdFormat {
+    /// Plain StatsD, the labels being part of the metric names
+    #[default]
+    Statsd,
+    /// DogStatsD, the host and labels being sent as tags
+    Dogstatsd,
+}
+
+impl FromStr for StatsdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "statsd" => Ok(StatsdFormat::Statsd),
+            "dogstatsd" => Ok(StatsdFormat::Dogstatsd),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for StatsdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StatsdFormat::Statsd => write!(f, "statsd"),
+            StatsdFormat::Dogstatsd => write!(f, "dogstatsd"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsdConfig {
+    /// Address of the agent, as HOST:PORT
+    pub addr: String,
+    pub format: StatsdFormat,
+    /// Prepended to the metric names, followed by a dot
+    pub prefix: Option<String>,
+    /// Tags added to every gauge, as KEY:VALUE, only sent by DogStatsD
+    pub tags: Vec<String>,
+    /// Largest payload of the datagrams
+    pub mtu: usize,
+}
+
+/// Replaces the characters DogStatsD reserves in tags
+fn sanitize_tag(value: &str) -> String {
+    value.chars()
+        .map(|c| if matches!(c, ',' | '|' | '#' | '\n') { '_' } else { c })
+        .collect()
+}
+
+pub struct StatsdSink {
+    config: StatsdConfig,
+    /// Connected on the first push, dropped after a failure to resolve the
+    /// agent again
+    socket: Option<UdpSocket>,
+}
+
+impl StatsdSink {
+    pub fn new(config: StatsdConfig) -> Self {
+        StatsdSink { config, socket: None }
+    }
+
+    fn name(&self, sample: &Sample) -> String {
+        let mut name = String::new();
+        if let Some(prefix) = &self.config.prefix {
+            name.push_str(prefix);
+            name.push('.');
+        }
+        name.push_str(sample.metric);
+        if self.config.format == StatsdFormat::Statsd {
+            if let Some(label) = &sample.label {
+                name.push('.');
+                name.push_str(&sink::sanitize(label));
+            }
+        }
+        name
+    }
+
+    /// Serializes the samples of the batch as gauges, one line each
+    pub fn encode(&self, batch: &Batch) -> Vec<String> {
+        let mut lines = Vec::with_capacity(batch.samples.len());
+        for sample in batch.samples.iter() {
+            let name = self.name(sample);
+            match self.config.format {
+                StatsdFormat::Statsd => {
+                    // A signed value would change the gauge instead
+                    if sample.value < 0. {
+                        lines.push(format!("{}:0|g", name));
+                    }
+                    lines.push(format!("{}:{}|g", name, sample.value));
+                },
+                StatsdFormat::Dogstatsd => {
+                    let mut tags = self.config.tags.clone();
+                    if !batch.host.is_empty() {
+                        tags.push(format!("host:{}", sanitize_tag(&batch.host)));
+                    }
+                    if let Some(label) = &sample.label {
+                        tags.push(format!("{}:{}", sample.label_name,
+                                          sanitize_tag(label)));
+                    }
+                    let mut line = format!("{}:{}|g", name, sample.value);
+                    if !tags.is_empty() {
+                        line.push_str("|#");
+                        line.push_str(&tags.join(","));
+                    }
+                    lines.push(line);
+                },
+            }
+        }
+        lines
+    }
+
+    fn socket(&mut self) -> io::Result<&UdpSocket> {
+        if self.socket.is_none() {
+            let addr = self.config.addr.to_socket_addrs()?.next()
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no address for {}", self.config.addr)))?;
+            let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(addr)?;
+            self.socket = Some(socket);
+        }
+        Ok(self.socket.as_ref().unwrap())
+    }
+}
+
+impl Sink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    /// Sends the gauges right away, those of a failed push being lost as
+    /// StatsD only keeps the last value anyway
+    fn push(&mut self, batch: &Batch) -> io::Result<()> {
+        let payloads = sink::pack(&self.encode(batch), self.config.mtu);
+        for payload in payloads {
+            let sent = self.socket()
+                .and_then(|socket| socket.send(payload.as_bytes()));
+            if let Err(err) = sent {
+                self.socket = None;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_statsd_sink() {
+    use std::time::Duration;
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut config = StatsdConfig {
+        addr: listener.local_addr().unwrap().to_string(),
+        format: StatsdFormat::Statsd,
+        prefix: Some("sysinfo".to_string()),
+        tags: vec!["env:prod".to_string()],
+        mtu: 100,
+    };
+    let disk = |value| Sample { metric: "disk_usage",
+                                label: Some("/dev/sda1".to_string()),
+                                label_name: "disk", value };
+    let batch = Batch { ts: 1000, host: "my|host".to_string(),
+                        samples: vec![disk(12.5), disk(-1.)] };
+    let sink = StatsdSink::new(config.clone());
+    assert_eq!(sink.encode(&batch), vec![
+        "sysinfo.disk_usage.dev_sda1:12.5|g",
+        "sysinfo.disk_usage.dev_sda1:0|g",
+        "sysinfo.disk_usage.dev_sda1:-1|g",
+    ]);
+    config.format = StatsdFormat::Dogstatsd;
+    let mut sink = StatsdSink::new(config);
+    let lines = sink.encode(&batch);
+    assert_eq!(lines[0],
+               "sysinfo.disk_usage:12.5|g|#env:prod,host:my_host,disk:/dev/sda1");
+    // Packed in datagrams of at most 100 bytes, at line boundaries
+    sink.push(&batch).unwrap();
+    let mut buf = [0u8; 2048];
+    for line in lines {
+        let len = listener.recv(&mut buf).unwrap();
+        assert!(len <= 100);
+        assert_eq!(&buf[..len], format!("{}\n", line).as_bytes());
+    }
+}
@@ -1,23 +1,154 @@
 use std::io;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 use std::marker::{Send, Sync};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::collections::vec_deque::Iter;
 use std::time::{Duration, SystemTime, Instant, UNIX_EPOCH};
 use std::thread::{sleep, spawn, JoinHandle};
-use sysinfo::{ProcessorExt, System, SystemExt, DiskExt, NetworkExt, NetworksExt};
-use crate::ringbuf::RingStatsBuffer;
+use crate::ringbuf::{BufferSpec, RestoreSeries, RingStatsBuffer, SampleSeries};
+use crate::collector::{Collector, CollectorState};
+use crate::error::Error;
+use crate::counter::CounterBuffer;
+use crate::diskio::{DiskIoStats, DiskstatsEntry};
+use crate::load::LoadStats;
+use crate::mem::MemStats;
+use crate::psi::PsiStats;
+use crate::rollup::RollupSeries;
 use crate::schema::SystatsSchemaBuilder;
+use crate::state::{self, Restored};
+use crate::tsdb::{Tsdb, TsdbSink};
+use crate::sink::{SampleTracker, Sink, SinkWorker};
 
 
-pub struct NetworkBytes {
-    pub rx_bytes: RingStatsBuffer<u64>,
-    pub tx_bytes: RingStatsBuffer<u64>
+pub struct CpuCore {
+    pub usage: RingStatsBuffer<f32>,
+    pub freq: RingStatsBuffer<u64>,
+    /// Scaling governor and driver, read from sysfs
+    pub governor: String,
+    pub driver: String,
+}
+
+impl CpuCore {
+    pub fn new(spec: &BufferSpec) -> Self {
+        CpuCore {
+            usage: RingStatsBuffer::with_spec(spec),
+            freq: RingStatsBuffer::with_spec(spec),
+            governor: String::new(),
+            driver: String::new(),
+        }
+    }
+}
+
+/// Per-second rates of the counters of a network interface
+pub struct NetworkStats {
+    pub rx_bytes: CounterBuffer,
+    pub tx_bytes: CounterBuffer,
+    pub rx_packets: CounterBuffer,
+    pub tx_packets: CounterBuffer,
+    pub rx_errors: CounterBuffer,
+    pub tx_errors: CounterBuffer,
+    pub rx_drops: CounterBuffer,
+    pub tx_drops: CounterBuffer,
+    /// MAC address, read from sysfs
+    pub address: String,
+}
+
+impl NetworkStats {
+    pub fn new(spec: &BufferSpec) -> Self {
+        NetworkStats {
+            rx_bytes: CounterBuffer::new(spec),
+            tx_bytes: CounterBuffer::new(spec),
+            rx_packets: CounterBuffer::new(spec),
+            tx_packets: CounterBuffer::new(spec),
+            rx_errors: CounterBuffer::new(spec),
+            tx_errors: CounterBuffer::new(spec),
+            rx_drops: CounterBuffer::new(spec),
+            tx_drops: CounterBuffer::new(spec),
+            address: String::new(),
+        }
+    }
+
+    /// Lists the counters along with the name of their metric
+    pub fn counters(&self) -> [(&'static str, &CounterBuffer); 8] {
+        [
+            ("rx_bytes", &self.rx_bytes),
+            ("tx_bytes", &self.tx_bytes),
+            ("rx_packets", &self.rx_packets),
+            ("tx_packets", &self.tx_packets),
+            ("rx_errors", &self.rx_errors),
+            ("tx_errors", &self.tx_errors),
+            ("rx_drops", &self.rx_drops),
+            ("tx_drops", &self.tx_drops),
+        ]
+    }
+
+    /// Same as counters, to restore them
+    pub fn counters_mut(&mut self) -> [(&'static str, &mut CounterBuffer); 8] {
+        [
+            ("rx_bytes", &mut self.rx_bytes),
+            ("tx_bytes", &mut self.tx_bytes),
+            ("rx_packets", &mut self.rx_packets),
+            ("tx_packets", &mut self.tx_packets),
+            ("rx_errors", &mut self.rx_errors),
+            ("tx_errors", &mut self.tx_errors),
+            ("rx_drops", &mut self.rx_drops),
+            ("tx_drops", &mut self.tx_drops),
+        ]
+    }
+}
+
+/// Unix time of the samples of a buffer, in milliseconds, along with the
+/// samples ever pushed to it when last stamped
+#[derive(Default, Debug)]
+pub struct SeriesTimes {
+    pushed: u64,
+    times: VecDeque<u64>,
+}
+
+impl SeriesTimes {
+    /// Times of samples already in a buffer, such as restored ones
+    pub fn with_times(pushed: u64, times: &[u64]) -> Self {
+        SeriesTimes { pushed, times: times.iter().copied().collect() }
+    }
+
+    /// Stamps with `ts` the samples pushed since the previous call, keeping
+    /// as many times as the buffer holds samples
+    pub fn update(&mut self, pushed: u64, len: usize, ts: u64) {
+        let new = pushed.saturating_sub(self.pushed).min(len as u64);
+        self.pushed = pushed;
+        for _ in 0..new {
+            self.times.push_back(ts);
+        }
+        while self.times.len() > len {
+            let _ = self.times.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<u64> {
+        self.times.get(index).copied()
+    }
+
+    pub fn iter(&self) -> Iter<'_, u64> {
+        self.times.iter()
+    }
 }
 
 pub struct SystatsData {
     pub name: String,
+    /// Network name of the host, tagging the exported samples
+    pub hostname: String,
     pub uptime: u64,
     pub cpu_cores: usize,
+    pub cpu_model: String,
     pub total_mem: u64,
     pub total_swap: u64,
     pub cpu_usage: RingStatsBuffer<f32>,
@@ -25,171 +156,456 @@ pub struct SystatsData {
     pub mem_free: RingStatsBuffer<u64>,
     pub mem_used: RingStatsBuffer<u64>,
     pub mem_available: RingStatsBuffer<u64>,
+    /// Breakdown of the RAM and swap usage from /proc/meminfo
+    pub memory: MemStats,
+    /// Logical CPUs, keyed by their id
+    pub cpus: HashMap<usize, CpuCore>,
     pub disk_usage: HashMap<String, RingStatsBuffer<u64>>,
-    pub networks: HashMap<String, NetworkBytes>,
+    pub networks: HashMap<String, NetworkStats>,
+    /// Block devices, keyed by their name in /proc/diskstats
+    pub diskio: HashMap<String, DiskIoStats>,
+    pub load: LoadStats,
+    pub pressure: PsiStats,
+    /// State of the collectors, keyed by their name
+    pub collectors: HashMap<&'static str, CollectorState>,
+    /// Unix time of each sample, in milliseconds
     pub timestamp: RingStatsBuffer<u64>,
+    /// Unix time of the samples of every series, keyed by its metric and
+    /// label, as a buffer may have missed some
+    pub times: HashMap<(&'static str, Option<String>), SeriesTimes>,
+    /// Shape of the buffers of the disks and interfaces found later
+    pub spec: BufferSpec,
+    /// Rollups of every series, keyed by its metric and label
+    pub rollups: HashMap<(&'static str, Option<String>), RollupSeries>,
+}
+
+/// A named buffer of samples, optionally labeled by disk or interface
+pub struct SeriesRef<'a> {
+    pub metric: &'static str,
+    pub label: Option<String>,
+    /// What the label names, such as disk or interface, empty without one
+    pub label_name: &'static str,
+    pub samples: &'a dyn SampleSeries,
+}
+
+/// Same as SeriesRef, to restore the buffer
+pub struct SeriesMut<'a> {
+    pub metric: &'static str,
+    pub label: Option<String>,
+    pub samples: &'a mut dyn RestoreSeries,
 }
 
 impl SystatsData {
-    pub fn new(capacity: usize, rst_flag: bool) -> Self {
+    pub fn new(spec: &BufferSpec) -> Self {
         SystatsData {
             name: String::new(),
+            hostname: String::new(),
             uptime: 0,
             cpu_cores: 0,
+            cpu_model: String::new(),
             total_mem: 0,
             total_swap: 0,
-            cpu_usage: RingStatsBuffer::new(capacity, rst_flag),
-            cpu_freq: RingStatsBuffer::new(capacity, rst_flag),
-            mem_free: RingStatsBuffer::new(capacity, rst_flag),
-            mem_used: RingStatsBuffer::new(capacity, rst_flag),
-            mem_available: RingStatsBuffer::new(capacity, rst_flag),
+            cpu_usage: RingStatsBuffer::with_spec(spec),
+            cpu_freq: RingStatsBuffer::with_spec(spec),
+            mem_free: RingStatsBuffer::with_spec(spec),
+            mem_used: RingStatsBuffer::with_spec(spec),
+            mem_available: RingStatsBuffer::with_spec(spec),
+            memory: MemStats::new(spec),
+            cpus: HashMap::new(),
             disk_usage: HashMap::new(),
             networks: HashMap::new(),
-            timestamp: RingStatsBuffer::new(capacity, rst_flag)
+            diskio: HashMap::new(),
+            load: LoadStats::new(spec),
+            pressure: PsiStats::new(spec),
+            collectors: HashMap::new(),
+            timestamp: RingStatsBuffer::new(spec.capacity, spec.rst),
+            times: HashMap::new(),
+            spec: spec.clone(),
+            rollups: HashMap::new(),
         }
     }
 
-    pub fn build_dynamic_values(&mut self, disks: &Vec<&str>,
+    pub fn build_dynamic_values(&mut self, cpus: usize, disks: &Vec<&str>,
                                 networks: &Vec<&str>) {
-        let capacity = self.timestamp.capacity();
-        let rst_flag = self.timestamp.has_reset_flag();
+        let spec = self.spec.clone();
+        for id in 0..cpus {
+            self.cpus.insert(id, CpuCore::new(&spec));
+        }
         for d in disks {
             self.disk_usage.insert(d.to_string(),
-                                   RingStatsBuffer::new(capacity, rst_flag));
+                                   RingStatsBuffer::with_spec(&spec));
         }
         for n in networks {
-            self.networks.insert(
-                n.to_string(),
-                NetworkBytes {
-                    rx_bytes: RingStatsBuffer::new(capacity, rst_flag),
-                    tx_bytes: RingStatsBuffer::new(capacity, rst_flag)
-                }
-            );
+            self.networks.insert(n.to_string(),
+                                 NetworkStats::new(&spec));
+        }
+    }
+
+    /// Pushes the counters of the block devices, starting to track the
+    /// ones seen for the first time
+    pub fn push_diskstats(&mut self, entries: Vec<DiskstatsEntry>,
+                          now: Instant) {
+        let spec = self.spec.clone();
+        for entry in entries {
+            if !self.diskio.contains_key(&entry.name) && !entry.is_relevant() {
+                continue;
+            }
+            self.diskio.entry(entry.name.clone())
+                .or_insert_with(|| DiskIoStats::new(&spec))
+                .push(entry, now);
+        }
+    }
+
+    /// Lists every buffer of samples, stamped by their entry in `times`
+    pub fn series(&self) -> Vec<SeriesRef<'_>> {
+        let mut series = vec![
+            SeriesRef { metric: "cpu_usage", label: None, label_name: "",
+                        samples: &self.cpu_usage },
+            SeriesRef { metric: "cpu_freq", label: None, label_name: "",
+                        samples: &self.cpu_freq },
+            SeriesRef { metric: "mem_free", label: None, label_name: "",
+                        samples: &self.mem_free },
+            SeriesRef { metric: "mem_used", label: None, label_name: "",
+                        samples: &self.mem_used },
+            SeriesRef { metric: "mem_available", label: None, label_name: "",
+                        samples: &self.mem_available },
+        ];
+        for (metric, buf) in self.memory.buffers() {
+            series.push(SeriesRef { metric, label: None, label_name: "",
+                                    samples: buf });
+        }
+        for (metric, buf) in self.load.buffers() {
+            series.push(SeriesRef { metric, label: None, label_name: "",
+                                    samples: buf });
+        }
+        for (resource, pressure) in self.pressure.resources() {
+            for (metric, buf) in pressure.buffers() {
+                series.push(SeriesRef { metric,
+                                        label: Some(resource.to_string()),
+                                        label_name: "resource",
+                                        samples: buf });
+            }
+        }
+        for (id, core) in self.cpus.iter() {
+            series.push(SeriesRef { metric: "core_usage",
+                                    label: Some(id.to_string()),
+                                    label_name: "cpu",
+                                    samples: &core.usage });
+            series.push(SeriesRef { metric: "core_freq",
+                                    label: Some(id.to_string()),
+                                    label_name: "cpu",
+                                    samples: &core.freq });
+        }
+        for (name, buf) in self.disk_usage.iter() {
+            series.push(SeriesRef { metric: "disk_usage",
+                                    label: Some(name.clone()),
+                                    label_name: "disk", samples: buf });
+        }
+        for (name, netstat) in self.networks.iter() {
+            for (metric, counter) in netstat.counters() {
+                series.push(SeriesRef { metric, label: Some(name.clone()),
+                                        label_name: "interface",
+                                        samples: &counter.rate });
+            }
+        }
+        for (name, iostat) in self.diskio.iter() {
+            for (metric, buf) in iostat.buffers() {
+                series.push(SeriesRef { metric, label: Some(name.clone()),
+                                        label_name: "device", samples: buf });
+            }
+        }
+        series
+    }
+
+    /// Same as series, to restore the buffers
+    pub fn series_mut(&mut self) -> Vec<SeriesMut<'_>> {
+        let mut series = vec![
+            SeriesMut { metric: "cpu_usage", label: None,
+                        samples: &mut self.cpu_usage },
+            SeriesMut { metric: "cpu_freq", label: None,
+                        samples: &mut self.cpu_freq },
+            SeriesMut { metric: "mem_free", label: None,
+                        samples: &mut self.mem_free },
+            SeriesMut { metric: "mem_used", label: None,
+                        samples: &mut self.mem_used },
+            SeriesMut { metric: "mem_available", label: None,
+                        samples: &mut self.mem_available },
+        ];
+        for (metric, buf) in self.memory.buffers_mut() {
+            series.push(SeriesMut { metric, label: None, samples: buf });
+        }
+        for (metric, buf) in self.load.buffers_mut() {
+            series.push(SeriesMut { metric, label: None, samples: buf });
+        }
+        for (resource, pressure) in self.pressure.resources_mut() {
+            for (metric, buf) in pressure.buffers_mut() {
+                series.push(SeriesMut { metric,
+                                        label: Some(resource.to_string()),
+                                        samples: buf });
+            }
+        }
+        for (id, core) in self.cpus.iter_mut() {
+            series.push(SeriesMut { metric: "core_usage",
+                                    label: Some(id.to_string()),
+                                    samples: &mut core.usage });
+            series.push(SeriesMut { metric: "core_freq",
+                                    label: Some(id.to_string()),
+                                    samples: &mut core.freq });
+        }
+        for (name, buf) in self.disk_usage.iter_mut() {
+            series.push(SeriesMut { metric: "disk_usage",
+                                    label: Some(name.clone()), samples: buf });
+        }
+        for (name, netstat) in self.networks.iter_mut() {
+            for (metric, counter) in netstat.counters_mut() {
+                series.push(SeriesMut { metric, label: Some(name.clone()),
+                                        samples: &mut counter.rate });
+            }
+        }
+        for (name, iostat) in self.diskio.iter_mut() {
+            for (metric, buf) in iostat.buffers_mut() {
+                series.push(SeriesMut { metric, label: Some(name.clone()),
+                                        samples: buf });
+            }
+        }
+        series
+    }
+
+    /// Consolidates the samples pushed at `ts` into the rollups
+    pub fn push_rollups(&mut self, ts: u64) {
+        let samples: Vec<_> = self.series().into_iter()
+            .map(|s| {
+                let last = s.samples.len().checked_sub(1)
+                    .and_then(|index| s.samples.get_f64(index));
+                ((s.metric, s.label), s.samples.pushed(), last)
+            })
+            .collect();
+        for (key, pushed, last) in samples {
+            self.rollups.entry(key).or_default().update(pushed, ts, last);
+        }
+    }
+
+    pub fn get_series(&self, metric: &str, label: Option<&str>)
+        -> Option<SeriesRef<'_>> {
+        self.series().into_iter()
+            .find(|s| s.metric == metric && s.label.as_deref() == label)
+    }
+
+    /// Stamps the samples pushed to each buffer with `ts`
+    pub fn push_times(&mut self, ts: u64) {
+        let series: Vec<_> = self.series().into_iter()
+            .map(|s| ((s.metric, s.label), s.samples.pushed(), s.samples.len()))
+            .collect();
+        for (key, pushed, len) in series {
+            self.times.entry(key).or_default().update(pushed, len, ts);
         }
     }
 }
 
+/// Current Unix time in milliseconds
+fn unix_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
 
 pub struct SystatsExecutor<T> {
-    systats: SystatsData,
-    sampling_freq: u64,
+    systats: Arc<RwLock<SystatsData>>,
+    sampling_interval: Duration,
+    collectors: Vec<Box<dyn Collector>>,
+    /// File the buffers are saved to every state_interval
+    state_file: Option<PathBuf>,
+    state_interval: Duration,
+    /// Sinks exporting the samples, each on its own thread
+    sinks: Vec<SinkWorker>,
+    tracker: SampleTracker,
     // In the future, check for a Fn pointer
     schema: Arc<T>,
 }
 
 impl<T> SystatsExecutor<T> where T: 'static + SystatsSchemaBuilder + Sync + Send {
-    pub fn new(capacity: usize, sampling_freq: u64, reset_flag: bool,
-               schema: Arc<T>) -> Self {
+    pub fn new(spec: &BufferSpec, sampling_interval: Duration,
+               collectors: Vec<Box<dyn Collector>>, schema: Arc<T>) -> Self {
         SystatsExecutor {
-            systats: SystatsData::new(capacity, reset_flag),
-            sampling_freq: sampling_freq,
-            schema: schema,
+            systats: Arc::new(RwLock::new(SystatsData::new(spec))),
+            sampling_interval,
+            collectors,
+            state_file: None,
+            state_interval: Duration::default(),
+            sinks: Vec::new(),
+            tracker: SampleTracker::new(),
+            schema,
         }
     }
 
+    /// Restores the buffers from `path` on start, then saves them to it
+    /// every `interval`
+    pub fn set_state_file(&mut self, path: PathBuf, interval: Duration) {
+        self.state_file = Some(path);
+        self.state_interval = interval;
+    }
+
+    /// Appends every sample to `tsdb` from its own thread, flushed when
+    /// the executor stops
+    pub fn set_tsdb(&mut self, tsdb: Arc<Tsdb>) {
+        self.add_sink(Box::new(TsdbSink::new(tsdb)));
+    }
+
+    /// Exports every sample to `sink`, flushed when the executor stops
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(SinkWorker::spawn(sink));
+    }
+
+    /// Returns the shared sample buffers, to be read by other threads
+    pub fn get_systats(&self) -> Arc<RwLock<SystatsData>> {
+        Arc::clone(&self.systats)
+    }
+
     #[cfg(feature = "debug_systats")]
-    fn debug_systats(&self) {
+    fn debug_systats(systats: &SystatsData) {
         println!("========================================================");
         println!("Timestamp: {} Uptime: {} Name: {}\n\
                  CPU cores: {} Total Mem: {} Total Swap: {}",
-                 self.systats.timestamp.get_last().unwrap(),
-                 self.systats.uptime, self.systats.name, self.systats.cpu_cores,
-                 self.systats.total_mem, self.systats.total_swap);
-        println!("CPU usage:  {:?}", self.systats.cpu_usage);
-        println!("CPU freq:   {:?}", self.systats.cpu_freq);
-        println!("Mem free:   {:?}", self.systats.mem_free);
-        println!("Mem used:   {:?}", self.systats.mem_used);
+                 systats.timestamp.get_last().unwrap(),
+                 systats.uptime, systats.name, systats.cpu_cores,
+                 systats.total_mem, systats.total_swap);
+        println!("CPU usage:  {:?}", systats.cpu_usage);
+        println!("CPU freq:   {:?}", systats.cpu_freq);
+        println!("Mem free:   {:?}", systats.mem_free);
+        println!("Mem used:   {:?}", systats.mem_used);
+        println!("Swap used:  {:?}", systats.memory.swap_used);
+        println!("Load:       {:?}", systats.load.load1);
+        println!("CPU cores:");
+        for (id, core) in systats.cpus.iter() {
+            println!(" {:4}:\n  Usage {:?}\n  Freq  {:?}",
+                     id, core.usage, core.freq);
+        }
         println!("Disk usage:");
-        for (name, buf) in self.systats.disk_usage.iter() {
+        for (name, buf) in systats.disk_usage.iter() {
             println!(" {:10}: {:?}", name, buf);
         }
+        println!("Disk I/O:");
+        for (name, iostat) in systats.diskio.iter() {
+            println!(" {:10}: {:?}", name, iostat.utilization);
+        }
         println!("Networks:");
-        for (name, netstat) in self.systats.networks.iter() {
+        for (name, netstat) in systats.networks.iter() {
             println!(" {:8}:\n  Rx {:?}\n  Tx {:?}",
-                     name, netstat.rx_bytes, netstat.tx_bytes);
-        }
-    }
-
-    fn read_sysinfo(&mut self, sysinfo: &mut System) {
-        sysinfo.refresh_cpu();
-        sysinfo.refresh_memory();
-        sysinfo.refresh_disks();
-        let usage = sysinfo.global_processor_info().cpu_usage();
-        let freq = sysinfo.global_processor_info().frequency();
-        let fmem = sysinfo.free_memory();
-        let umem = sysinfo.used_memory();
-        let amem = sysinfo.available_memory();
-        for disk in sysinfo.disks() {
-            let name = disk.name().to_str().unwrap_or("").to_string();
-            if let Some(buf) = self.systats.disk_usage.get_mut(&name) {
-                buf.push_back(disk.total_space() -
-                              disk.available_space());
-            }
+                     name, netstat.rx_bytes.rate, netstat.tx_bytes.rate);
         }
-        for (ifname, netdata) in sysinfo.networks().iter() {
-            if let Some(netstat) = self.systats.networks.get_mut(ifname) {
-                netstat.tx_bytes.push_back(netdata.total_transmitted());
-                netstat.rx_bytes.push_back(netdata.total_received());
-            }
+    }
+
+    fn read_systats(&mut self) {
+        for collector in self.collectors.iter_mut() {
+            collector.refresh();
         }
-        if self.systats.name.is_empty() {
-            self.systats.name = sysinfo.name().unwrap_or("".to_string());
-        }
-        self.systats.uptime = sysinfo.uptime();
-        self.systats.cpu_cores = sysinfo.physical_core_count().unwrap_or(0);
-        self.systats.total_mem = sysinfo.total_memory();
-        self.systats.total_swap = sysinfo.total_swap();
-        self.systats.cpu_usage.push_back(usage);
-        self.systats.cpu_freq.push_back(freq);
-        self.systats.mem_free.push_back(fmem);
-        self.systats.mem_used.push_back(umem);
-        self.systats.mem_available.push_back(amem);
-        let ts = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => 0,
+        let now = Instant::now();
+        let mut systats = match self.systats.write() {
+            Ok(systats) => systats,
+            Err(_) => return,
         };
-        self.systats.timestamp.push_back(ts);
-        self.schema.build(&self.systats);
+        let ts = unix_millis();
+        for collector in self.collectors.iter_mut() {
+            let result = collector.collect(&mut systats, now);
+            // Failures are reported by the status of the collector
+            systats.collectors.entry(collector.name())
+                .or_default()
+                .update(&result, ts);
+        }
+        systats.timestamp.push_back(ts);
+        systats.push_times(ts);
+        systats.push_rollups(ts);
+        // Built first, for the sinks exporting the schema
+        self.schema.build(&systats);
         #[cfg(feature = "debug_systats")]
         {
-            self.debug_systats();
+            Self::debug_systats(&systats);
+        }
+        if self.sinks.is_empty() {
+            return;
+        }
+        // Exported once the buffers are unlocked, not to block the readers
+        let batch = self.tracker.take(&systats, ts);
+        drop(systats);
+        for worker in self.sinks.iter_mut() {
+            worker.push(batch.clone());
         }
     }
 
-    fn init_dynamic_attrs(&mut self, sysinfo: &System) {
-        let mut disks: Vec<&str> = sysinfo.disks().iter()
-            .filter_map(|x| {
-                if let Some(x) = x.name().to_str() {
-                    return Some(x);
-                }
-                None
-            })
-            .collect();
-        disks.dedup();
-        let nets: Vec<&str> = sysinfo.networks().iter()
-            .map(|(k, _v)| k.as_str())
-            .collect();
-        self.systats.build_dynamic_values(&disks, &nets);
+    fn init_collectors(&mut self) {
+        if let Ok(mut systats) = self.systats.write() {
+            for collector in self.collectors.iter_mut() {
+                collector.init(&mut systats);
+            }
+            // Describes the system and the windows before the first sample
+            self.schema.build(&systats);
+        }
     }
 
-    pub fn run_executor(mut self, mut sysinfo: System,
-                        run_flag: Arc<RwLock<bool>>)
+    /// Restores the samples of the state file, if any and not stale
+    fn restore_state(&mut self) -> Option<Restored> {
+        let path = self.state_file.as_ref()?;
+        let state = match state::load(path) {
+            Ok(state) => state,
+            Err(Error::Io { source, .. })
+                if source.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                eprintln!("Ignoring the state file: {}", err);
+                return None;
+            }
+        };
+        let mut systats = self.systats.write().ok()?;
+        let restored = state::restore(&mut systats, &state, unix_millis(),
+                                      Instant::now());
+        self.tracker.skip(&systats);
+        self.schema.build(&systats);
+        Some(restored)
+    }
+
+    pub fn run_executor(mut self, run_flag: Arc<RwLock<bool>>)
                         -> JoinHandle<io::Result<()>> {
-        self.init_dynamic_attrs(&sysinfo);
+        self.init_collectors();
+        let mut restored = self.restore_state();
         let handle = spawn(move || {
-            let read_interval = Duration::new(self.sampling_freq, 0);
+            // Wakes up at least every second to check the run flag
             let sleep_res = Duration::new(1, 0);
-            let mut now = Instant::now();
+            let mut next = Instant::now() + self.sampling_interval;
+            let mut ticks = 0;
+            let mut saved = Instant::now();
             while *run_flag.read().unwrap() {
-                sleep(sleep_res);
-                if now.elapsed() >= read_interval {
-                    self.read_sysinfo(&mut sysinfo);
-                    now = Instant::now();
+                let now = Instant::now();
+                if now < next {
+                    sleep(sleep_res.min(next - now));
+                    continue;
+                }
+                self.read_systats();
+                // Counters push their first rate on the second sample, by
+                // then every disk and interface still there was pushed to
+                ticks += 1;
+                if ticks == 2 {
+                    if let (Some(restored), Ok(mut systats)) =
+                        (restored.take(), self.systats.write()) {
+                        state::prune(&mut systats, restored);
+                    }
+                }
+                if let Some(path) = &self.state_file {
+                    if saved.elapsed() >= self.state_interval {
+                        state::save_shared(&self.systats, path);
+                        saved = Instant::now();
+                    }
                 }
+                // Keeps the samples evenly spaced, skipping the ones missed
+                next += self.sampling_interval;
+                if next < Instant::now() {
+                    next = Instant::now() + self.sampling_interval;
+                }
+            }
+            for worker in self.sinks.drain(..) {
+                worker.stop();
             }
             Ok(())
         });
         handle
     }
 }
-
@@ -34,8 +34,9 @@ fn read_sysinfo(sys_lock: &RwLock<System>, sts_lock: &RwLock<SystatsData>) {
             }
             for (ifname, netdata) in sys.networks().iter() {
                 if let Some(netstat) = sts.networks.get_mut(ifname) {
-                    netstat.tx_bytes.push_back(netdata.total_transmitted());
-                    netstat.rx_bytes.push_back(netdata.total_received());
+                    let now = Instant::now();
+                    netstat.tx_bytes.push(netdata.total_transmitted(), now);
+                    netstat.rx_bytes.push(netdata.total_received(), now);
                 }
             }
             sts.cpu_usage.push_back(usage);
@@ -58,7 +59,7 @@ pub fn task_sysinfo_compute(sys_opts: SysinfoOpts,
                             run_flag: Arc<RwLock<bool>>)
                             -> JoinHandle<io::Result<()>> {
     let handle = spawn(move || {
-        let read_interval = Duration::new(sys_opts.sampling_freq as u64, 0);
+        let read_interval = sys_opts.sampling_interval;
         let sleep_res = Duration::new(1, 0);
         let mut now = Instant::now();
         while *run_flag.read().unwrap() {
@@ -82,7 +83,7 @@ pub fn task_sysinfo_show(sys_lock: Arc<RwLock<System>>,
         while *run_flag.read().unwrap() {
             if let Ok(sys) = sys_lock.read() {
                 if let Ok(sts) = sts_lock.read() {
-                    if sts.timestamp.len() == 0 {
+                    if sts.timestamp.is_empty() {
                         continue;
                     }
                     println!("========================================================");
@@ -103,7 +104,7 @@ pub fn task_sysinfo_show(sys_lock: Arc<RwLock<System>>,
                     println!("NETWORKS:");
                     for (name, netstat) in sts.networks.iter() {
                         println!(" {:10}: rx_bytes {:?} tx_bytes {:?}",
-                                 name, netstat.rx_bytes, netstat.tx_bytes);
+                                 name, netstat.rx_bytes.rate, netstat.tx_bytes.rate);
                     }
                 }
             }
@@ -115,20 +116,17 @@ pub fn task_sysinfo_show(sys_lock: Arc<RwLock<System>>,
 
 /// Loop that implements signal handling
 pub fn task_handle_signals(run_flag: Arc<RwLock<bool>>) -> Result<(), Error> {
-    let mut signals = Signals::new(&[
+    let mut signals = Signals::new([
         SIGHUP,
         SIGTERM,
         SIGINT,
         SIGQUIT,
     ])?;
-    for signal in signals.forever() {
+    if let Some(signal) = signals.forever().next() {
         match signal as libc::c_int {
             SIGHUP | SIGTERM | SIGINT | SIGQUIT => {
-                {
-                    let mut flag = run_flag.write().unwrap();
-                    *flag = false;
-                    break;
-                }
+                let mut flag = run_flag.write().unwrap();
+                *flag = false;
             },
             _ => unreachable!(),
         }
@@ -0,0 +1,438 @@
+//! This module keeps the history of every series on disk, much longer than
+//! the ring buffers. Samples are compressed and appended every minute to
+//! segment files of an hour, named after the Unix time in milliseconds
+//! they start at. Each append is a record framed by its length and CRC,
+//! so a record torn by a crash is detected and discarded. Whole segments
+//! are deleted once older than the retention or above the size limit
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::gorilla::{self, Encoder};
+use crate::sink::{Batch, Sink};
+
+
+/// Milliseconds spanned by each segment
+pub const SEGMENT_SPAN: u64 = 60*60*1000;
+/// Milliseconds of samples kept in memory before being appended
+pub const FLUSH_SPAN: u64 = 60*1000;
+const SEGMENT_EXT: &str = "seg";
+/// Length and CRC of a record
+const HEADER_LEN: usize = 8;
+
+/// CRC-32 as used by zlib
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+type SeriesKey = (String, Option<String>);
+
+/// Samples of a series compressed in a record
+struct Chunk<'a> {
+    key: &'a SeriesKey,
+    samples: &'a Encoder,
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Reads the payload of a record, in the order written by encode_record
+struct Cursor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.u16()? as usize;
+        std::str::from_utf8(self.take(len)?).ok()
+    }
+}
+
+/// Record holding the chunks: for each, its metric, label if any, number
+/// of samples and compressed bytes
+fn encode_record(chunks: &[Chunk]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for chunk in chunks {
+        put_str(&mut payload, &chunk.key.0);
+        match &chunk.key.1 {
+            Some(label) => {
+                payload.push(1);
+                put_str(&mut payload, label);
+            },
+            None => payload.push(0),
+        }
+        let bytes = chunk.samples.as_bytes();
+        payload.extend_from_slice(&chunk.samples.len().to_le_bytes());
+        payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        payload.extend_from_slice(bytes);
+    }
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+/// Splits the content of a segment in the payloads of its records, along
+/// with the length of the valid ones. Stops at the first record truncated
+/// or corrupted
+fn split_records(content: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut cursor = Cursor { buf: content };
+    let mut payloads = Vec::new();
+    let mut valid = 0;
+    while let (Some(len), Some(crc)) = (cursor.u32(), cursor.u32()) {
+        match cursor.take(len as usize) {
+            Some(payload) if crc32(payload) == crc => {
+                payloads.push(payload);
+                valid += HEADER_LEN + payload.len();
+            },
+            _ => break,
+        }
+    }
+    (payloads, valid)
+}
+
+/// Decodes the samples of `metric` and `label` out of a record payload
+fn decode_payload(payload: &[u8], metric: &str, label: Option<&str>)
+    -> Option<Vec<(u64, f64)>> {
+    let mut cursor = Cursor { buf: payload };
+    let mut samples = Vec::new();
+    while !cursor.buf.is_empty() {
+        let chunk_metric = cursor.str()?;
+        let chunk_label = match cursor.take(1)?[0] {
+            0 => None,
+            _ => Some(cursor.str()?),
+        };
+        let count = cursor.u32()?;
+        let len = cursor.u32()? as usize;
+        let bytes = cursor.take(len)?;
+        if chunk_metric == metric && chunk_label == label {
+            samples.extend(gorilla::decode(bytes, count)?);
+        }
+    }
+    Some(samples)
+}
+
+/// Segments of the directory, sorted by their start
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        let start = path.file_stem().and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        if let Some(start) = start {
+            segments.push((start, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Segment appended to, along with the length of its valid records
+struct Segment {
+    start: u64,
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+/// Samples not appended yet, and the segment appended to
+#[derive(Default)]
+struct Writer {
+    pending: HashMap<SeriesKey, Encoder>,
+    first_ts: Option<u64>,
+    segment: Option<Segment>,
+}
+
+pub struct Tsdb {
+    dir: PathBuf,
+    retention: Duration,
+    max_bytes: Option<u64>,
+    writer: Mutex<Writer>,
+}
+
+impl Tsdb {
+    /// Opens the store in `dir`, created if needed. A record torn by a
+    /// crash at the end of the last segment is truncated
+    pub fn open(dir: &Path, retention: Duration, max_bytes: Option<u64>)
+        -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        if let Some((_, path)) = list_segments(dir)?.last() {
+            let content = fs::read(path)?;
+            let (_, valid) = split_records(&content);
+            if valid < content.len() {
+                eprintln!("Discarding {} bytes torn from {}",
+                          content.len() - valid, path.display());
+                let file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(valid as u64)?;
+                file.sync_all()?;
+            }
+        }
+        Ok(Tsdb {
+            dir: dir.to_path_buf(),
+            retention,
+            max_bytes,
+            writer: Mutex::new(Writer::default()),
+        })
+    }
+
+    /// Takes the samples of a batch, appending them to the current segment
+    /// once they span FLUSH_SPAN
+    pub fn push(&self, batch: &Batch) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+        for sample in batch.samples.iter() {
+            let key = (sample.metric.to_string(), sample.label.clone());
+            writer.pending.entry(key).or_default().push(batch.ts, sample.value);
+            writer.first_ts.get_or_insert(batch.ts);
+        }
+        match writer.first_ts {
+            Some(first) if batch.ts.saturating_sub(first) >= FLUSH_SPAN => {
+                self.append(&mut writer)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends the pending samples, as on exit
+    pub fn flush(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+        self.append(&mut writer)
+    }
+
+    /// Appends the pending samples as a record, kept pending if it could
+    /// not be written whole
+    fn append(&self, writer: &mut Writer) -> io::Result<()> {
+        let first = match writer.first_ts {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let mut chunks: Vec<Chunk> = writer.pending.iter()
+            .map(|(key, samples)| Chunk { key, samples })
+            .collect();
+        chunks.sort_by(|a, b| a.key.cmp(b.key));
+        let record = encode_record(&chunks);
+        let start = first - first % SEGMENT_SPAN;
+        if writer.segment.as_ref().map(|segment| segment.start) != Some(start) {
+            let path = self.dir.join(format!("{}.{}", start, SEGMENT_EXT));
+            let file = OpenOptions::new().create(true).append(true)
+                .open(&path)?;
+            // Makes the new segment itself durable
+            File::open(&self.dir)?.sync_all()?;
+            let len = file.metadata()?.len();
+            writer.segment = Some(Segment { start, path, file, len });
+        }
+        if let Some(segment) = writer.segment.as_mut() {
+            let written = segment.file.write_all(&record)
+                .and_then(|()| segment.file.sync_data());
+            if let Err(err) = written {
+                // Cuts what was written of the record, for the next ones
+                // to follow the last valid one
+                let _ = OpenOptions::new().write(true).open(&segment.path)
+                    .and_then(|file| file.set_len(segment.len));
+                writer.segment = None;
+                return Err(err);
+            }
+            segment.len += record.len() as u64;
+        }
+        writer.pending.clear();
+        writer.first_ts = None;
+        self.enforce_retention(first)
+    }
+
+    /// Deletes the segments entirely older than the retention before `now`,
+    /// then the oldest ones while above the size limit. The last segment
+    /// is always kept
+    fn enforce_retention(&self, now: u64) -> io::Result<()> {
+        let mut segments = list_segments(&self.dir)?;
+        let oldest = now.saturating_sub(self.retention.as_millis() as u64);
+        while segments.len() > 1 && segments[1].0 <= oldest {
+            fs::remove_file(&segments.remove(0).1)?;
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            let mut sizes = Vec::with_capacity(segments.len());
+            for (_, path) in segments.iter() {
+                sizes.push(fs::metadata(path)?.len());
+            }
+            let mut total: u64 = sizes.iter().sum();
+            let older = segments.len().saturating_sub(1);
+            for ((_, path), size) in segments.iter().zip(sizes).take(older) {
+                if total <= max_bytes {
+                    break;
+                }
+                fs::remove_file(path)?;
+                total -= size;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the samples of a series appended within `from` and `to`,
+    /// included. Segments deleted meanwhile are skipped
+    pub fn query(&self, metric: &str, label: Option<&str>, from: u64, to: u64)
+        -> io::Result<Vec<(u64, f64)>> {
+        let segments = list_segments(&self.dir)?;
+        let mut samples = Vec::new();
+        for (index, (start, path)) in segments.iter().enumerate() {
+            // Records may spill over the next segment by a flush
+            let end = segments.get(index + 1).map_or(u64::MAX, |next| next.0);
+            if *start > to || end.saturating_add(FLUSH_SPAN) < from {
+                continue;
+            }
+            let content = match fs::read(path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for payload in split_records(&content).0 {
+                let decoded = decode_payload(payload, metric, label)
+                    .ok_or_else(|| io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupted record in {}", path.display())))?;
+                samples.extend(decoded.into_iter()
+                               .filter(|(ts, _)| *ts >= from && *ts <= to));
+            }
+        }
+        samples.sort_by_key(|(ts, _)| *ts);
+        Ok(samples)
+    }
+}
+
+/// Appends the samples to a store shared with the history queries
+pub struct TsdbSink {
+    tsdb: Arc<Tsdb>,
+}
+
+impl TsdbSink {
+    pub fn new(tsdb: Arc<Tsdb>) -> Self {
+        TsdbSink { tsdb }
+    }
+}
+
+impl Sink for TsdbSink {
+    fn name(&self) -> &'static str {
+        "tsdb"
+    }
+
+    fn push(&mut self, batch: &Batch) -> io::Result<()> {
+        self.tsdb.push(batch)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tsdb.flush()
+    }
+}
+
+#[test]
+fn test_tsdb() {
+    use crate::ringbuf::BufferSpec;
+    use crate::sink::SampleTracker;
+    use crate::systats::SystatsData;
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    let dir = std::env::temp_dir()
+        .join(format!("sysinfo-tsdb-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let retention = Duration::from_millis(2*SEGMENT_SPAN);
+    let tsdb = Tsdb::open(&dir, retention, None).unwrap();
+    let mut systats = SystatsData::new(&BufferSpec::new(4, false));
+    systats.build_dynamic_values(0, &vec!["sda1"], &vec![]);
+    let mut tracker = SampleTracker::new();
+    // A sample every 30 seconds for 3 hours and a half
+    let start = 10*SEGMENT_SPAN;
+    for i in 0..420u64 {
+        let ts = start + i*30*1000;
+        systats.cpu_usage.push_back(i as f32);
+        if i % 2 == 0 {
+            systats.disk_usage.get_mut("sda1").unwrap().push_back(i);
+        }
+        tsdb.push(&tracker.take(&systats, ts)).unwrap();
+    }
+    tsdb.flush().unwrap();
+    let segments = list_segments(&dir).unwrap();
+    // The first segment is older than the retention
+    assert_eq!(segments.iter().map(|(start, _)| *start).collect::<Vec<_>>(),
+               vec![start + SEGMENT_SPAN, start + 2*SEGMENT_SPAN,
+                    start + 3*SEGMENT_SPAN]);
+    let from = start + 3*SEGMENT_SPAN;
+    let samples = tsdb.query("cpu_usage", None, from, u64::MAX).unwrap();
+    assert_eq!(samples.len(), 60);
+    assert_eq!(samples[0], (from, 360.));
+    assert_eq!(samples.last(), Some(&(start + 419*30*1000, 419.)));
+    let samples = tsdb.query("disk_usage", Some("sda1"), from, from + 60*1000)
+        .unwrap();
+    assert_eq!(samples, vec![(from, 360.), (from + 60*1000, 362.)]);
+    assert!(tsdb.query("disk_usage", None, 0, u64::MAX).unwrap().is_empty());
+    // A record torn by a crash is discarded on open
+    let last = &segments.last().unwrap().1;
+    let len = fs::metadata(last).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(last).unwrap();
+    file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+    drop(tsdb);
+    let tsdb = Tsdb::open(&dir, retention, Some(len + 1)).unwrap();
+    assert_eq!(fs::metadata(last).unwrap().len(), len);
+    assert_eq!(tsdb.query("cpu_usage", None, from, u64::MAX).unwrap().len(), 60);
+    // A record failing partway is cut, its samples appended again with
+    // the next ones
+    for i in 420..422u64 {
+        systats.cpu_usage.push_back(i as f32);
+        tsdb.push(&tracker.take(&systats, start + i*30*1000)).unwrap();
+        if i == 420 {
+            tsdb.flush().unwrap();
+        }
+    }
+    let len = fs::metadata(last).unwrap().len();
+    {
+        let mut writer = tsdb.writer.lock().unwrap();
+        let segment = writer.segment.as_mut().unwrap();
+        // Torn as by a full disk, then refusing the rest of the record
+        let mut file = OpenOptions::new().append(true).open(last).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        segment.file = File::open(last).unwrap();
+    }
+    assert!(tsdb.flush().is_err());
+    assert_eq!(fs::metadata(last).unwrap().len(), len);
+    systats.cpu_usage.push_back(422.);
+    tsdb.push(&tracker.take(&systats, start + 422*30*1000)).unwrap();
+    tsdb.flush().unwrap();
+    let samples = tsdb.query("cpu_usage", None, from, u64::MAX).unwrap();
+    assert_eq!(samples.len(), 63);
+    assert_eq!(samples.last(), Some(&(start + 422*30*1000, 422.)));
+    // Above the size limit, only the last segment is kept
+    systats.cpu_usage.push_back(1.);
+    tsdb.push(&tracker.take(&systats, start + 423*30*1000)).unwrap();
+    tsdb.flush().unwrap();
+    assert_eq!(list_segments(&dir).unwrap().len(), 1);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,14 +1,27 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
+use crate::error::{Error, Result};
 
 
-pub fn open_and_read(filename: &str) -> String {
-    let mut f = File::open(&filename)
-        .expect(&format!("Error opening file: {}", filename));
-    let mut content = String::new();
-    f.read_to_string(&mut content)
-        .expect(&format!("Error reading content: {}", filename));
-    content
+/// Reads a whole file
+pub fn read_file(filename: &str) -> Result<String> {
+    fs::read_to_string(filename).map_err(|source| Error::Io {
+        path: filename.to_string(),
+        source,
+    })
+}
+
+/// Reads and parses a whole file, `parse` returning None when the content
+/// is not in the expected format
+pub fn parse_file<T, F>(filename: &str, parse: F) -> Result<T>
+where F: FnOnce(&str) -> Option<T>
+{
+    let content = read_file(filename)?;
+    parse(&content).ok_or_else(|| Error::Parse { path: filename.to_string() })
+}
+
+/// Reads a file holding a single integer, as the ones in sysfs
+pub fn read_u64(filename: &str) -> Result<u64> {
+    parse_file(filename, |content| content.trim().parse::<u64>().ok())
 }
 
 pub fn parse_key_from_text(s: &str, k: &str, endstr: &str,
@@ -19,8 +32,8 @@ pub fn parse_key_from_text(s: &str, k: &str, endstr: &str,
             match content.find(endstr) {
                 Some(idx_e) => {
                     let mut val = &content[..idx_e];
-                    if trim.is_some() {
-                        val = val.trim_matches(trim.unwrap());
+                    if let Some(trim) = trim {
+                        val = val.trim_matches(trim);
                     }
                     Some(String::from(val))
                 },
@@ -42,18 +55,15 @@ pub fn parse_online_cpus(s: &str) -> Vec<u32> {
     let mut cpus: Vec<u32> = Vec::new();
     for range in cpu_ranges {
         if let Some(index) = range.find('-') {
-            match (&range[..index].parse::<u32>(), &range[index + 1..].parse::<u32>()) {
-                (Ok(l), Ok(r)) => {
-                    for i in *l..*r+1 {
-                        cpus.push(i);
-                    }
-                },
-                _ => ()
+            if let (Ok(l), Ok(r)) = (range[..index].parse::<u32>(),
+                                     range[index + 1..].parse::<u32>()) {
+                for i in l..r+1 {
+                    cpus.push(i);
+                }
             }
         } else {
-            match range.parse::<u32>() {
-                Ok(nr) => { cpus.push(nr); },
-                _ => ()
+            if let Ok(nr) = range.parse::<u32>() {
+                cpus.push(nr);
             }
         }
     }
@@ -64,3 +74,20 @@ pub fn parse_online_cpus(s: &str) -> Vec<u32> {
 fn test_parse_online_cpus() {
     assert_eq!(parse_online_cpus("0-4,6-7"), vec![0, 1, 2, 3, 4, 6, 7]);
 }
+
+#[test]
+fn test_read_file() {
+    let missing = "/nonexistent/cpufreq/scaling_driver";
+    match read_file(missing) {
+        Err(Error::Io { path, .. }) => assert_eq!(path, missing),
+        _ => panic!("missing file should be an I/O error"),
+    }
+    let stat = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/proc/stat");
+    assert!(matches!(read_u64(stat), Err(Error::Parse { .. })));
+    let freq = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sys/devices/\
+                        system/cpu/cpu0/cpufreq/scaling_cur_freq");
+    assert_eq!(read_u64(freq).unwrap(), 2400000);
+    assert_eq!(read_u64(missing).unwrap_err().to_string(),
+               format!("failed to read {}: No such file or directory \
+                        (os error 2)", missing));
+}